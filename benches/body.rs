@@ -0,0 +1,106 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use http_client::body::Body;
+use http_client::client::HttpClient;
+use http_client::codec::{read_body, read_event, DecoderReader, ResponseDecoder};
+use http_client::method::HttpMethod;
+use http_client::request::Request;
+use std::io::{self, BufReader, Cursor, Read, Write};
+
+const SIZE: usize = 8 * 1024 * 1024;
+
+// 書き込みは捨てて、用意したレスポンスを返すだけのコネクション
+struct Conn(Cursor<Vec<u8>>);
+
+impl Read for Conn {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for Conn {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn length_response() -> Vec<u8> {
+    let mut resp = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", SIZE).into_bytes();
+    resp.resize(resp.len() + SIZE, b'a');
+    resp
+}
+
+// 16KB ずつのチャンクに分ける
+fn chunked_response() -> Vec<u8> {
+    let mut resp = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+    let chunk = vec![b'a'; 16 * 1024];
+    for _ in 0..SIZE / chunk.len() {
+        resp.extend_from_slice(format!("{:x}\r\n", chunk.len()).as_bytes());
+        resp.extend_from_slice(&chunk);
+        resp.extend_from_slice(b"\r\n");
+    }
+    resp.extend_from_slice(b"0\r\n\r\n");
+    resp
+}
+
+fn read_head(data: &[u8]) -> (ResponseDecoder, BufReader<&[u8]>) {
+    let mut r = BufReader::new(data);
+    let mut decoder = ResponseDecoder::new(&HttpMethod::Get);
+    while decoder.body_kind().is_none() {
+        read_event(&mut decoder, &mut r).unwrap();
+    }
+    (decoder, r)
+}
+
+fn decode(c: &mut Criterion) {
+    for (name, data) in [
+        ("length", length_response()),
+        ("chunked", chunked_response()),
+    ] {
+        let mut group = c.benchmark_group(format!("decode_{}", name));
+        group.throughput(Throughput::Bytes(SIZE as u64));
+        group.bench_function("read_body", |b| {
+            b.iter(|| {
+                let (mut decoder, mut r) = read_head(&data);
+                read_body(&mut decoder, &mut r).unwrap()
+            })
+        });
+        // NOTE: 以前の read_response と同じく、Read を通して read_to_end する
+        group.bench_function("read_to_end", |b| {
+            b.iter(|| {
+                let (decoder, r) = read_head(&data);
+                let mut body = Vec::new();
+                BufReader::new(DecoderReader::new(decoder, r))
+                    .read_to_end(&mut body)
+                    .unwrap();
+                body
+            })
+        });
+        group.bench_function("execute_request", |b| {
+            let req = Request::get("http://localhost/");
+            b.iter_batched(
+                || HttpClient::new(Conn(Cursor::new(data.clone()))),
+                |mut client| client.execute_request(&req).unwrap(),
+                BatchSize::LargeInput,
+            )
+        });
+        group.finish();
+    }
+}
+
+fn access(c: &mut Criterion) {
+    let body = Body::new(vec![b'a'; SIZE]);
+    let mut group = c.benchmark_group("body_access");
+    group.bench_function("clone", |b| b.iter(|| body.clone()));
+    group.bench_function("as_bytes", |b| b.iter(|| body.as_bytes().len()));
+    group.bench_function("raw", |b| b.iter(|| body.raw()));
+    group.bench_function("as_text", |b| b.iter(|| body.as_text().unwrap().len()));
+    group.bench_function("text", |b| b.iter(|| body.text().unwrap()));
+    group.finish();
+}
+
+criterion_group!(benches, decode, access);
+criterion_main!(benches);
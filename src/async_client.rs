@@ -0,0 +1,1164 @@
+use crate::body::Body;
+use crate::client::{fix_header, take_encodings, InterimFn};
+use crate::codec::{BodyEncoder, BodyKind, Event, ResponseDecoder, MAX_RESERVE};
+use crate::compression::{
+    check_ratio, is_zlib, ContentEncoding, ACCEPT_ENCODING, DEFAULT_MAX_DECOMPRESSION_RATIO,
+};
+use crate::header::HttpHeader;
+use crate::request::{OutgoingBody, OutgoingStream, Request};
+use crate::resolve::Resolve;
+use crate::response::Response;
+use crate::version::HttpVersion;
+use anyhow::{anyhow, bail, Result};
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::future::Future;
+use std::io::{self, Read};
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+    ReadBuf,
+};
+use tokio::net::TcpStream;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio_util::io::StreamReader;
+
+pub trait AsyncReadWriter: AsyncRead + AsyncWrite + Unpin + Send {}
+
+// NOTE: ReadWriter と同じく、AsyncRead と AsyncWrite を満たすすべての T に実装する
+impl<T> AsyncReadWriter for T where T: AsyncRead + AsyncWrite + Unpin + Send {}
+
+type BoxAsyncBufRead<'a> = Pin<Box<dyn AsyncBufRead + Send + 'a>>;
+
+pub struct AsyncBodyReader<'a> {
+    inner: BoxAsyncBufRead<'a>,
+}
+
+impl<'a> AsyncBodyReader<'a> {
+    pub fn new<R: AsyncBufRead + Send + 'a>(inner: R) -> Self {
+        Self {
+            inner: Box::pin(inner),
+        }
+    }
+
+    pub async fn into_body(mut self) -> Result<Body> {
+        let mut data = Vec::new();
+        self.inner.read_to_end(&mut data).await?;
+        Ok(Body::new(data))
+    }
+}
+
+impl AsyncRead for AsyncBodyReader<'_> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.inner.as_mut().poll_read(cx, buf)
+    }
+}
+
+impl AsyncBufRead for AsyncBodyReader<'_> {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        self.get_mut().inner.as_mut().poll_fill_buf(cx)
+    }
+
+    fn consume(mut self: Pin<&mut Self>, amt: usize) {
+        self.inner.as_mut().consume(amt)
+    }
+}
+
+pub struct AsyncStreamResponse<'a> {
+    pub version: HttpVersion,
+    pub status: u32,
+    pub header: HttpHeader,
+    pub body: AsyncBodyReader<'a>,
+}
+
+impl AsyncStreamResponse<'_> {
+    // StreamResponse::into_response の非同期版
+    pub async fn into_response(self) -> Result<Response> {
+        let body = self.body.into_body().await?;
+        let body = match body.is_empty() {
+            true => None,
+            false => Some(Body::with_header(body.into_bytes(), &self.header)),
+        };
+        Ok(Response {
+            version: self.version,
+            status: self.status,
+            header: self.header,
+            body,
+        })
+    }
+}
+
+// codec::read_event の非同期版
+async fn read_event<R: AsyncBufRead + Unpin>(
+    decoder: &mut ResponseDecoder,
+    r: &mut R,
+) -> Result<Event> {
+    loop {
+        if let (_, Some(event)) = decoder.decode(&[])? {
+            return Ok(event);
+        }
+        let buf = r.fill_buf().await?;
+        if buf.is_empty() {
+            return decoder.eof();
+        }
+        let (n, event) = decoder.decode(buf)?;
+        r.consume(n);
+        if let Some(event) = event {
+            return Ok(event);
+        }
+    }
+}
+
+// codec::read_body の非同期版
+async fn read_body<R: AsyncBufRead + Unpin>(
+    decoder: &mut ResponseDecoder,
+    r: &mut R,
+) -> Result<Vec<u8>> {
+    let mut body = Vec::new();
+    if let Some(BodyKind::Length(n)) = decoder.body_kind() {
+        body.reserve(n.min(MAX_RESERVE) as usize);
+    }
+    while !decoder.is_done() {
+        if let (_, Some(_)) = decoder.decode(&[])? {
+            continue;
+        }
+        let input = r.fill_buf().await?;
+        if input.is_empty() {
+            decoder.eof()?;
+            continue;
+        }
+        let n = match decoder.advance_data(input.len()) {
+            Some(n) => {
+                body.extend_from_slice(&input[..n]);
+                n
+            }
+            None => decoder.decode(input)?.0,
+        };
+        r.consume(n);
+    }
+    Ok(body)
+}
+
+// ボディの Data イベントだけを読む。トレイラーは読み飛ばす
+fn decoded_body<'a, R: AsyncBufRead + Unpin + Send + 'a>(
+    decoder: ResponseDecoder,
+    r: R,
+) -> BoxAsyncBufRead<'a> {
+    let stream =
+        futures_util::stream::try_unfold((decoder, r), |(mut decoder, mut r)| async move {
+            while !decoder.is_done() {
+                let event = read_event(&mut decoder, &mut r).await.map_err(|e| {
+                    e.downcast::<io::Error>().unwrap_or_else(|e| {
+                        io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+                    })
+                })?;
+                if let Event::Data(data) = event {
+                    return Ok(Some((Bytes::from(data), (decoder, r))));
+                }
+            }
+            Ok::<_, io::Error>(None)
+        });
+    Box::pin(StreamReader::new(stream))
+}
+
+struct CountingReader<'a> {
+    inner: BoxAsyncBufRead<'a>,
+    count: Arc<AtomicU64>,
+}
+
+impl AsyncRead for CountingReader<'_> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        ready!(self.inner.as_mut().poll_read(cx, buf))?;
+        let n = buf.filled().len() - before;
+        self.count.fetch_add(n as u64, Ordering::Relaxed);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncBufRead for CountingReader<'_> {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        self.get_mut().inner.as_mut().poll_fill_buf(cx)
+    }
+
+    fn consume(mut self: Pin<&mut Self>, amt: usize) {
+        self.count.fetch_add(amt as u64, Ordering::Relaxed);
+        self.inner.as_mut().consume(amt)
+    }
+}
+
+struct RatioLimit<'a> {
+    inner: BoxAsyncBufRead<'a>,
+    compressed: Arc<AtomicU64>,
+    decompressed: u64,
+    max_ratio: u64,
+}
+
+impl AsyncRead for RatioLimit<'_> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        ready!(self.inner.as_mut().poll_read(cx, buf))?;
+        self.decompressed += (buf.filled().len() - before) as u64;
+        let compressed = self.compressed.load(Ordering::Relaxed);
+        // NOTE: エラーを返すときは何も読んでいないことにしないといけない
+        if let Err(e) = check_ratio(self.decompressed, compressed, self.max_ratio) {
+            buf.set_filled(before);
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+// compression::decoder の非同期版
+async fn decompressor<'a>(
+    r: BoxAsyncBufRead<'a>,
+    encodings: &[ContentEncoding],
+    max_ratio: Option<u64>,
+) -> io::Result<BoxAsyncBufRead<'a>> {
+    use async_compression::tokio::bufread::{
+        BrotliDecoder, DeflateDecoder, GzipDecoder, ZlibDecoder, ZstdDecoder,
+    };
+
+    let compressed = Arc::new(AtomicU64::new(0));
+    let mut r: BoxAsyncBufRead<'a> = Box::pin(CountingReader {
+        inner: r,
+        count: compressed.clone(),
+    });
+
+    for encoding in encodings.iter().rev() {
+        r = match encoding {
+            ContentEncoding::Gzip => {
+                let mut d = GzipDecoder::new(r);
+                d.multiple_members(true);
+                Box::pin(BufReader::new(d))
+            }
+            ContentEncoding::Deflate => {
+                if is_zlib(r.fill_buf().await?) {
+                    Box::pin(BufReader::new(ZlibDecoder::new(r)))
+                } else {
+                    Box::pin(BufReader::new(DeflateDecoder::new(r)))
+                }
+            }
+            ContentEncoding::Brotli => Box::pin(BufReader::new(BrotliDecoder::new(r))),
+            ContentEncoding::Zstd => Box::pin(BufReader::new(ZstdDecoder::new(r))),
+            ContentEncoding::Identity => r,
+        };
+    }
+
+    Ok(match max_ratio {
+        Some(max_ratio) => Box::pin(BufReader::new(RatioLimit {
+            inner: r,
+            compressed,
+            decompressed: 0,
+            max_ratio,
+        })),
+        None => r,
+    })
+}
+
+async fn with_timeout<R, F: Future<Output = Result<R>>>(t: Option<Duration>, f: F) -> Result<R> {
+    match t {
+        Some(t) => tokio::time::timeout(t, f)
+            .await
+            .map_err(|_| anyhow!("request timed out after {:?}", t))?,
+        None => f.await,
+    }
+}
+
+// NOTE: StreamBody は同期の Read なので、ランタイムを止めないように spawn_blocking で読む
+async fn read_blocking(
+    mut reader: Box<dyn Read + Send>,
+) -> Result<(Box<dyn Read + Send>, Vec<u8>)> {
+    let (reader, buf) = tokio::task::spawn_blocking(move || {
+        let mut buf = vec![0u8; 8 * 1024];
+        let n = reader.read(&mut buf);
+        (
+            reader,
+            n.map(|n| {
+                buf.truncate(n);
+                buf
+            }),
+        )
+    })
+    .await?;
+    Ok((reader, buf?))
+}
+
+async fn write_stream<W: AsyncWrite + Unpin>(w: &mut W, stream: OutgoingStream) -> Result<()> {
+    let OutgoingStream { mut reader, length } = stream;
+    let mut encoder = BodyEncoder::new(length);
+    while !encoder.is_full() {
+        let (r, buf) = read_blocking(reader).await?;
+        reader = r;
+        if buf.is_empty() {
+            break;
+        }
+        w.write_all(&encoder.encode(&buf)).await?;
+    }
+    w.write_all(&encoder.finish()?).await?;
+    Ok(())
+}
+
+pub struct AsyncHttpClient<T: AsyncReadWriter> {
+    conn: BufReader<T>,
+    decompress: bool,
+    max_decompression_ratio: Option<u64>,
+    timeout: Option<Duration>,
+    on_interim: Option<InterimFn>,
+}
+
+impl<T: AsyncReadWriter> AsyncHttpClient<T> {
+    pub fn new(conn: T) -> Self {
+        Self {
+            conn: BufReader::new(conn),
+            decompress: true,
+            max_decompression_ratio: Some(DEFAULT_MAX_DECOMPRESSION_RATIO),
+            timeout: None,
+            on_interim: None,
+        }
+    }
+
+    pub fn decompress(&mut self, p: bool) -> &mut Self {
+        self.decompress = p;
+        self
+    }
+
+    pub fn max_decompression_ratio(&mut self, p: Option<u64>) -> &mut Self {
+        self.max_decompression_ratio = p;
+        self
+    }
+
+    // execute_request ではレスポンス全体、execute_request_stream ではヘッダを受け取るまでの時間
+    pub fn timeout(&mut self, p: Duration) -> &mut Self {
+        self.timeout = Some(p);
+        self
+    }
+
+    fn default_header(&self) -> HttpHeader {
+        let mut header = HttpHeader::new();
+        if self.decompress {
+            header.add("Accept-Encoding", ACCEPT_ENCODING);
+        }
+        header
+    }
+
+    // HttpClient::on_interim と同じ
+    pub fn on_interim<F: FnMut(u32, &HttpHeader) + Send + 'static>(&mut self, f: F) -> &mut Self {
+        self.on_interim = Some(Box::new(f));
+        self
+    }
+
+    fn interim(&mut self, status: u32, header: &HttpHeader) {
+        if let Some(f) = self.on_interim.as_mut() {
+            f(status, header);
+        }
+    }
+
+    // リクエストを送り、ボディを送ったかどうかを返す。100 Continue を待つ間に読んだものは decoder に入る
+    async fn write_request(
+        &mut self,
+        req: &Request,
+        decoder: &mut ResponseDecoder,
+    ) -> Result<bool> {
+        let mut extra = self.default_header();
+        let timeout = req.continue_timeout();
+        if timeout.is_some() {
+            extra.add("Expect", "100-continue");
+        }
+        let (head, body) = req.prepare_head(&extra)?;
+        self.conn.get_mut().write_all(&head).await?;
+        self.conn.get_mut().flush().await?;
+
+        let send = match timeout {
+            Some(t) => {
+                // NOTE: タイムアウトしたら 100 Continue を返さないサーバーとみなしてボディを送る
+                tokio::time::timeout(t, self.wait_continue(decoder))
+                    .await
+                    .unwrap_or(Ok(true))?
+            }
+            None => true,
+        };
+        if send {
+            let OutgoingBody { body, stream } = body;
+            let w = self.conn.get_mut();
+            if let Some(body) = body {
+                w.write_all(&body).await?;
+            }
+            if let Some(stream) = stream {
+                write_stream(w, stream).await?;
+            }
+            w.flush().await?;
+        }
+        Ok(send)
+    }
+
+    // 100 Continue を受け取ったら true を、先に最終的なレスポンスが返ったら false を返す
+    async fn wait_continue(&mut self, decoder: &mut ResponseDecoder) -> Result<bool> {
+        loop {
+            match read_event(decoder, &mut self.conn).await? {
+                Event::Interim(status, header) => {
+                    self.interim(status, &header);
+                    if status == 100 {
+                        return Ok(true);
+                    }
+                }
+                Event::HeadersComplete => return Ok(false),
+                _ => {}
+            }
+        }
+    }
+
+    // ステータス行とヘッダを読み、ボディを読むためのデコーダを返す。1xx は on_interim に渡して読み飛ばす
+    async fn read_head(
+        &mut self,
+        mut decoder: ResponseDecoder,
+        body_sent: bool,
+    ) -> Result<(u32, HttpHeader, ResponseDecoder)> {
+        while decoder.body_kind().is_none() {
+            if let Event::Interim(status, header) = read_event(&mut decoder, &mut self.conn).await?
+            {
+                self.interim(status, &header);
+            }
+        }
+        let mut header = decoder.header().clone();
+        // NOTE: ボディを送らなかったのでこのコネクションは再利用しない
+        if !body_sent {
+            header.remove("connection");
+            header.add("connection", "close");
+        }
+        Ok((decoder.status(), header, decoder))
+    }
+
+    async fn body_reader(
+        &mut self,
+        decoder: ResponseDecoder,
+        header: &mut HttpHeader,
+    ) -> Result<AsyncBodyReader<'_>> {
+        let kind = decoder.body_kind().unwrap_or(BodyKind::Empty);
+        let encodings = take_encodings(self.decompress, &kind, header);
+        self.decoding_reader(&encodings, decoder).await
+    }
+
+    async fn decoding_reader(
+        &mut self,
+        encodings: &[ContentEncoding],
+        decoder: ResponseDecoder,
+    ) -> Result<AsyncBodyReader<'_>> {
+        let max_ratio = self.max_decompression_ratio;
+        let body = decoded_body(decoder, &mut self.conn);
+        if encodings.is_empty() {
+            return Ok(AsyncBodyReader { inner: body });
+        }
+        Ok(AsyncBodyReader {
+            inner: decompressor(body, encodings, max_ratio).await?,
+        })
+    }
+
+    async fn read_response(
+        &mut self,
+        decoder: ResponseDecoder,
+        body_sent: bool,
+    ) -> Result<Response> {
+        let (status, mut header, mut decoder) = self.read_head(decoder, body_sent).await?;
+        let kind = decoder.body_kind().unwrap_or(BodyKind::Empty);
+        let version = decoder.version();
+
+        // NOTE: HttpClient::read_response と同じく、展開しなければ直接ボディへコピーする
+        let encodings = take_encodings(self.decompress, &kind, &mut header);
+        let body = match encodings.is_empty() {
+            true => read_body(&mut decoder, &mut self.conn).await?,
+            false => {
+                let mut body = Vec::new();
+                self.decoding_reader(&encodings, decoder)
+                    .await?
+                    .read_to_end(&mut body)
+                    .await?;
+                body
+            }
+        };
+        fix_header(&kind, !encodings.is_empty(), body.len(), &mut header);
+
+        let body = match body.is_empty() {
+            true => None,
+            false => Some(Body::with_header(body, &header)),
+        };
+        Ok(Response {
+            version,
+            status,
+            header,
+            body,
+        })
+    }
+
+    pub async fn execute_request(&mut self, req: &Request) -> Result<Response> {
+        let timeout = self.timeout;
+        with_timeout(timeout, async {
+            let mut decoder = ResponseDecoder::new(&req.method);
+            let body_sent = self.write_request(req, &mut decoder).await?;
+            self.read_response(decoder, body_sent).await
+        })
+        .await
+    }
+
+    // プールから取り出したコネクションで送る。レスポンスを 1 バイトも受け取らないうちに
+    // コネクションが閉じられたりリセットされたりしたら (サーバーがアイドルのコネクションを閉じていた)、None を返す
+    async fn execute_reused(&mut self, req: &Request) -> Result<Option<Response>> {
+        let timeout = self.timeout;
+        with_timeout(timeout, async {
+            let mut decoder = ResponseDecoder::new(&req.method);
+            let body_sent = match self.write_request(req, &mut decoder).await {
+                Ok(sent) => sent,
+                Err(e) if !decoder.is_started() && is_closed(&e) => return Ok(None),
+                Err(e) => return Err(e),
+            };
+            if !decoder.is_started() {
+                let closed = match self.conn.fill_buf().await {
+                    Ok(buf) => buf.is_empty(),
+                    Err(e) => {
+                        let e = e.into();
+                        if !is_closed(&e) {
+                            return Err(e);
+                        }
+                        true
+                    }
+                };
+                if closed {
+                    return Ok(None);
+                }
+            }
+            self.read_response(decoder, body_sent).await.map(Some)
+        })
+        .await
+    }
+
+    // NOTE: HttpClient::execute_request_stream と同じく、ボディは最後まで読むこと
+    pub async fn execute_request_stream(
+        &mut self,
+        req: &Request,
+    ) -> Result<AsyncStreamResponse<'_>> {
+        let timeout = self.timeout;
+        let (status, mut header, decoder) = with_timeout(timeout, async {
+            let mut decoder = ResponseDecoder::new(&req.method);
+            let body_sent = self.write_request(req, &mut decoder).await?;
+            self.read_head(decoder, body_sent).await
+        })
+        .await?;
+
+        let version = decoder.version();
+        let body = self.body_reader(decoder, &mut header).await?;
+        Ok(AsyncStreamResponse {
+            version,
+            status,
+            header,
+            body,
+        })
+    }
+}
+
+// NOTE: 再送しても安全なメソッドだけ、プールから取り出した古いコネクションで失敗したときに再送する
+fn is_idempotent(req: &Request) -> bool {
+    req.stream.is_none() && req.method.is_idempotent()
+}
+
+// 相手がコネクションを閉じた、またはリセットしたなら true
+fn is_closed(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<io::Error>()
+        .map(|e| {
+            matches!(
+                e.kind(),
+                io::ErrorKind::UnexpectedEof
+                    | io::ErrorKind::BrokenPipe
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+            )
+        })
+        .unwrap_or(false)
+}
+
+// connector::Connector の非同期版。AsyncPool が新しいコネクションを張るときに使う
+pub trait AsyncConnector: Send + Sync {
+    type Conn: AsyncReadWriter;
+
+    fn connect(&self, host: &str, port: u16) -> impl Future<Output = Result<Self::Conn>> + Send;
+}
+
+// NOTE: Connector と同じく、クロージャもそのまま使えるようにしておく
+impl<F, Fut, T> AsyncConnector for F
+where
+    F: Fn(&str, u16) -> Fut + Send + Sync,
+    Fut: Future<Output = Result<T>> + Send,
+    T: AsyncReadWriter,
+{
+    type Conn = T;
+
+    fn connect(&self, host: &str, port: u16) -> impl Future<Output = Result<T>> + Send {
+        self(host, port)
+    }
+}
+
+// resolver を指定しなければ tokio の名前解決を使う
+#[derive(Clone, Default)]
+pub struct AsyncTcpConnector {
+    resolver: Option<Arc<dyn Resolve>>,
+}
+
+impl AsyncTcpConnector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // NOTE: Resolve はブロックするので spawn_blocking で呼ぶ
+    pub fn resolver<R: Resolve + 'static>(&mut self, p: R) -> &mut Self {
+        self.resolver = Some(Arc::new(p));
+        self
+    }
+}
+
+impl AsyncConnector for AsyncTcpConnector {
+    type Conn = TcpStream;
+
+    async fn connect(&self, host: &str, port: u16) -> Result<TcpStream> {
+        let conn = match self.resolver.clone() {
+            Some(resolver) => {
+                let host = host.to_string();
+                let addrs =
+                    tokio::task::spawn_blocking(move || resolver.resolve(&host, port)).await??;
+                TcpStream::connect(&addrs[..]).await?
+            }
+            None => TcpStream::connect((host, port)).await?,
+        };
+        conn.set_nodelay(true)?;
+        Ok(conn)
+    }
+}
+
+#[cfg(unix)]
+#[derive(Debug, Clone)]
+pub struct AsyncUnixConnector {
+    path: PathBuf,
+}
+
+#[cfg(unix)]
+impl AsyncUnixConnector {
+    pub fn new<P: AsRef<std::path::Path>>(path: P) -> Self {
+        Self {
+            path: crate::unix::socket_path(path),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl AsyncConnector for AsyncUnixConnector {
+    type Conn = UnixStream;
+
+    // host と port は使わない
+    async fn connect(&self, _host: &str, _port: u16) -> Result<UnixStream> {
+        UnixStream::connect(&self.path)
+            .await
+            .map_err(|e| anyhow!("cannot connect to {}: {}", self.path.display(), e))
+    }
+}
+
+type Idle<T> = HashMap<(String, u16), Vec<AsyncHttpClient<T>>>;
+
+// host:port ごとにアイドルのコネクションを使い回す
+pub struct AsyncPool<C: AsyncConnector = AsyncTcpConnector> {
+    connector: C,
+    idle: Mutex<Idle<C::Conn>>,
+    max_idle_per_host: usize,
+    timeout: Option<Duration>,
+}
+
+impl Default for AsyncPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AsyncPool {
+    pub fn new() -> Self {
+        Self::with_connector(AsyncTcpConnector::new())
+    }
+}
+
+impl<C: AsyncConnector> AsyncPool<C> {
+    pub fn with_connector(connector: C) -> Self {
+        Self {
+            connector,
+            idle: Mutex::new(HashMap::new()),
+            max_idle_per_host: 8,
+            timeout: None,
+        }
+    }
+
+    pub fn max_idle_per_host(&mut self, p: usize) -> &mut Self {
+        self.max_idle_per_host = p;
+        self
+    }
+
+    pub fn timeout(&mut self, p: Duration) -> &mut Self {
+        self.timeout = Some(p);
+        self
+    }
+
+    pub fn idle_count(&self, host: &str, port: u16) -> usize {
+        self.idle
+            .lock()
+            .unwrap()
+            .get(&(host.to_lowercase(), port))
+            .map(|v| v.len())
+            .unwrap_or(0)
+    }
+
+    fn checkout(&self, host: &str, port: u16) -> Option<AsyncHttpClient<C::Conn>> {
+        self.idle
+            .lock()
+            .unwrap()
+            .get_mut(&(host.to_lowercase(), port))
+            .and_then(|v| v.pop())
+    }
+
+    fn checkin(&self, host: &str, port: u16, client: AsyncHttpClient<C::Conn>) {
+        let mut idle = self.idle.lock().unwrap();
+        let conns = idle.entry((host.to_lowercase(), port)).or_default();
+        if conns.len() < self.max_idle_per_host {
+            conns.push(client);
+        }
+    }
+
+    async fn connect(&self, host: &str, port: u16) -> Result<AsyncHttpClient<C::Conn>> {
+        let conn = with_timeout(self.timeout, self.connector.connect(host, port)).await?;
+
+        let mut client = AsyncHttpClient::new(conn);
+        if let Some(t) = self.timeout {
+            client.timeout(t);
+        }
+        Ok(client)
+    }
+
+    pub async fn execute_request(&self, host: &str, port: u16, req: &Request) -> Result<Response> {
+        if let Some(mut client) = self.checkout(host, port) {
+            // NOTE: レスポンスを受け取り始めてからのエラーは、サーバーが処理したかもしれないので再送しない
+            let resp = match client.execute_reused(req).await? {
+                Some(resp) => resp,
+                None if is_idempotent(req) => return self.execute_new(host, port, req).await,
+                None => bail!("connection was closed before the response"),
+            };
+            if resp.keep_alive() {
+                self.checkin(host, port, client);
+            }
+            return Ok(resp);
+        }
+        self.execute_new(host, port, req).await
+    }
+
+    async fn execute_new(&self, host: &str, port: u16, req: &Request) -> Result<Response> {
+        let mut client = self.connect(host, port).await?;
+        let resp = client.execute_request(req).await?;
+        if resp.keep_alive() {
+            self.checkin(host, port, client);
+        }
+        Ok(resp)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compression::{self, Compression};
+    use crate::method::HttpMethod;
+    use httptest::{matchers::*, responders::*, Expectation, ServerBuilder};
+    use serde::Serialize;
+    use std::net::SocketAddr;
+    use std::sync::atomic::AtomicUsize;
+    use tokio::net::TcpListener;
+
+    #[derive(Serialize)]
+    struct Animal {
+        name: String,
+        age: usize,
+    }
+
+    // コネクションごとに responses を順番に返すモックサーバー。受け付けたコネクション数を数える
+    async fn serve(responses: Vec<Vec<u8>>) -> (SocketAddr, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let responses = Arc::new(Mutex::new(responses.into_iter()));
+
+        let count = accepted.clone();
+        tokio::spawn(async move {
+            loop {
+                let (conn, _) = listener.accept().await.unwrap();
+                count.fetch_add(1, Ordering::SeqCst);
+                let responses = responses.clone();
+                tokio::spawn(async move {
+                    let mut conn = BufReader::new(conn);
+                    loop {
+                        let mut line = String::new();
+                        loop {
+                            line.clear();
+                            if conn.read_line(&mut line).await.unwrap_or(0) == 0 {
+                                return;
+                            }
+                            if line == "\r\n" {
+                                break;
+                            }
+                        }
+                        let resp = responses.lock().unwrap().next();
+                        match resp {
+                            Some(resp) => conn.get_mut().write_all(&resp).await.unwrap(),
+                            // 返すものがなければ応答しない
+                            None => std::future::pending::<()>().await,
+                        }
+                    }
+                });
+            }
+        });
+        (addr, accepted)
+    }
+
+    #[tokio::test]
+    async fn request_get() -> Result<()> {
+        let want_body = r#"{"name": "gorilla", "age": 5}"#;
+
+        let addr: SocketAddr = ([127, 0, 0, 1], 0).into();
+        let server = ServerBuilder::new().bind_addr(addr).run()?;
+        server.expect(
+            Expectation::matching(request::method_path("GET", "/hello")).respond_with(
+                status_code(200)
+                    .append_header("Content-Type", "application/json")
+                    .body(want_body),
+            ),
+        );
+
+        let conn = TcpStream::connect(server.addr()).await?;
+        let mut client = AsyncHttpClient::new(conn);
+        let resp = client.execute_request(&Request::get("/hello")).await?;
+
+        assert_eq!(resp.status, 200);
+        assert_eq!(resp.body.unwrap().text()?, want_body);
+        assert_eq!(resp.header.get("content-type").unwrap(), "application/json");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn request_post() -> Result<()> {
+        let animal = serde_json::to_value(Animal {
+            name: "gorilla".into(),
+            age: 10,
+        })?;
+        let want_body = animal.to_string();
+
+        let addr: SocketAddr = ([127, 0, 0, 1], 0).into();
+        let server = ServerBuilder::new().bind_addr(addr).run()?;
+        server.expect(
+            Expectation::matching(all_of![
+                request::method_path("POST", "/hello"),
+                request::body(want_body.clone()),
+            ])
+            .respond_with(status_code(201).body("true")),
+        );
+
+        let header: HttpHeader = [("Content-Length", want_body.len().to_string().as_str())]
+            .into_iter()
+            .collect();
+        let mut req = Request::post("/hello", animal);
+        req.header(header);
+
+        let conn = TcpStream::connect(server.addr()).await?;
+        let mut client = AsyncHttpClient::new(conn);
+        let resp = client.execute_request(&req).await?;
+        assert_eq!(resp.status, 201);
+        assert_eq!(resp.body.unwrap().text()?, "true");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn request_stream_body() -> Result<()> {
+        let body = "gorilla\n".repeat(10000);
+
+        let addr: SocketAddr = ([127, 0, 0, 1], 0).into();
+        let server = ServerBuilder::new().bind_addr(addr).run()?;
+        server.expect(
+            Expectation::matching(all_of![
+                request::method_path("PUT", "/upload"),
+                request::headers(contains(("transfer-encoding", "chunked"))),
+                request::body(body.clone()),
+            ])
+            .respond_with(status_code(204)),
+        );
+
+        let mut req = Request::new("/upload".into());
+        req.method(HttpMethod::Put)
+            .body_reader(io::Cursor::new(body.into_bytes()), None);
+
+        let conn = TcpStream::connect(server.addr()).await?;
+        let mut client = AsyncHttpClient::new(conn);
+        let resp = client.execute_request(&req).await?;
+        assert_eq!(resp.status, 204);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn response_stream() -> Result<()> {
+        let data = "hello gorilla ".repeat(1000);
+        let encoded = compression::encode(data.as_bytes(), Compression::gzip(6))?;
+
+        let mut resp =
+            b"HTTP/1.1 200 OK\r\nContent-Encoding: gzip\r\nTransfer-Encoding: chunked\r\n\r\n"
+                .to_vec();
+        for chunk in encoded.chunks(100) {
+            resp.extend(format!("{:x}\r\n", chunk.len()).as_bytes());
+            resp.extend(chunk);
+            resp.extend(b"\r\n");
+        }
+        resp.extend(b"0\r\n\r\n");
+        let (addr, _) = serve(vec![resp.clone(), resp]).await;
+
+        let conn = TcpStream::connect(addr).await?;
+        let mut client = AsyncHttpClient::new(conn);
+        let req = Request::get("/stream");
+        {
+            let mut resp = client.execute_request_stream(&req).await?;
+            assert!(resp.header.get("content-encoding").is_none());
+            let mut got = String::new();
+            resp.body.read_to_string(&mut got).await?;
+            assert_eq!(got, data);
+        }
+
+        // 同じコネクションでバッファして読む
+        let resp = client.execute_request(&req).await?;
+        assert_eq!(resp.body.unwrap().text()?, data);
+        assert_eq!(
+            resp.header.get("content-length").unwrap(),
+            &data.len().to_string()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn default_decompression_ratio() -> Result<()> {
+        let bomb = zstd::encode_all(&vec![0u8; 10 * 1024 * 1024][..], 3)?;
+        let mut resp = format!(
+            "HTTP/1.1 200 OK\r\nContent-Encoding: zstd\r\nContent-Length: {}\r\n\r\n",
+            bomb.len()
+        )
+        .into_bytes();
+        resp.extend(bomb);
+        let (addr, _) = serve(vec![resp]).await;
+
+        let mut client = AsyncHttpClient::new(TcpStream::connect(addr).await?);
+        let err = client
+            .execute_request(&Request::get("/bomb"))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("decompression ratio exceeded"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn request_timeout() -> Result<()> {
+        let (addr, _) = serve(vec![]).await;
+
+        let conn = TcpStream::connect(addr).await?;
+        let mut client = AsyncHttpClient::new(conn);
+        client.timeout(Duration::from_millis(100));
+        let err = client
+            .execute_request(&Request::get("/slow"))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("timed out"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pool_reuses_connection() -> Result<()> {
+        let ok = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nOK".to_vec();
+        let close = b"HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 2\r\n\r\nOK".to_vec();
+        let (addr, accepted) = serve(vec![ok.clone(), ok.clone(), close, ok]).await;
+        let port = addr.port();
+
+        let pool = AsyncPool::new();
+        let req = Request::get("/hello");
+        pool.execute_request("127.0.0.1", port, &req).await?;
+        assert_eq!(pool.idle_count("127.0.0.1", port), 1);
+        pool.execute_request("127.0.0.1", port, &req).await?;
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+
+        // Connection: close のコネクションはプールに戻さない
+        pool.execute_request("127.0.0.1", port, &req).await?;
+        assert_eq!(pool.idle_count("127.0.0.1", port), 0);
+
+        let resp = pool.execute_request("127.0.0.1", port, &req).await?;
+        assert_eq!(resp.body.unwrap().text()?, "OK");
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
+        Ok(())
+    }
+
+    // 接続するたびに scripts の先頭を取り出し、リクエストのヘッダを読むたびにその中のレスポンスを順に返す。
+    // 返し終わったらコネクションを閉じる
+    fn scripted(
+        scripts: Vec<Vec<&'static [u8]>>,
+    ) -> (
+        impl AsyncConnector<Conn = tokio::io::DuplexStream>,
+        Arc<AtomicUsize>,
+    ) {
+        let scripts = Arc::new(Mutex::new(scripts.into_iter()));
+        let connected = Arc::new(AtomicUsize::new(0));
+        let count = connected.clone();
+        let connector = move |_host: &str, _port: u16| {
+            count.fetch_add(1, Ordering::SeqCst);
+            let script = scripts.lock().unwrap().next().unwrap_or_default();
+            async move {
+                let (client, server) = tokio::io::duplex(4096);
+                tokio::spawn(async move {
+                    let mut conn = BufReader::new(server);
+                    for resp in script {
+                        let mut line = String::new();
+                        while line != "\r\n" {
+                            line.clear();
+                            if conn.read_line(&mut line).await.unwrap_or(0) == 0 {
+                                return;
+                            }
+                        }
+                        conn.get_mut().write_all(resp).await.unwrap();
+                    }
+                });
+                Ok(client)
+            }
+        };
+        (connector, connected)
+    }
+
+    const OK: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nOK";
+
+    #[tokio::test]
+    async fn pool_with_connector() -> Result<()> {
+        // 2 つ目のリクエストは読んだだけで何も返さずに閉じる
+        let (connector, connected) = scripted(vec![vec![OK, b""], vec![OK]]);
+        let pool = AsyncPool::with_connector(connector);
+        let req = Request::get("/hello");
+        pool.execute_request("example.com", 80, &req).await?;
+        assert_eq!(pool.idle_count("example.com", 80), 1);
+
+        // 閉じられていたので新しいコネクションで送り直す
+        let resp = pool.execute_request("example.com", 80, &req).await?;
+        assert_eq!(resp.body.unwrap().text()?, "OK");
+        assert_eq!(connected.load(Ordering::SeqCst), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pool_does_not_retry_after_response_started() -> Result<()> {
+        let partial = b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nOK";
+        let (connector, connected) = scripted(vec![vec![OK, partial], vec![OK]]);
+        let pool = AsyncPool::with_connector(connector);
+        let req = Request::get("/hello");
+        pool.execute_request("example.com", 80, &req).await?;
+
+        // レスポンスを受け取り始めてから閉じられたので、送り直さずにエラーを返す
+        let err = pool
+            .execute_request("example.com", 80, &req)
+            .await
+            .unwrap_err();
+        assert!(is_closed(&err));
+        assert_eq!(connected.load(Ordering::SeqCst), 1);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn pool_unix_connector() -> Result<()> {
+        let path =
+            std::env::temp_dir().join(format!("http_client-{}-pool.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = tokio::net::UnixListener::bind(&path)?;
+        tokio::spawn(async move {
+            let (conn, _) = listener.accept().await.unwrap();
+            let mut conn = BufReader::new(conn);
+            let mut line = String::new();
+            while line != "\r\n" {
+                line.clear();
+                conn.read_line(&mut line).await.unwrap();
+            }
+            conn.get_mut().write_all(OK).await.unwrap();
+        });
+
+        let pool = AsyncPool::with_connector(AsyncUnixConnector::new(&path));
+        let resp = pool
+            .execute_request("localhost", 0, &Request::get("/_ping"))
+            .await?;
+        assert_eq!(resp.body.unwrap().text()?, "OK");
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pool_with_resolver() -> Result<()> {
+        use crate::resolve::OverrideResolver;
+        use std::net::Ipv4Addr;
+
+        let (addr, accepted) = serve(vec![OK.to_vec()]).await;
+        let mut resolver = OverrideResolver::new();
+        resolver.add("api.internal", Ipv4Addr::LOCALHOST.into());
+        let mut connector = AsyncTcpConnector::new();
+        connector.resolver(resolver);
+
+        let pool = AsyncPool::with_connector(connector);
+        let resp = pool
+            .execute_request("api.internal", addr.port(), &Request::get("/hello"))
+            .await?;
+        assert_eq!(resp.body.unwrap().text()?, "OK");
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn expect_continue() -> Result<()> {
+        let (addr, _) = serve(vec![
+            b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok".to_vec(),
+            b"HTTP/1.1 417 Expectation Failed\r\nContent-Length: 0\r\n\r\n".to_vec(),
+        ])
+        .await;
+        let interim = Arc::new(Mutex::new(Vec::new()));
+        let seen = interim.clone();
+
+        let mut req = Request::new("/upload".into());
+        req.method(HttpMethod::Put)
+            .body(b"gorilla".to_vec())
+            .expect_continue(Duration::from_secs(10));
+
+        let mut client = AsyncHttpClient::new(TcpStream::connect(addr).await?);
+        client.on_interim(move |status, _| seen.lock().unwrap().push(status));
+        let resp = client.execute_request(&req).await?;
+        assert_eq!(resp.status, 200);
+        assert_eq!(*interim.lock().unwrap(), vec![100]);
+
+        // 100 Continue の前に最終的なレスポンスが返ればボディは送らず、コネクションも使い回さない
+        let mut client = AsyncHttpClient::new(TcpStream::connect(addr).await?);
+        let resp = client.execute_request(&req).await?;
+        assert_eq!(resp.status, 417);
+        assert!(!resp.keep_alive());
+        Ok(())
+    }
+}
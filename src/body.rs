@@ -1,26 +1,359 @@
-use anyhow::{anyhow, Result};
-use serde::de::Deserialize;
+use crate::codec::{BodyEncoder, BodyKind, DecoderReader, ResponseDecoder};
+use crate::form::Form;
+use crate::format::Format;
+use crate::header::HttpHeader;
+use anyhow::{anyhow, bail, Result};
+use bytes::Bytes;
+use encoding_rs::{Encoding, UTF_8};
+use serde::de::{Deserialize, DeserializeOwned};
+use std::borrow::Cow;
+use std::io::{self, BufRead, Read, Write};
+use std::sync::Mutex;
 
+// NOTE: データは Bytes で持つので、clone してもコピーされない
 #[derive(Debug, Clone)]
 pub struct Body {
-    data: Vec<u8>,
+    data: Bytes,
+    // text で文字コードを決めるために、レスポンスの Content-Type を覚えておく
+    content_type: Option<String>,
 }
 
 impl Body {
-    pub fn new(data: Vec<u8>) -> Self {
-        Self { data }
+    pub fn new<T: Into<Bytes>>(data: T) -> Self {
+        Self {
+            data: data.into(),
+            content_type: None,
+        }
     }
 
-    pub fn raw(&self) -> Vec<u8> {
+    // header の Content-Type を覚えておく
+    pub fn with_header<T: Into<Bytes>>(data: T, header: &HttpHeader) -> Self {
+        Self {
+            data: data.into(),
+            content_type: header.get_ignore_case("content-type").cloned(),
+        }
+    }
+
+    pub fn content_type(&self) -> Option<&str> {
+        self.content_type.as_deref()
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    // データを共有したまま Bytes を返す
+    pub fn bytes(&self) -> Bytes {
         self.data.clone()
     }
 
+    pub fn into_bytes(self) -> Bytes {
+        self.data
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    // NOTE: Vec<u8> にコピーする。コピーしたくなければ as_bytes か bytes を使う
+    pub fn raw(&self) -> Vec<u8> {
+        self.data.to_vec()
+    }
+
+    // Content-Type の charset パラメータの値
+    pub fn charset(&self) -> Option<&str> {
+        self.content_type
+            .as_deref()?
+            .split(';')
+            .skip(1)
+            .filter_map(|p| p.trim().split_once('='))
+            .find(|(k, _)| k.trim().eq_ignore_ascii_case("charset"))
+            .map(|(_, v)| v.trim().trim_matches('"'))
+    }
+
+    // NOTE: WHATWG Encoding の decode と同じく BOM を charset より優先する。
+    // charset が無いか知らないラベルなら UTF-8 とみなす
+    fn encoding(&self) -> (&'static Encoding, usize) {
+        if let Some(bom) = Encoding::for_bom(&self.data) {
+            return bom;
+        }
+        let encoding = self
+            .charset()
+            .and_then(|label| Encoding::for_label(label.as_bytes()))
+            .unwrap_or(UTF_8);
+        (encoding, 0)
+    }
+
+    // charset に従ってデコードする。不正なバイト列があればエラーにする
     pub fn text(&self) -> Result<String> {
-        Ok(String::from_utf8(self.data.clone())?)
+        Ok(self.as_text()?.into_owned())
+    }
+
+    // text と同じだが、UTF-8 ならコピーせずに借りた文字列を返す
+    pub fn as_text(&self) -> Result<Cow<'_, str>> {
+        let (encoding, bom) = self.encoding();
+        decode_strict(encoding, &self.data[bom..])
     }
 
+    // text と同じだが、BOM の無い UTF-8 ならバッファを使い回す
+    pub fn into_text(self) -> Result<String> {
+        let (encoding, bom) = self.encoding();
+        if encoding != UTF_8 || bom > 0 {
+            return self.text();
+        }
+        String::from_utf8(Vec::from(self.data))
+            .map_err(|_| anyhow!("body is not valid {}", encoding.name()))
+    }
+
+    // charset に従ってデコードし、不正なバイト列は U+FFFD に置き換える
+    pub fn text_lossy(&self) -> String {
+        let (encoding, bom) = self.encoding();
+        let (text, _) = encoding.decode_without_bom_handling(&self.data[bom..]);
+        text.into_owned()
+    }
+
+    // text_lossy と同じく charset でデコードした先頭 EXCERPT_CHARS 文字
+    fn excerpt(&self) -> String {
+        let (encoding, bom) = self.encoding();
+        // NOTE: どの文字コードでも 1 文字は 4 バイトまでなので、先頭だけデコードすれば足りる
+        let data = &self.data[bom..];
+        let head = &data[..data.len().min(EXCERPT_CHARS * 4)];
+        let (text, _) = encoding.decode_without_bom_handling(head);
+        let mut excerpt: String = text.chars().take(EXCERPT_CHARS).collect();
+        if head.len() < data.len() || text.chars().nth(EXCERPT_CHARS).is_some() {
+            excerpt.push_str("...");
+        }
+        excerpt
+    }
+
+    // charset を無視して label の文字コードでデコードする
+    pub fn text_with_charset(&self, label: &str) -> Result<String> {
+        let encoding = Encoding::for_label(label.as_bytes())
+            .ok_or_else(|| anyhow!("unknown charset: {}", label))?;
+        Ok(decode_strict(encoding, &self.data)?.into_owned())
+    }
+
+    // charset も BOM も見ずに UTF-8 としてデコードする
+    pub fn text_utf8(&self) -> Result<&str> {
+        Ok(std::str::from_utf8(&self.data)?)
+    }
+
+    // 失敗したら DecodeError を返す。Response::json ならステータスも入る
     pub fn json<T: for<'b> Deserialize<'b>>(&self) -> Result<T> {
-        serde_json::from_slice(self.data.as_slice()).map_err(|x| anyhow!("{}", x))
+        let mut de = serde_json::Deserializer::from_slice(&self.data);
+        let value = serde_path_to_error::deserialize(&mut de).map_err(|e| {
+            let path = e.path().to_string();
+            self.decode_error(Format::Json, path, e.into_inner().to_string())
+        })?;
+        // NOTE: serde_json::from_slice と同じく、値の後ろに空白以外があればエラーにする
+        de.end()
+            .map_err(|e| self.decode_error(Format::Json, ".".into(), e.to_string()))?;
+        Ok(value)
+    }
+
+    fn decode_error(&self, format: Format, path: String, message: String) -> DecodeError {
+        DecodeError {
+            format,
+            path,
+            message,
+            status: None,
+            content_type: self.content_type.clone(),
+            excerpt: self.excerpt(),
+        }
+    }
+
+    // application/x-www-form-urlencoded のボディを構造体にする
+    pub fn form<T: DeserializeOwned>(&self) -> Result<T> {
+        Form::parse(&self.as_text()?)?.deserialize()
+    }
+
+    #[cfg(feature = "msgpack")]
+    pub fn msgpack<T: DeserializeOwned>(&self) -> Result<T> {
+        rmp_serde::from_slice(&self.data).map_err(|x| anyhow!("{}", x))
+    }
+
+    #[cfg(feature = "cbor")]
+    pub fn cbor<T: DeserializeOwned>(&self) -> Result<T> {
+        ciborium::from_reader(self.as_bytes()).map_err(|x| anyhow!("{}", x))
+    }
+
+    // NOTE: XML 宣言の encoding ではなく、Content-Type の charset でデコードする
+    #[cfg(feature = "xml")]
+    pub fn xml<T: DeserializeOwned>(&self) -> Result<T> {
+        quick_xml::de::from_str(&self.as_text()?).map_err(|x| anyhow!("{}", x))
+    }
+
+    // Content-Type を見て json, form, msgpack, cbor, xml のどれでデコードするか決める
+    pub fn decode<T: DeserializeOwned>(&self) -> Result<T> {
+        let content_type = self
+            .content_type()
+            .ok_or_else(|| anyhow!("content-type is missing"))?;
+        let format = Format::from_content_type(content_type)
+            .ok_or_else(|| anyhow!("unsupported content-type: {}", content_type))?;
+        match format {
+            Format::Json => self.json(),
+            Format::Form => self.form(),
+            #[cfg(feature = "msgpack")]
+            Format::MsgPack => self.msgpack(),
+            #[cfg(feature = "cbor")]
+            Format::Cbor => self.cbor(),
+            #[cfg(feature = "xml")]
+            Format::Xml => self.xml(),
+            #[allow(unreachable_patterns)]
+            _ => bail!(
+                "cannot decode {}: the `{}` feature is not enabled",
+                content_type,
+                format.feature().unwrap_or_default()
+            ),
+        }
+    }
+}
+
+// NOTE: エラーページ全体を載せないように、エラーに含めるボディはこの文字数までにする
+const EXCERPT_CHARS: usize = 200;
+
+// ボディを構造体にデコードできなかったときのエラー。anyhow::Error から downcast して使う
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodeError {
+    pub format: Format,
+    // 失敗したフィールドへのパス。`items[0].name` のように書き、ルートなら `.`
+    pub path: String,
+    pub message: String,
+    pub status: Option<u32>,
+    pub content_type: Option<String>,
+    // ボディの先頭 EXCERPT_CHARS 文字
+    pub excerpt: String,
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "cannot decode {} body at {}: {}",
+            self.format, self.path, self.message
+        )?;
+        if let Some(status) = self.status {
+            write!(f, ", status: {}", status)?;
+        }
+        if let Some(content_type) = &self.content_type {
+            write!(f, ", content-type: {}", content_type)?;
+        }
+        write!(f, ", body: {:?}", self.excerpt)
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+fn decode_strict<'a>(encoding: &'static Encoding, data: &'a [u8]) -> Result<Cow<'a, str>> {
+    encoding
+        .decode_without_bom_handling_and_without_replacement(data)
+        .ok_or_else(|| anyhow!("body is not valid {}", encoding.name()))
+}
+
+pub struct BodyReader<'a> {
+    inner: Box<dyn BufRead + 'a>,
+}
+
+impl<'a> BodyReader<'a> {
+    pub fn new<R: BufRead + 'a>(inner: R) -> Self {
+        Self {
+            inner: Box::new(inner),
+        }
+    }
+
+    // NOTE: BodyReader はヘッダを持たないので、Content-Type の無い Body になる。
+    // charset を使いたければ StreamResponse::into_response を使う
+    pub fn into_body(mut self) -> Result<Body> {
+        let mut data = Vec::new();
+        self.inner.read_to_end(&mut data)?;
+        Ok(Body::new(data))
+    }
+}
+
+impl Read for BodyReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl BufRead for BodyReader<'_> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.inner.consume(amt)
+    }
+}
+
+// リクエストボディをメモリに載せずに送るためのストリーム。一度送ると空になる
+pub struct StreamBody {
+    reader: Mutex<Option<Box<dyn Read + Send>>>,
+    length: Option<u64>,
+}
+
+impl StreamBody {
+    // length が分からない場合は Transfer-Encoding: chunked で送る
+    pub fn new<R: Read + Send + 'static>(reader: R, length: Option<u64>) -> Self {
+        Self {
+            reader: Mutex::new(Some(Box::new(reader))),
+            length,
+        }
+    }
+
+    pub fn length(&self) -> Option<u64> {
+        self.length
+    }
+
+    pub(crate) fn take(&self) -> Option<Box<dyn Read + Send>> {
+        self.reader.lock().unwrap().take()
+    }
+}
+
+impl std::fmt::Debug for StreamBody {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StreamBody")
+            .field("length", &self.length)
+            .finish()
+    }
+}
+
+pub fn write_chunked<R: Read, W: Write>(r: &mut R, w: &mut W) -> io::Result<u64> {
+    let mut encoder = BodyEncoder::new(None);
+    let mut buf = vec![0u8; 8 * 1024];
+    loop {
+        let n = match r.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        w.write_all(&encoder.encode(&buf[..n]))?;
+    }
+    w.write_all(&encoder.finish().map_err(io::Error::other)?)?;
+    Ok(encoder.written())
+}
+
+// Transfer-Encoding: chunked のボディをデコードしながら読む
+pub struct ChunkedReader<R> {
+    inner: DecoderReader<R>,
+}
+
+impl<R: BufRead> ChunkedReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner: DecoderReader::new(ResponseDecoder::body(BodyKind::Chunked), inner),
+        }
+    }
+}
+
+impl<R: BufRead> Read for ChunkedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
     }
 }
 
@@ -36,9 +369,7 @@ mod test {
             name: String,
             age: usize,
         }
-        let body = Body {
-            data: r#"{"name": "gorilla", "age": 5}"#.as_bytes().to_vec(),
-        };
+        let body = Body::new(r#"{"name": "gorilla", "age": 5}"#.as_bytes().to_vec());
 
         let got: Gorilla = body.json()?;
         let want = Gorilla {
@@ -48,4 +379,211 @@ mod test {
         assert_eq!(want, got);
         Ok(())
     }
+
+    fn body(data: &[u8], content_type: &str) -> Body {
+        let mut header = HttpHeader::new();
+        header.add("content-type", content_type);
+        Body::with_header(data.to_vec(), &header)
+    }
+
+    #[test]
+    fn share_bytes() -> Result<()> {
+        let body = Body::new("ゴリラ".as_bytes().to_vec());
+        let cloned = body.clone();
+        // clone してもデータは同じバッファを指す
+        assert_eq!(body.as_bytes().as_ptr(), cloned.as_bytes().as_ptr());
+        assert_eq!(body.bytes().as_ptr(), body.as_bytes().as_ptr());
+
+        assert!(matches!(body.as_text()?, Cow::Borrowed("ゴリラ")));
+        assert_eq!(cloned.into_text()?, "ゴリラ");
+        assert_eq!(body.len(), 9);
+        assert_eq!(body.into_bytes(), Bytes::from("ゴリラ"));
+        Ok(())
+    }
+
+    #[test]
+    fn json_decode_error() {
+        #[derive(Deserialize, Debug)]
+        #[allow(dead_code)]
+        struct Item {
+            name: String,
+        }
+        #[derive(Deserialize, Debug)]
+        #[allow(dead_code)]
+        struct Items {
+            items: Vec<Item>,
+        }
+        let body = Body::new(r#"{"items": [{"name": "a"}, {"name": 1}]}"#);
+        let err = body.json::<Items>().unwrap_err();
+        let err = err.downcast_ref::<DecodeError>().unwrap();
+        assert_eq!(err.path, "items[1].name");
+        assert_eq!(err.excerpt, r#"{"items": [{"name": "a"}, {"name": 1}]}"#);
+
+        // HTML のエラーページが返ってきた場合
+        let header: HttpHeader = [("Content-Type", "text/html")].into_iter().collect();
+        let page = format!("<html>{}</html>", "x".repeat(300));
+        let err = Body::with_header(page, &header)
+            .json::<Items>()
+            .unwrap_err();
+        let err = err.downcast_ref::<DecodeError>().unwrap();
+        assert_eq!(err.path, ".");
+        assert_eq!(err.content_type.as_deref(), Some("text/html"));
+        assert_eq!(err.excerpt, format!("<html>{}...", "x".repeat(194)));
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "cannot decode JSON body at .: expected value at line 1 column 1, \
+                 content-type: text/html, body: \"<html>{}...\"",
+                "x".repeat(194)
+            )
+        );
+
+        // Shift_JIS のエラーページも charset に従ってデコードする
+        let header: HttpHeader = [("Content-Type", "text/html; charset=Shift_JIS")]
+            .into_iter()
+            .collect();
+        let (page, _, _) = encoding_rs::SHIFT_JIS.encode("<p>エラー</p>");
+        let err = Body::with_header(page.into_owned(), &header)
+            .json::<Items>()
+            .unwrap_err();
+        let err = err.downcast_ref::<DecodeError>().unwrap();
+        assert_eq!(err.excerpt, "<p>エラー</p>");
+
+        let err = Body::new("{} {}").json::<serde_json::Value>().unwrap_err();
+        assert!(err.to_string().contains("trailing characters"), "{}", err);
+    }
+
+    #[test]
+    fn decode_by_content_type() -> Result<()> {
+        #[derive(Deserialize, Debug, PartialEq, Eq)]
+        struct Gorilla {
+            name: String,
+        }
+        let body = |ct: &str, data: &'static str| {
+            let header: HttpHeader = [("Content-Type", ct)].into_iter().collect();
+            Body::with_header(data, &header)
+        };
+        let want = Gorilla {
+            name: "gorilla".into(),
+        };
+        let json = body("application/vnd.api+json", r#"{"name":"gorilla"}"#);
+        assert_eq!(json.decode::<Gorilla>()?, want);
+        let form = body("application/x-www-form-urlencoded", "name=gorilla");
+        assert_eq!(form.decode::<Gorilla>()?, want);
+
+        let err = body("text/plain", "gorilla")
+            .decode::<Gorilla>()
+            .unwrap_err();
+        assert_eq!(err.to_string(), "unsupported content-type: text/plain");
+        let err = Body::new("{}").decode::<Gorilla>().unwrap_err();
+        assert_eq!(err.to_string(), "content-type is missing");
+        #[cfg(not(feature = "cbor"))]
+        {
+            let err = body("application/cbor", "")
+                .decode::<Gorilla>()
+                .unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "cannot decode application/cbor: the `cbor` feature is not enabled"
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn text_with_charset() -> Result<()> {
+        // "日本語" の Shift_JIS
+        let sjis = body(
+            b"\x93\xfa\x96\x7b\x8c\xea",
+            "text/html; charset=\"Shift_JIS\"",
+        );
+        assert_eq!(sjis.charset(), Some("Shift_JIS"));
+        assert_eq!(sjis.text()?, "日本語");
+        assert!(sjis.text_utf8().is_err());
+
+        let latin1 = body(b"caf\xe9", "text/plain; charset=iso-8859-1");
+        assert_eq!(latin1.text()?, "café");
+
+        // BOM は charset より優先する
+        let bom = body(b"\xff\xfeh\x00i\x00", "text/plain; charset=utf-8");
+        assert_eq!(bom.text()?, "hi");
+
+        // charset が無ければ UTF-8
+        assert_eq!(Body::new("日本".as_bytes().to_vec()).text()?, "日本");
+
+        assert_eq!(
+            body(b"caf\xe9", "text/plain").text_with_charset("windows-1252")?,
+            "café"
+        );
+        assert!(body(b"x", "text/plain")
+            .text_with_charset("no-such")
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn text_invalid_bytes() {
+        let broken = body(b"ok\xff", "text/plain; charset=utf-8");
+        assert_eq!(
+            broken.text().unwrap_err().to_string(),
+            "body is not valid UTF-8"
+        );
+        assert_eq!(broken.text_lossy(), "ok\u{fffd}");
+    }
+
+    #[test]
+    fn read_as_form() -> Result<()> {
+        #[derive(Deserialize, Debug, PartialEq, Eq)]
+        struct Token {
+            access_token: String,
+            expires_in: u64,
+        }
+        let body = Body::new(b"access_token=a%2Fb+c&expires_in=3600".to_vec());
+
+        let got: Token = body.form()?;
+        let want = Token {
+            access_token: "a/b c".into(),
+            expires_in: 3600,
+        };
+        assert_eq!(want, got);
+        Ok(())
+    }
+
+    #[test]
+    fn read_chunked() -> Result<()> {
+        let data = "4\r\nWiki\r\n6;ext=1\r\npedia \r\nE\r\nin \r\n\r\nchunks.\r\n0\r\nExpires: never\r\n\r\nrest";
+        let mut r = io::BufReader::new(data.as_bytes());
+        let mut got = String::new();
+        ChunkedReader::new(&mut r).read_to_string(&mut got)?;
+        assert_eq!(got, "Wikipedia in \r\n\r\nchunks.");
+
+        // 後続のデータは読まれずに残っていること
+        let mut rest = String::new();
+        r.read_to_string(&mut rest)?;
+        assert_eq!(rest, "rest");
+        Ok(())
+    }
+
+    #[test]
+    fn write_and_read_chunked() -> Result<()> {
+        let data = vec![b'x'; 20 * 1024];
+        let mut encoded = Vec::new();
+        let written = write_chunked(&mut data.as_slice(), &mut encoded)?;
+        assert_eq!(written, data.len() as u64);
+        assert!(encoded.starts_with(b"2000\r\n"));
+        assert!(encoded.ends_with(b"\r\n0\r\n\r\n"));
+
+        let mut decoded = Vec::new();
+        ChunkedReader::new(encoded.as_slice()).read_to_end(&mut decoded)?;
+        assert_eq!(decoded, data);
+        Ok(())
+    }
+
+    #[test]
+    fn read_truncated_chunked() {
+        let data = "a\r\nshort";
+        let mut got = Vec::new();
+        let result = ChunkedReader::new(data.as_bytes()).read_to_end(&mut got);
+        assert!(result.is_err());
+    }
 }
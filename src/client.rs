@@ -1,10 +1,13 @@
-use crate::body::Body;
+use crate::body::{Body, BodyReader};
+use crate::codec::{read_body, read_event, BodyKind, DecoderReader, Event, ResponseDecoder};
+use crate::compression::{self, ContentEncoding, ACCEPT_ENCODING, DEFAULT_MAX_DECOMPRESSION_RATIO};
 use crate::header::*;
-use crate::method::HttpMethod;
 use crate::request::*;
 use crate::response::*;
-use anyhow::{anyhow, bail, Context, Result};
+use crate::upgrade::Upgraded;
+use anyhow::{bail, Result};
 use std::io::{self, BufRead, BufReader, Read};
+use std::time::Duration;
 
 pub trait ReadWriter: io::Read + io::Write {}
 
@@ -14,160 +17,471 @@ pub trait ReadWriter: io::Read + io::Write {}
 impl<T> ReadWriter for T where T: io::Read + io::Write {}
 
 pub struct HttpClient<T: ReadWriter> {
-    conn: T,
+    // NOTE: レスポンスごとに BufReader を作ると先読みしたバイトが捨てられてしまうので
+    // コネクションと一緒に持っておく
+    conn: BufReader<T>,
+    decompress: bool,
+    max_decompression_ratio: Option<u64>,
+    pipeline_non_idempotent: bool,
+    pipeline_window: usize,
+    on_interim: Option<InterimFn>,
+    read_timeout_fn: Option<(GetReadTimeoutFn<T>, ReadTimeoutFn<T>)>,
+}
+
+pub(crate) type InterimFn = Box<dyn FnMut(u32, &HttpHeader) + Send>;
+
+// NOTE: 全部送ってから読むと、サーバーの送信バッファが埋まったときにお互いが書き込みで止まってしまうので、
+// レスポンスを待っているリクエストがこれだけになったら先にレスポンスを読む
+const DEFAULT_PIPELINE_WINDOW: usize = 8;
+
+// コネクションの読み込みタイムアウトを取得・設定する関数。TcpStream::read_timeout と
+// TcpStream::set_read_timeout などを渡す
+pub type GetReadTimeoutFn<T> = fn(&T) -> io::Result<Option<Duration>>;
+pub type ReadTimeoutFn<T> = fn(&T, Option<Duration>) -> io::Result<()>;
+
+fn is_timeout(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<io::Error>()
+        .map(|e| {
+            matches!(
+                e.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            )
+        })
+        .unwrap_or(false)
+}
+
+// pipeline の結果。responses[i] は requests[i] へのレスポンスで、
+// 途中でコネクションが閉じられて応答されなかったリクエストの添字が retry に入る
+#[derive(Debug)]
+pub struct Pipelined {
+    pub responses: Vec<Response>,
+    pub retry: Vec<usize>,
+}
+
+// NOTE: 展開したボディは長さが変わるので、展開する場合は Content-Encoding と Content-Length を取り除く
+pub(crate) fn take_encodings(
+    decompress: bool,
+    kind: &BodyKind,
+    header: &mut HttpHeader,
+) -> Vec<ContentEncoding> {
+    let encodings = match header.get("content-encoding") {
+        Some(value) if decompress && !matches!(kind, BodyKind::Empty | BodyKind::Length(0)) => {
+            // 知らないエンコーディングの場合はそのまま返す
+            compression::parse_encodings(value).unwrap_or_default()
+        }
+        _ => Vec::new(),
+    };
+    if !encodings.is_empty() {
+        header.remove("content-encoding");
+        header.remove("content-length");
+    }
+    encodings
+}
+
+// NOTE: ボディをバッファしたので、Content-Length をその長さに付け直す。
+// また、閉じられるまで読んだコネクションは再利用できないので Connection: close を付けておく
+pub(crate) fn fix_header(kind: &BodyKind, decoded: bool, len: usize, header: &mut HttpHeader) {
+    if matches!(kind, BodyKind::Chunked | BodyKind::Close) || decoded {
+        header.add("content-length", len.to_string().as_str());
+        header.remove("transfer-encoding")
+    }
+    if *kind == BodyKind::Close {
+        header.remove("connection");
+        header.add("connection", "close");
+    }
 }
 
 impl<T: ReadWriter> HttpClient<T> {
     pub fn new(conn: T) -> Self {
-        HttpClient { conn }
+        HttpClient {
+            conn: BufReader::new(conn),
+            decompress: true,
+            max_decompression_ratio: Some(DEFAULT_MAX_DECOMPRESSION_RATIO),
+            pipeline_non_idempotent: false,
+            pipeline_window: DEFAULT_PIPELINE_WINDOW,
+            on_interim: None,
+            read_timeout_fn: None,
+        }
     }
 
-    fn read_response(&mut self, req: &Request) -> Result<Response> {
-        let mut r = BufReader::new(&mut self.conn);
-        let mut buf = Vec::new();
-
-        // read status line
-        r.read_until(b'\n', &mut buf).unwrap();
-        let status_line = String::from_utf8(buf.clone())?;
+    // false にすると Accept-Encoding を送らず、Content-Encoding が付いていても生のバイト列を返す
+    pub fn decompress(&mut self, p: bool) -> &mut Self {
+        self.decompress = p;
+        self
+    }
 
-        let status = status_line
-            .split_whitespace()
-            .nth(1)
-            .ok_or_else(|| anyhow!("cannot get status code"))?
-            .parse::<u32>()?;
+    // 展開後のサイズが圧縮されたサイズの p 倍を超えたらエラーにする (zip bomb 対策)。
+    // デフォルトは DEFAULT_MAX_DECOMPRESSION_RATIO で、None にすると確認しない
+    pub fn max_decompression_ratio(&mut self, p: Option<u64>) -> &mut Self {
+        self.max_decompression_ratio = p;
+        self
+    }
 
-        // read headers
-        let mut header = HttpHeader::default();
-        loop {
-            buf.clear();
-            let readed = r.read_until(b'\n', &mut buf)?;
+    // true にすると POST など冪等でないメソッドもパイプラインで送る
+    pub fn pipeline_non_idempotent(&mut self, p: bool) -> &mut Self {
+        self.pipeline_non_idempotent = p;
+        self
+    }
 
-            if readed == 0 {
-                bail!("unexpected endof");
-            }
+    // pipeline でレスポンスを待たずに送るリクエストの数。1 ならパイプラインしない
+    pub fn pipeline_window(&mut self, p: usize) -> &mut Self {
+        self.pipeline_window = p.max(1);
+        self
+    }
 
-            let mut line = String::from_utf8(buf.clone())?;
-            if line == "\r\n" {
-                break;
-            }
-            line = line.trim().to_string();
+    // 100 Continue や 103 Early Hints などの 1xx を受け取ったときに呼ばれる
+    pub fn on_interim<F: FnMut(u32, &HttpHeader) + Send + 'static>(&mut self, f: F) -> &mut Self {
+        self.on_interim = Some(Box::new(f));
+        self
+    }
 
-            let mut cols = line.split(": ");
-            let key = cols
-                .next()
-                .ok_or_else(|| anyhow!("invalid header key"))?
-                .to_lowercase();
-            let key = key.as_str();
-            let val = cols.next().ok_or_else(|| anyhow!("invalid header value"))?;
+    // Request::expect_continue で 100 Continue を待つときに使う。これが無いと expect_continue は送れない。
+    // 待つ前に get で読み込みタイムアウトを覚えておき、待ち終わったら元に戻す
+    pub fn read_timeout_fn(
+        &mut self,
+        get: GetReadTimeoutFn<T>,
+        set: ReadTimeoutFn<T>,
+    ) -> &mut Self {
+        self.read_timeout_fn = Some((get, set));
+        self
+    }
 
-            header.add(key, val);
+    fn default_header(&self) -> HttpHeader {
+        let mut header = HttpHeader::new();
+        if self.decompress {
+            header.add("Accept-Encoding", ACCEPT_ENCODING);
         }
+        header
+    }
 
-        match status {
-            204 | 304 => {
-                let resp = Response {
-                    status,
-                    header,
-                    body: None,
-                };
-                return Ok(resp);
-            }
-            _ => {}
+    fn interim(&mut self, status: u32, header: &HttpHeader) {
+        if let Some(f) = self.on_interim.as_mut() {
+            f(status, header);
         }
+    }
 
-        let must_read_body = !matches!(req.method, HttpMethod::Head | HttpMethod::Options);
-        let tf = header.get("transfer-encoding");
-        let cl = header.get("content-length");
+    // リクエストを送り、レスポンスを読むためのデコーダと、ボディを送ったかどうかを返す
+    fn send_request(
+        &mut self,
+        req: &Request,
+        extra: &HttpHeader,
+    ) -> Result<(ResponseDecoder, bool)> {
+        let decoder = ResponseDecoder::new(&req.method);
+        let timeout = match req.continue_timeout() {
+            Some(timeout) => timeout,
+            None => {
+                req.write_to(self.conn.get_mut(), extra)?;
+                return Ok((decoder, true));
+            }
+        };
 
-        if must_read_body && tf.is_none() && cl.is_none() {
-            bail!("missing transfer-encoding or content-length");
+        // NOTE: 待てないままボディを送ってしまうと Expect を付ける意味がないので、何も送らずにエラーにする
+        if self.read_timeout_fn.is_none() {
+            bail!("expect_continue requires read_timeout_fn to wait for 100 Continue");
         }
-        let mut body = Vec::new();
-
-        if must_read_body {
-            let is_chunked = tf.map(|x| *x == "chunked").unwrap_or(false);
-
-            let mut content_length: usize = 0;
+        let mut extra = extra.clone();
+        extra.add("Expect", "100-continue");
+        let (head, body) = req.prepare_head(&extra)?;
+        self.conn.get_mut().write_all(&head)?;
+        self.conn.get_mut().flush()?;
+        let (decoder, send) = self.wait_continue(decoder, timeout)?;
+        if send {
+            body.write_to(self.conn.get_mut())?;
+        }
+        Ok((decoder, send))
+    }
 
-            if is_chunked {
-                // read body
-                loop {
-                    buf.clear();
-                    let readed = r.read_until(b'\n', &mut buf).unwrap();
-                    content_length += readed;
-                    if readed == 0 {
-                        break;
+    // 100 Continue を受け取るかタイムアウトしたら true を、先に最終的なレスポンスが返ったら false を返す
+    fn wait_continue(
+        &mut self,
+        mut decoder: ResponseDecoder,
+        timeout: Duration,
+    ) -> Result<(ResponseDecoder, bool)> {
+        let (get_timeout, set_timeout) = match self.read_timeout_fn {
+            Some(f) => f,
+            None => bail!("read_timeout_fn is not set"),
+        };
+        let previous = get_timeout(self.conn.get_ref())?;
+        set_timeout(self.conn.get_ref(), Some(timeout))?;
+        let result = loop {
+            match read_event(&mut decoder, &mut self.conn) {
+                Ok(Event::Interim(status, header)) => {
+                    self.interim(status, &header);
+                    if status == 100 {
+                        break Ok(true);
                     }
+                }
+                Ok(Event::HeadersComplete) => break Ok(false),
+                Ok(_) => {}
+                Err(e) if is_timeout(&e) => break Ok(true),
+                Err(e) => break Err(e),
+            }
+        };
+        set_timeout(self.conn.get_ref(), previous)?;
+        Ok((decoder, result?))
+    }
 
-                    let line = String::from_utf8(buf.clone())
-                        .context("cannot coonvert bytes to string")?;
-                    let chunk_size = i64::from_str_radix(line.trim(), 16)
-                        .context(format!("cannot read chunk length: {}", line))?;
+    // ステータス行とヘッダを読み、ボディを読むためのデコーダを返す。1xx は on_interim に渡して読み飛ばす。
+    // ボディを送らなかった場合、サーバーはまだボディを待っているかもしれないので Connection: close を付ける
+    fn read_head(
+        &mut self,
+        mut decoder: ResponseDecoder,
+        body_sent: bool,
+    ) -> Result<(u32, HttpHeader, ResponseDecoder)> {
+        while decoder.body_kind().is_none() {
+            if let Event::Interim(status, header) = read_event(&mut decoder, &mut self.conn)? {
+                self.interim(status, &header);
+            }
+        }
+        let mut header = decoder.header().clone();
+        if !body_sent {
+            header.remove("connection");
+            header.add("connection", "close");
+        }
+        Ok((decoder.status(), header, decoder))
+    }
 
-                    if chunk_size == 0 {
-                        let _ = r.read_until(b'\n', &mut buf);
-                        break;
-                    }
+    // r (コネクション) からボディを読み、必要なら展開する BodyReader を作る
+    fn body_reader<'a, R: BufRead + 'a>(
+        decompress: bool,
+        max_ratio: Option<u64>,
+        decoder: ResponseDecoder,
+        r: R,
+        header: &mut HttpHeader,
+    ) -> Result<BodyReader<'a>> {
+        let kind = decoder.body_kind().unwrap_or(BodyKind::Empty);
+        let encodings = take_encodings(decompress, &kind, header);
+        Self::decoding_reader(&encodings, max_ratio, decoder, r)
+    }
 
-                    let mut chunk = vec![0u8; chunk_size as usize];
-                    r.read_exact(&mut chunk).unwrap();
-                    body.append(&mut chunk);
+    fn decoding_reader<'a, R: BufRead + 'a>(
+        encodings: &[ContentEncoding],
+        max_ratio: Option<u64>,
+        decoder: ResponseDecoder,
+        r: R,
+    ) -> Result<BodyReader<'a>> {
+        let body = BufReader::new(DecoderReader::new(decoder, r));
+        if encodings.is_empty() {
+            return Ok(BodyReader::new(body));
+        }
+        Ok(BodyReader::new(compression::decoder(
+            body, encodings, max_ratio,
+        )?))
+    }
 
-                    // consume \r\n
-                    let _ = r.read_until(b'\n', &mut buf);
-                }
-            } else {
-                let value = header.get("content-length");
-                if value.is_none() {
-                    bail!("not found content-length");
-                }
-                let value = value.unwrap().parse::<usize>();
-
-                match value {
-                    Ok(size) => {
-                        content_length = size;
-                        let mut buf = vec![0u8; size];
-                        r.read_exact(&mut buf).unwrap();
-                        body = buf;
-                    }
-                    Err(e) => {
-                        bail!(e.to_string());
-                    }
-                };
-            }
+    fn decoded_body_reader(
+        &mut self,
+        decoder: ResponseDecoder,
+        header: &mut HttpHeader,
+    ) -> Result<BodyReader<'_>> {
+        Self::body_reader(
+            self.decompress,
+            self.max_decompression_ratio,
+            decoder,
+            &mut self.conn,
+            header,
+        )
+    }
 
-            if is_chunked {
-                header.add("content-length", content_length.to_string().as_str());
-                header.remove("transfer-encoding")
+    fn read_response(&mut self, decoder: ResponseDecoder, body_sent: bool) -> Result<Response> {
+        let (status, mut header, mut decoder) = self.read_head(decoder, body_sent)?;
+        let kind = decoder.body_kind().unwrap_or(BodyKind::Empty);
+        let version = decoder.version();
+
+        // NOTE: 展開しなくてよければ、コネクションのバッファから直接ボディへコピーする
+        let encodings = take_encodings(self.decompress, &kind, &mut header);
+        let body = match encodings.is_empty() {
+            true => read_body(&mut decoder, &mut self.conn)?,
+            false => {
+                let mut body = Vec::new();
+                let max_ratio = self.max_decompression_ratio;
+                Self::decoding_reader(&encodings, max_ratio, decoder, &mut self.conn)?
+                    .read_to_end(&mut body)?;
+                body
             }
-        }
+        };
+        fix_header(&kind, !encodings.is_empty(), body.len(), &mut header);
 
         let mut resp = Response {
+            version,
             status,
             header,
             body: None,
         };
 
         if !body.is_empty() {
-            resp.body = Some(Body::new(body));
+            resp.body = Some(Body::with_header(body, &resp.header));
         }
         Ok(resp)
     }
 
     pub fn execute_request(&mut self, req: &Request) -> Result<Response> {
-        let body = req.build();
-        self.conn.write_all(&body).unwrap();
-        self.read_response(req)
+        let extra = self.default_header();
+        let (decoder, body_sent) = self.send_request(req, &extra)?;
+        self.read_response(decoder, body_sent)
+    }
+
+    // NOTE: ボディを読み切らずに StreamResponse を捨てるとコネクションに残りのボディが
+    // 残ってしまうので、次のリクエストを送る前に必ず最後まで読むこと
+    pub fn execute_request_stream(&mut self, req: &Request) -> Result<StreamResponse<'_>> {
+        let extra = self.default_header();
+        let (decoder, body_sent) = self.send_request(req, &extra)?;
+
+        let (status, mut header, decoder) = self.read_head(decoder, body_sent)?;
+        let version = decoder.version();
+        let body = self.decoded_body_reader(decoder, &mut header)?;
+        Ok(StreamResponse {
+            version,
+            status,
+            header,
+            body,
+        })
+    }
+
+    // execute_request_stream と同じだが、コネクションごと StreamResponse に渡す。
+    // SSE のように最後まで読まずに長く持つストリーム向けで、読み終えたコネクションは再利用できない
+    pub fn into_stream(mut self, req: &Request) -> Result<StreamResponse<'static>>
+    where
+        T: 'static,
+    {
+        let extra = self.default_header();
+        let (decoder, body_sent) = self.send_request(req, &extra)?;
+
+        let (status, mut header, decoder) = self.read_head(decoder, body_sent)?;
+        let version = decoder.version();
+        let body = Self::body_reader(
+            self.decompress,
+            self.max_decompression_ratio,
+            decoder,
+            self.conn,
+            &mut header,
+        )?;
+        Ok(StreamResponse {
+            version,
+            status,
+            header,
+            body,
+        })
+    }
+
+    // Connection: Upgrade と Upgrade: protocol を付けて送り、101 が返ればプロトコルを切り替えた
+    // コネクションを返す。それ以外のステータスならエラーにする
+    pub fn upgrade(self, req: &Request, protocol: &str) -> Result<Upgraded<T>> {
+        self.upgrade_with_header(req, protocol, HttpHeader::new())
+    }
+
+    // header はリクエストに同じ名前のヘッダが無いときだけ追加する
+    pub(crate) fn upgrade_with_header(
+        mut self,
+        req: &Request,
+        protocol: &str,
+        header: HttpHeader,
+    ) -> Result<Upgraded<T>> {
+        let mut extra: HttpHeader = [("Connection", "Upgrade"), ("Upgrade", protocol)]
+            .into_iter()
+            .collect();
+        for (k, v) in header.iter().chain(self.default_header().iter()) {
+            extra.add(k, v);
+        }
+        req.write_to(self.conn.get_mut(), &extra)?;
+
+        let (status, header, decoder) = self.read_head(ResponseDecoder::new(&req.method), true)?;
+        if status != 101 {
+            // ボディまで読んでからエラーにする
+            self.decoded_body_reader(decoder, &mut header.clone())?
+                .read_to_end(&mut Vec::new())?;
+            bail!("server did not switch protocols: status {}", status);
+        }
+
+        let upgrade = header
+            .get("upgrade")
+            .map(|v| v.as_str())
+            .unwrap_or_default();
+        if !upgrade.eq_ignore_ascii_case(protocol) {
+            bail!("server switched to unexpected protocol: {:?}", upgrade);
+        }
+        Ok(Upgraded::new(self.conn, header))
+    }
+
+    // サーバーが閉じた、またはリセットしたなら true
+    fn is_closed(&mut self) -> bool {
+        match self.conn.fill_buf() {
+            Ok(buf) => buf.is_empty(),
+            Err(e) => matches!(
+                e.kind(),
+                io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted
+            ),
+        }
+    }
+
+    // requests をレスポンスを待たずに pipeline_window 個まで送り、レスポンスを送った順に読む。
+    // 途中で Connection: close が返ったり、コネクションが閉じられたり、レスポンスを読めなかったりした場合、
+    // 残りのリクエストはエラーにせず Pipelined::retry で返す。1 つもレスポンスを読めなければエラーを返す
+    pub fn pipeline(&mut self, requests: &[Request]) -> Result<Pipelined> {
+        if !self.pipeline_non_idempotent {
+            if let Some(req) = requests.iter().find(|r| !r.method.is_idempotent()) {
+                bail!("cannot pipeline non-idempotent method: {}", req.method);
+            }
+        }
+
+        let extra = self.default_header();
+        let mut sent = 0;
+        let mut write_failed = false;
+        let mut responses = Vec::new();
+        while responses.len() < requests.len() {
+            let before = sent;
+            while !write_failed
+                && sent < requests.len()
+                && sent - responses.len() < self.pipeline_window
+            {
+                match requests[sent].write_to(self.conn.get_mut(), &extra) {
+                    Ok(()) => sent += 1,
+                    // 1 つも送れなければそのエラーを返す
+                    Err(e) if sent == 0 => return Err(e),
+                    Err(_) => write_failed = true,
+                }
+            }
+            if sent > before {
+                if let Err(e) = self.conn.get_mut().flush() {
+                    if responses.is_empty() {
+                        return Err(e.into());
+                    }
+                    break;
+                }
+            }
+            // 送ったリクエストのレスポンスをすべて読んだ
+            if responses.len() == sent || self.is_closed() {
+                break;
+            }
+
+            let req = &requests[responses.len()];
+            match self.read_response(ResponseDecoder::new(&req.method), true) {
+                Ok(resp) => {
+                    let keep_alive = resp.keep_alive();
+                    responses.push(resp);
+                    if !keep_alive {
+                        break;
+                    }
+                }
+                Err(e) if responses.is_empty() => return Err(e),
+                Err(_) => break,
+            }
+        }
+
+        let retry = (responses.len()..requests.len()).collect();
+        Ok(Pipelined { responses, retry })
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::method::HttpMethod;
     use httptest::{matchers::*, responders::*, Expectation, ServerBuilder};
     use serde::Serialize;
     use serde_json::json;
     use std::net::{SocketAddr, TcpStream};
+    use std::sync::mpsc;
 
     #[derive(Serialize, Clone)]
     struct Animal {
@@ -203,6 +517,159 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn request_stream() -> Result<()> {
+        let want_body = r#"{"name": "gorilla", "age": 5}"#;
+
+        let addr: SocketAddr = ([127, 0, 0, 1], 0).into();
+        let server = ServerBuilder::new().bind_addr(addr).run()?;
+        server.expect(
+            Expectation::matching(request::method_path("GET", "/hello"))
+                .times(2)
+                .respond_with(status_code(200).body(want_body)),
+        );
+
+        let conn = TcpStream::connect(server.addr())?;
+        let mut client = HttpClient::new(conn);
+        let req = Request::get("/hello");
+
+        {
+            let mut resp = client.execute_request_stream(&req)?;
+            assert_eq!(resp.status, 200);
+            let mut body = String::new();
+            resp.body.read_to_string(&mut body)?;
+            assert_eq!(body, want_body);
+        }
+
+        // ボディを読み切ったあとは同じコネクションで次のリクエストを送れること
+        let resp = client.execute_request(&req)?;
+        assert_eq!(resp.body.unwrap().text()?, want_body);
+
+        Ok(())
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        use std::io::Write;
+        let mut e = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        e.write_all(data).unwrap();
+        e.finish().unwrap()
+    }
+
+    #[test]
+    fn request_decompress() -> Result<()> {
+        let want_body = r#"{"name": "gorilla", "age": 5}"#;
+
+        let addr: SocketAddr = ([127, 0, 0, 1], 0).into();
+        let server = ServerBuilder::new().bind_addr(addr).run()?;
+        server.expect(
+            Expectation::matching(all_of![
+                request::method_path("GET", "/hello"),
+                request::headers(contains(("accept-encoding", ACCEPT_ENCODING))),
+            ])
+            .times(2)
+            .respond_with(
+                status_code(200)
+                    .append_header("Content-Encoding", "gzip")
+                    .body(gzip(want_body.as_bytes())),
+            ),
+        );
+
+        let conn = TcpStream::connect(server.addr())?;
+        let mut client = HttpClient::new(conn);
+        let req = Request::get("/hello");
+
+        let resp = client.execute_request(&req)?;
+        assert_eq!(resp.body.unwrap().text()?, want_body);
+        assert!(resp.header.get("content-encoding").is_none());
+        assert_eq!(
+            resp.header.get("content-length").unwrap(),
+            &want_body.len().to_string()
+        );
+
+        let mut resp = client.execute_request_stream(&req)?;
+        let mut body = String::new();
+        resp.body.read_to_string(&mut body)?;
+        assert_eq!(body, want_body);
+
+        Ok(())
+    }
+
+    #[test]
+    fn request_without_decompress() -> Result<()> {
+        let encoded = gzip(b"gorilla");
+
+        let addr: SocketAddr = ([127, 0, 0, 1], 0).into();
+        let server = ServerBuilder::new().bind_addr(addr).run()?;
+        server.expect(
+            Expectation::matching(all_of![
+                request::method_path("GET", "/hello"),
+                not(request::headers(contains(key("accept-encoding")))),
+            ])
+            .respond_with(
+                status_code(200)
+                    .append_header("Content-Encoding", "gzip")
+                    .body(encoded.clone()),
+            ),
+        );
+
+        let conn = TcpStream::connect(server.addr())?;
+        let mut client = HttpClient::new(conn);
+        client.decompress(false);
+        let resp = client.execute_request(&Request::get("/hello"))?;
+        assert_eq!(resp.body.unwrap().raw(), encoded);
+        assert_eq!(resp.header.get("content-encoding").unwrap(), "gzip");
+
+        Ok(())
+    }
+
+    #[test]
+    fn request_decompression_ratio() -> Result<()> {
+        let addr: SocketAddr = ([127, 0, 0, 1], 0).into();
+        let server = ServerBuilder::new().bind_addr(addr).run()?;
+        server.expect(
+            Expectation::matching(request::method_path("GET", "/bomb")).respond_with(
+                status_code(200)
+                    .append_header("Content-Encoding", "gzip")
+                    .body(gzip(&vec![0u8; 10 * 1024 * 1024])),
+            ),
+        );
+
+        let conn = TcpStream::connect(server.addr())?;
+        let mut client = HttpClient::new(conn);
+        client.max_decompression_ratio(Some(100));
+        let err = client.execute_request(&Request::get("/bomb")).unwrap_err();
+        assert!(err.to_string().contains("decompression ratio exceeded"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn default_decompression_ratio() -> Result<()> {
+        let bomb = zstd::encode_all(&vec![0u8; 10 * 1024 * 1024][..], 3)?;
+        let addr: SocketAddr = ([127, 0, 0, 1], 0).into();
+        let server = ServerBuilder::new().bind_addr(addr).run()?;
+        server.expect(
+            Expectation::matching(request::method_path("GET", "/bomb"))
+                .times(2)
+                .respond_with(
+                    status_code(200)
+                        .append_header("Content-Encoding", "zstd")
+                        .body(bomb),
+                ),
+        );
+
+        let mut client = HttpClient::new(TcpStream::connect(server.addr())?);
+        let err = client.execute_request(&Request::get("/bomb")).unwrap_err();
+        assert!(err.to_string().contains("decompression ratio exceeded"));
+
+        // None にすれば確認しない
+        let mut client = HttpClient::new(TcpStream::connect(server.addr())?);
+        client.max_decompression_ratio(None);
+        let resp = client.execute_request(&Request::get("/bomb"))?;
+        assert_eq!(resp.body.unwrap().len(), 10 * 1024 * 1024);
+        Ok(())
+    }
+
     #[test]
     fn request_post() -> Result<()> {
         let _ = pretty_env_logger::try_init();
@@ -246,6 +713,76 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn request_post_compressed() -> Result<()> {
+        let body = serde_json::to_vec(&vec![
+            Animal {
+                name: "gorilla".into(),
+                age: 10,
+            };
+            100
+        ])?;
+        let compression = crate::compression::Compression::zstd(3);
+        let encoded = crate::compression::encode(&body, compression)?;
+
+        let addr: SocketAddr = ([127, 0, 0, 1], 0).into();
+        let server = ServerBuilder::new().bind_addr(addr).run()?;
+        server.expect(
+            Expectation::matching(all_of![
+                request::method_path("POST", "/ingest"),
+                request::headers(contains(("content-encoding", "zstd"))),
+                request::headers(contains(("content-length", encoded.len().to_string()))),
+                request::body(encoded),
+            ])
+            .respond_with(status_code(202)),
+        );
+
+        let conn = TcpStream::connect(server.addr())?;
+        let mut client = HttpClient::new(conn);
+        let mut req = Request::new("/ingest".into());
+        req.method(HttpMethod::Post)
+            .body(body)
+            .compress(compression);
+        let resp = client.execute_request(&req)?;
+        assert_eq!(resp.status, 202);
+
+        Ok(())
+    }
+
+    #[test]
+    fn request_post_stream() -> Result<()> {
+        let body = "gorilla\n".repeat(10000);
+
+        let addr: SocketAddr = ([127, 0, 0, 1], 0).into();
+        let server = ServerBuilder::new().bind_addr(addr).run()?;
+        server.expect(
+            Expectation::matching(all_of![
+                request::method_path("POST", "/ingest"),
+                request::headers(contains(("transfer-encoding", "chunked"))),
+                request::headers(contains(("content-encoding", "gzip"))),
+                request::body(crate::compression::encode(
+                    body.as_bytes(),
+                    crate::compression::Compression::gzip(6)
+                )?),
+            ])
+            .respond_with(status_code(202)),
+        );
+
+        let conn = TcpStream::connect(server.addr())?;
+        let mut client = HttpClient::new(conn);
+        let mut req = Request::new("/ingest".into());
+        req.method(HttpMethod::Post)
+            .body_reader(
+                io::Cursor::new(body.clone().into_bytes()),
+                Some(body.len() as u64),
+            )
+            .compress(crate::compression::Compression::gzip(6));
+        let resp = client.execute_request(&req)?;
+        assert_eq!(resp.status, 202);
+
+        Ok(())
+    }
+
     #[test]
     fn request_put() -> Result<()> {
         let _ = pretty_env_logger::try_init();
@@ -395,4 +932,451 @@ mod test {
 
         Ok(())
     }
+
+    // requests 個のリクエストを全部受け取ってから responses を返すサーバー
+    fn pipeline_server(requests: usize, responses: Vec<&'static str>) -> SocketAddr {
+        use std::io::Write;
+        use std::net::{Shutdown, TcpListener};
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let (conn, _) = listener.accept().unwrap();
+            let mut r = BufReader::new(conn.try_clone().unwrap());
+            let mut w = conn;
+            let mut line = String::new();
+            for _ in 0..requests {
+                loop {
+                    line.clear();
+                    r.read_line(&mut line).unwrap();
+                    if line == "\r\n" {
+                        break;
+                    }
+                }
+            }
+            for resp in responses {
+                w.write_all(resp.as_bytes()).unwrap();
+            }
+            w.shutdown(Shutdown::Write).unwrap();
+            // 読み残しがあると RST が送られてしまうので、クライアントが閉じるまで読む
+            let _ = io::copy(&mut r, &mut io::sink());
+        });
+        addr
+    }
+
+    #[test]
+    fn pipeline_in_order() -> Result<()> {
+        let addr = pipeline_server(
+            3,
+            vec![
+                "HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\none",
+                "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\ntwo\r\n0\r\n\r\n",
+                "HTTP/1.1 404 Not Found\r\nContent-Length: 5\r\n\r\nthree",
+            ],
+        );
+
+        let mut client = HttpClient::new(TcpStream::connect(addr)?);
+        let requests = vec![Request::get("/1"), Request::get("/2"), Request::get("/3")];
+        let result = client.pipeline(&requests)?;
+
+        let bodies: Vec<String> = result
+            .responses
+            .iter()
+            .map(|r| r.body.as_ref().unwrap().text().unwrap())
+            .collect();
+        assert_eq!(bodies, vec!["one", "two", "three"]);
+        assert_eq!(result.responses[2].status, 404);
+        assert!(result.retry.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn pipeline_closed_midway() -> Result<()> {
+        let addr = pipeline_server(
+            4,
+            vec![
+                "HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\none",
+                "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 3\r\n\r\ntwo",
+            ],
+        );
+
+        let mut client = HttpClient::new(TcpStream::connect(addr)?);
+        let requests: Vec<Request> = (1..=4).map(|i| Request::get(&format!("/{}", i))).collect();
+        let result = client.pipeline(&requests)?;
+        assert_eq!(result.responses.len(), 2);
+        assert_eq!(result.retry, vec![2, 3]);
+        Ok(())
+    }
+
+    // レスポンスを 1 つずつ返し、返すときにレスポンスを待っているリクエストの数を記録するコネクション
+    struct WindowConn {
+        responses: std::collections::VecDeque<&'static [u8]>,
+        written: Vec<u8>,
+        given: usize,
+        max_in_flight: usize,
+    }
+
+    impl io::Read for WindowConn {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let resp = match self.responses.pop_front() {
+                Some(resp) => resp,
+                None => return Ok(0),
+            };
+            let requests = self.written.windows(4).filter(|w| w == b"\r\n\r\n").count();
+            self.max_in_flight = self.max_in_flight.max(requests - self.given);
+            self.given += 1;
+            buf[..resp.len()].copy_from_slice(resp);
+            Ok(resp.len())
+        }
+    }
+
+    impl io::Write for WindowConn {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn pipeline_window() -> Result<()> {
+        let conn = WindowConn {
+            responses: vec![&b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok"[..]; 5].into(),
+            written: Vec::new(),
+            given: 0,
+            max_in_flight: 0,
+        };
+        let mut client = HttpClient::new(conn);
+        client.pipeline_window(2);
+        let requests: Vec<Request> = (1..=5).map(|i| Request::get(&format!("/{}", i))).collect();
+        let result = client.pipeline(&requests)?;
+        assert_eq!(result.responses.len(), 5);
+        assert_eq!(client.conn.get_ref().max_in_flight, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn pipeline_broken_response() -> Result<()> {
+        let addr = pipeline_server(
+            3,
+            vec![
+                "HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\none",
+                "HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\ntwo",
+            ],
+        );
+
+        let mut client = HttpClient::new(TcpStream::connect(addr)?);
+        let requests: Vec<Request> = (1..=3).map(|i| Request::get(&format!("/{}", i))).collect();
+        // 読めたレスポンスは捨てずに返し、読めなかったものから retry に入れる
+        let result = client.pipeline(&requests)?;
+        assert_eq!(result.responses.len(), 1);
+        assert_eq!(result.retry, vec![1, 2]);
+        Ok(())
+    }
+
+    #[test]
+    fn pipeline_rejects_non_idempotent() -> Result<()> {
+        let addr = pipeline_server(0, vec![]);
+        let mut client = HttpClient::new(TcpStream::connect(addr)?);
+        let requests = vec![Request::get("/"), Request::post("/", json!(true))];
+
+        let err = client.pipeline(&requests).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "cannot pipeline non-idempotent method: POST"
+        );
+        Ok(())
+    }
+
+    // 101 を返したあと、先に "hello\n" を送り、あとは受け取った行をそのまま返すサーバー
+    fn upgrade_server(status_line: &'static str) -> SocketAddr {
+        use std::io::Write;
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let (conn, _) = listener.accept().unwrap();
+            let mut r = BufReader::new(conn.try_clone().unwrap());
+            let mut w = conn;
+            let mut head = String::new();
+            loop {
+                let mut line = String::new();
+                r.read_line(&mut line).unwrap();
+                head.push_str(&line);
+                if line == "\r\n" {
+                    break;
+                }
+            }
+            assert!(head.contains("Connection: Upgrade\r\n"));
+            assert!(head.contains("Upgrade: echo\r\n"));
+
+            write!(
+                w,
+                "{}\r\nConnection: Upgrade\r\nUpgrade: echo\r\nContent-Length: 0\r\n\r\nhello\n",
+                status_line
+            )
+            .unwrap();
+            let mut line = String::new();
+            while r.read_line(&mut line).unwrap_or(0) > 0 {
+                w.write_all(line.as_bytes()).unwrap();
+                line.clear();
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn upgrade_protocol() -> Result<()> {
+        use std::io::Write;
+
+        let addr = upgrade_server("HTTP/1.1 101 Switching Protocols");
+        let client = HttpClient::new(TcpStream::connect(addr)?);
+        let mut conn = client.upgrade(&Request::get("/echo"), "echo")?;
+
+        // 101 と一緒に届いたバイトが読めること
+        let mut line = String::new();
+        conn.read_line(&mut line)?;
+        assert_eq!(line, "hello\n");
+
+        conn.write_all(b"gorilla\n")?;
+        line.clear();
+        conn.read_line(&mut line)?;
+        assert_eq!(line, "gorilla\n");
+        Ok(())
+    }
+
+    #[test]
+    fn upgrade_rejected() -> Result<()> {
+        let addr = upgrade_server("HTTP/1.1 200 OK");
+        let client = HttpClient::new(TcpStream::connect(addr)?);
+        let err = client
+            .upgrade(&Request::get("/echo"), "echo")
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "server did not switch protocols: status 200"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn http10_close_delimited() -> Result<()> {
+        use crate::version::HttpVersion;
+        use std::io::Write;
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let server = std::thread::spawn(move || {
+            let (conn, _) = listener.accept().unwrap();
+            let mut r = BufReader::new(conn.try_clone().unwrap());
+            let mut w = conn;
+            let mut request_line = String::new();
+            r.read_line(&mut request_line).unwrap();
+            loop {
+                let mut line = String::new();
+                r.read_line(&mut line).unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+            }
+            // Content-Length を付けずに、コネクションを閉じてボディの終わりを示す
+            w.write_all(b"HTTP/1.0 200 OK\r\nServer: old\r\n\r\nhello http/1.0")
+                .unwrap();
+            request_line
+        });
+
+        let mut client = HttpClient::new(TcpStream::connect(addr)?);
+        let mut req = Request::get("/");
+        req.version(HttpVersion::Http10);
+        let resp = client.execute_request(&req)?;
+
+        assert_eq!(server.join().unwrap(), "GET / HTTP/1.0\r\n");
+        assert_eq!(resp.version, HttpVersion::Http10);
+        assert!(!resp.keep_alive());
+        assert_eq!(resp.header.get("content-length").unwrap(), "14");
+        assert_eq!(resp.body.unwrap().text()?, "hello http/1.0");
+        Ok(())
+    }
+
+    #[test]
+    fn http10_rejects_chunked_body() {
+        use crate::version::HttpVersion;
+
+        let mut req = Request::new("/".into());
+        req.method(HttpMethod::Post)
+            .version(HttpVersion::Http10)
+            .body_reader(&b"gorilla"[..], None);
+        let err = req.take_stream().err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "HTTP/1.0 request body requires a known length"
+        );
+    }
+
+    #[test]
+    fn rejects_http2_request() {
+        use crate::version::HttpVersion;
+
+        let mut req = Request::new("/".into());
+        req.version(HttpVersion::Http2);
+        let err = req
+            .write_to(&mut Vec::new(), &HttpHeader::new())
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "HTTP/2 requests must be sent with H2Client"
+        );
+    }
+
+    // ヘッダを読んだあと、interim を送ってから Content-Length 分のボディを読み、読んだボディを返す。
+    // read_body が false ならボディを読まずに 417 を返す
+    fn expect_server(
+        interim: &'static str,
+        read_body: bool,
+    ) -> (SocketAddr, mpsc::Receiver<String>) {
+        use std::io::Write;
+        use std::net::TcpListener;
+
+        let (tx, rx) = mpsc::channel();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let (conn, _) = listener.accept().unwrap();
+            let mut r = BufReader::new(conn.try_clone().unwrap());
+            let mut w = conn;
+            let mut head = String::new();
+            loop {
+                let mut line = String::new();
+                r.read_line(&mut line).unwrap();
+                head.push_str(&line);
+                if line == "\r\n" {
+                    break;
+                }
+            }
+            assert!(head.contains("Expect: 100-continue\r\n"));
+            w.write_all(interim.as_bytes()).unwrap();
+
+            if !read_body {
+                w.write_all(b"HTTP/1.1 417 Expectation Failed\r\nContent-Length: 0\r\n\r\n")
+                    .unwrap();
+                // クライアントがコネクションを閉じるまでに届いたものを返す
+                let mut rest = String::new();
+                r.read_to_string(&mut rest).unwrap();
+                tx.send(rest).unwrap();
+                return;
+            }
+            let mut body = vec![0u8; 7];
+            r.read_exact(&mut body).unwrap();
+            w.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")
+                .unwrap();
+            tx.send(String::from_utf8(body).unwrap()).unwrap();
+        });
+        (addr, rx)
+    }
+
+    fn expect_request() -> Request {
+        let mut req = Request::new("/upload".into());
+        req.method(HttpMethod::Put)
+            .body(b"gorilla".to_vec())
+            .expect_continue(Duration::from_secs(10));
+        req
+    }
+
+    #[test]
+    fn expect_continue() -> Result<()> {
+        let (addr, rx) = expect_server(
+            "HTTP/1.1 103 Early Hints\r\nLink: </a.css>\r\n\r\nHTTP/1.1 100 Continue\r\n\r\n",
+            true,
+        );
+        let interim = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut client = HttpClient::new(TcpStream::connect(addr)?);
+        let seen = interim.clone();
+        client
+            .read_timeout_fn(TcpStream::read_timeout, TcpStream::set_read_timeout)
+            .on_interim(move |status, _| seen.lock().unwrap().push(status));
+
+        let resp = client.execute_request(&expect_request())?;
+        assert_eq!(resp.status, 200);
+        assert_eq!(resp.body.unwrap().text()?, "ok");
+        assert_eq!(rx.recv()?, "gorilla");
+        assert_eq!(*interim.lock().unwrap(), vec![103, 100]);
+        Ok(())
+    }
+
+    #[test]
+    fn expect_continue_early_final_status() -> Result<()> {
+        let (addr, rx) = expect_server("", false);
+        let mut client = HttpClient::new(TcpStream::connect(addr)?);
+        client.read_timeout_fn(TcpStream::read_timeout, TcpStream::set_read_timeout);
+
+        let resp = client.execute_request(&expect_request())?;
+        assert_eq!(resp.status, 417);
+        assert!(!resp.keep_alive());
+        drop(client);
+        // ボディは送られていない
+        assert_eq!(rx.recv()?, "");
+        Ok(())
+    }
+
+    #[test]
+    fn expect_continue_without_read_timeout() -> Result<()> {
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let mut client = HttpClient::new(TcpStream::connect(listener.local_addr()?)?);
+        let (mut conn, _) = listener.accept()?;
+
+        // 待てないのでヘッダもボディも送らずにエラーになる
+        let err = client.execute_request(&expect_request()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "expect_continue requires read_timeout_fn to wait for 100 Continue"
+        );
+        drop(client);
+        let mut sent = String::new();
+        conn.read_to_string(&mut sent)?;
+        assert_eq!(sent, "");
+        Ok(())
+    }
+
+    #[test]
+    fn expect_continue_connect() -> Result<()> {
+        // HttpClient::connect ならそのまま待てる
+        let (addr, rx) = expect_server("", false);
+        let mut client = HttpClient::connect("127.0.0.1", addr.port())?;
+
+        let resp = client.execute_request(&expect_request())?;
+        assert_eq!(resp.status, 417);
+        drop(client);
+        assert_eq!(rx.recv()?, "");
+        Ok(())
+    }
+
+    #[test]
+    fn expect_continue_timeout() -> Result<()> {
+        // 100 Continue を返さないサーバーでも、タイムアウトしたらボディを送る
+        let (addr, rx) = expect_server("", true);
+        let stream = TcpStream::connect(addr)?;
+        let timeout = Some(Duration::from_secs(5));
+        stream.set_read_timeout(timeout)?;
+        // NOTE: try_clone したストリームは同じソケットなので、タイムアウトも共有される
+        let probe = stream.try_clone()?;
+        let mut client = HttpClient::new(stream);
+        client.read_timeout_fn(TcpStream::read_timeout, TcpStream::set_read_timeout);
+
+        let mut req = expect_request();
+        req.expect_continue(Duration::from_millis(50));
+        let resp = client.execute_request(&req)?;
+        assert_eq!(resp.status, 200);
+        assert_eq!(rx.recv()?, "gorilla");
+        // 待ち終わったら元の読み込みタイムアウトに戻っている
+        assert_eq!(probe.read_timeout()?, timeout);
+        Ok(())
+    }
 }
@@ -0,0 +1,668 @@
+use crate::header::HttpHeader;
+use crate::method::HttpMethod;
+use crate::version::HttpVersion;
+use anyhow::{anyhow, bail, Result};
+use std::io::{self, BufRead, Read};
+
+// NOTE: ステータス行やヘッダの 1 行がこれより長ければ不正なレスポンスとみなす
+const MAX_LINE_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BodyKind {
+    Empty,
+    Chunked,
+    Length(u64),
+    // Content-Length も chunked も無ければ、コネクションが閉じられるまでがボディ
+    Close,
+}
+
+// ResponseDecoder が返すイベント。Status, Header*, HeadersComplete, Data*, Trailer*, End の順で返る。
+// 100 Continue などの 1xx の場合は HeadersComplete の代わりに Interim を返し、また Status から始まる
+#[derive(Debug, PartialEq, Eq)]
+pub enum Event {
+    Status(HttpVersion, u32),
+    Header(String, String),
+    Interim(u32, HttpHeader),
+    HeadersComplete,
+    Data(Vec<u8>),
+    Trailer(String, String),
+    End,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    StatusLine,
+    Headers,
+    Length(u64),
+    UntilClose,
+    ChunkSize,
+    ChunkData(u64),
+    ChunkEnd,
+    Trailers,
+    End,
+    Done,
+}
+
+pub(crate) fn parse_status_line(line: &[u8]) -> Result<(HttpVersion, u32)> {
+    let status_line = String::from_utf8(line.to_vec())?;
+    let mut cols = status_line.split_whitespace();
+
+    let version = HttpVersion::parse(cols.next().unwrap_or_default())?;
+    let status = cols
+        .next()
+        .ok_or_else(|| anyhow!("cannot get status code"))?
+        .parse::<u32>()?;
+    Ok((version, status))
+}
+
+// ヘッダの終わりの空行なら None を返す
+pub(crate) fn parse_header_line(line: &[u8]) -> Result<Option<(String, String)>> {
+    let mut line = String::from_utf8(line.to_vec())?;
+    if line == "\r\n" || line == "\n" {
+        return Ok(None);
+    }
+    line = line.trim().to_string();
+
+    let mut cols = line.split(": ");
+    let key = cols
+        .next()
+        .ok_or_else(|| anyhow!("invalid header key"))?
+        .to_lowercase();
+    let val = cols.next().ok_or_else(|| anyhow!("invalid header value"))?;
+
+    Ok(Some((key, val.to_string())))
+}
+
+pub(crate) fn parse_chunk_size(line: &[u8]) -> Result<u64> {
+    let line = String::from_utf8_lossy(line);
+    // NOTE: chunk-size の後ろには `;name=value` の chunk-ext が付くことがある
+    let size = line.split(';').next().unwrap_or_default().trim();
+    u64::from_str_radix(size, 16).map_err(|_| anyhow!("cannot read chunk length: {}", line))
+}
+
+pub(crate) fn body_kind(method: &HttpMethod, status: u32, header: &HttpHeader) -> Result<BodyKind> {
+    // 1xx (101 Switching Protocols を含む) にはボディが無い
+    if (100..200).contains(&status) || matches!(status, 204 | 304) {
+        return Ok(BodyKind::Empty);
+    }
+
+    let must_read_body = !matches!(method, HttpMethod::Head | HttpMethod::Options);
+    if !must_read_body {
+        return Ok(BodyKind::Empty);
+    }
+
+    let tf = header.get("transfer-encoding");
+    let cl = header.get("content-length");
+
+    if tf.map(|x| *x == "chunked").unwrap_or(false) {
+        return Ok(BodyKind::Chunked);
+    }
+
+    match cl {
+        Some(value) => Ok(BodyKind::Length(value.parse::<u64>()?)),
+        None => Ok(BodyKind::Close),
+    }
+}
+
+// I/O を持たないレスポンスのパーサー。読み込んだバイト列を decode に渡すと、
+// 消費したバイト数と、イベントがあればそれを返す。
+// 1 つのレスポンスの終わりより先は消費しないので、同じコネクションで続けて使える
+pub struct ResponseDecoder {
+    state: State,
+    method: HttpMethod,
+    version: HttpVersion,
+    status: u32,
+    header: HttpHeader,
+    kind: Option<BodyKind>,
+    line: Vec<u8>,
+}
+
+impl ResponseDecoder {
+    pub fn new(method: &HttpMethod) -> Self {
+        Self {
+            state: State::StatusLine,
+            method: *method,
+            version: HttpVersion::Http11,
+            status: 0,
+            header: HttpHeader::new(),
+            kind: None,
+            line: Vec::new(),
+        }
+    }
+
+    // ヘッダを読み終えたあとのボディだけをデコードする
+    pub fn body(kind: BodyKind) -> Self {
+        let mut decoder = Self::new(&HttpMethod::Get);
+        decoder.start_body(kind);
+        decoder
+    }
+
+    pub fn version(&self) -> HttpVersion {
+        self.version
+    }
+
+    pub fn status(&self) -> u32 {
+        self.status
+    }
+
+    pub fn header(&self) -> &HttpHeader {
+        &self.header
+    }
+
+    // HeadersComplete を返したあとに決まる
+    pub fn body_kind(&self) -> Option<BodyKind> {
+        self.kind
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.state == State::Done
+    }
+
+    // レスポンスを 1 バイトでも受け取ったら true
+    pub fn is_started(&self) -> bool {
+        self.state != State::StatusLine || !self.line.is_empty() || self.status != 0
+    }
+
+    fn start_body(&mut self, kind: BodyKind) {
+        self.kind = Some(kind);
+        self.state = match kind {
+            BodyKind::Empty | BodyKind::Length(0) => State::End,
+            BodyKind::Length(size) => State::Length(size),
+            BodyKind::Chunked => State::ChunkSize,
+            BodyKind::Close => State::UntilClose,
+        };
+    }
+
+    // 改行までを self.line に貯める。1 行そろったら true を返す
+    fn read_line(&mut self, input: &[u8]) -> Result<(usize, bool)> {
+        let (n, complete) = match input.iter().position(|b| *b == b'\n') {
+            Some(i) => (i + 1, true),
+            None => (input.len(), false),
+        };
+        if self.line.len() + n > MAX_LINE_SIZE {
+            bail!("line too long in response");
+        }
+        self.line.extend_from_slice(&input[..n]);
+        Ok((n, complete))
+    }
+
+    fn take_line(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.line)
+    }
+
+    // input が空でも、入力なしで進める状態 (ボディの終わりなど) ならイベントを返す
+    pub fn decode(&mut self, input: &[u8]) -> Result<(usize, Option<Event>)> {
+        match self.state {
+            State::StatusLine => {
+                let (n, complete) = self.read_line(input)?;
+                if !complete {
+                    return Ok((n, None));
+                }
+                (self.version, self.status) = parse_status_line(&self.take_line())?;
+                self.state = State::Headers;
+                Ok((n, Some(Event::Status(self.version, self.status))))
+            }
+            State::Headers => {
+                let (n, complete) = self.read_line(input)?;
+                if !complete {
+                    return Ok((n, None));
+                }
+                match parse_header_line(&self.take_line())? {
+                    Some((key, val)) => {
+                        self.header.add(&key, &val);
+                        Ok((n, Some(Event::Header(key, val))))
+                    }
+                    // NOTE: 101 Switching Protocols はこのコネクションでの最後のレスポンスなので中間レスポンスではない
+                    None if (100..200).contains(&self.status) && self.status != 101 => {
+                        self.state = State::StatusLine;
+                        let header = std::mem::take(&mut self.header);
+                        Ok((n, Some(Event::Interim(self.status, header))))
+                    }
+                    None => {
+                        let kind = body_kind(&self.method, self.status, &self.header)?;
+                        self.start_body(kind);
+                        Ok((n, Some(Event::HeadersComplete)))
+                    }
+                }
+            }
+            State::Length(_) | State::UntilClose | State::ChunkData(_) => {
+                match self.advance_data(input.len()) {
+                    Some(0) | None => Ok((0, None)),
+                    Some(n) => Ok((n, Some(Event::Data(input[..n].to_vec())))),
+                }
+            }
+            State::ChunkSize => {
+                let (n, complete) = self.read_line(input)?;
+                if !complete {
+                    return Ok((n, None));
+                }
+                self.state = match parse_chunk_size(&self.take_line())? {
+                    0 => State::Trailers,
+                    size => State::ChunkData(size),
+                };
+                Ok((n, None))
+            }
+            State::ChunkEnd => {
+                // consume \r\n
+                let (n, complete) = self.read_line(input)?;
+                if complete {
+                    self.take_line();
+                    self.state = State::ChunkSize;
+                }
+                Ok((n, None))
+            }
+            State::Trailers => {
+                let (n, complete) = self.read_line(input)?;
+                if !complete {
+                    return Ok((n, None));
+                }
+                match parse_header_line(&self.take_line())? {
+                    Some((key, val)) => Ok((n, Some(Event::Trailer(key, val)))),
+                    None => {
+                        self.state = State::Done;
+                        Ok((n, Some(Event::End)))
+                    }
+                }
+            }
+            State::End => {
+                self.state = State::Done;
+                Ok((0, Some(Event::End)))
+            }
+            State::Done => Ok((0, None)),
+        }
+    }
+
+    // ボディのデータを読んでいる状態なら、len バイトのうち何バイトがボディかを返して状態を進める。
+    // NOTE: Event::Data を作らずに呼び出し側のバッファへ直接コピーするために使う
+    pub(crate) fn advance_data(&mut self, len: usize) -> Option<usize> {
+        let (n, next) = match self.state {
+            State::Length(remaining) => {
+                let n = len.min(remaining as usize);
+                match remaining - n as u64 {
+                    0 => (n, State::End),
+                    rest => (n, State::Length(rest)),
+                }
+            }
+            State::ChunkData(remaining) => {
+                let n = len.min(remaining as usize);
+                match remaining - n as u64 {
+                    0 => (n, State::ChunkEnd),
+                    rest => (n, State::ChunkData(rest)),
+                }
+            }
+            State::UntilClose => (len, State::UntilClose),
+            _ => return None,
+        };
+        self.state = next;
+        Some(n)
+    }
+
+    // 入力が終わった (コネクションが閉じられた) ときに呼ぶ
+    pub fn eof(&mut self) -> Result<Event> {
+        match self.state {
+            // NOTE: io::Error にしておくと、AsyncPool が閉じられていたコネクションだと判断できる
+            State::StatusLine if self.line.is_empty() => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed before status line",
+            )
+            .into()),
+            State::UntilClose | State::End | State::Done => {
+                self.state = State::Done;
+                Ok(Event::End)
+            }
+            _ => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "unexpected endof").into()),
+        }
+    }
+}
+
+// 同期の BufRead から次のイベントを読む
+pub fn read_event<R: BufRead>(decoder: &mut ResponseDecoder, r: &mut R) -> Result<Event> {
+    loop {
+        if let (_, Some(event)) = decoder.decode(&[])? {
+            return Ok(event);
+        }
+        let buf = r.fill_buf()?;
+        if buf.is_empty() {
+            return decoder.eof();
+        }
+        let (n, event) = decoder.decode(buf)?;
+        r.consume(n);
+        if let Some(event) = event {
+            return Ok(event);
+        }
+    }
+}
+
+fn into_io_error(e: anyhow::Error) -> io::Error {
+    e.downcast::<io::Error>()
+        .unwrap_or_else(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+}
+
+// NOTE: Content-Length を信じて確保しすぎないように、先に確保する量はこれまでにする
+pub(crate) const MAX_RESERVE: u64 = 16 * 1024 * 1024;
+
+// ボディを最後まで読む。データは r のバッファから body へ 1 回だけコピーする。トレイラーは読み飛ばす
+pub fn read_body<R: BufRead>(decoder: &mut ResponseDecoder, r: &mut R) -> Result<Vec<u8>> {
+    let mut body = Vec::new();
+    if let Some(BodyKind::Length(n)) = decoder.body_kind() {
+        body.reserve(n.min(MAX_RESERVE) as usize);
+    }
+    while !decoder.is_done() {
+        if let (_, Some(_)) = decoder.decode(&[])? {
+            continue;
+        }
+        let input = r.fill_buf()?;
+        if input.is_empty() {
+            decoder.eof()?;
+            continue;
+        }
+        let n = match decoder.advance_data(input.len()) {
+            Some(n) => {
+                body.extend_from_slice(&input[..n]);
+                n
+            }
+            None => decoder.decode(input)?.0,
+        };
+        r.consume(n);
+    }
+    Ok(body)
+}
+
+// ボディを Read として読む。トレイラーは読み飛ばす
+pub struct DecoderReader<R> {
+    decoder: ResponseDecoder,
+    inner: R,
+}
+
+impl<R: BufRead> DecoderReader<R> {
+    pub fn new(decoder: ResponseDecoder, inner: R) -> Self {
+        Self { decoder, inner }
+    }
+}
+
+impl<R: BufRead> Read for DecoderReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while !self.decoder.is_done() && !buf.is_empty() {
+            if let (_, Some(_)) = self.decoder.decode(&[]).map_err(into_io_error)? {
+                continue;
+            }
+            let input = self.inner.fill_buf()?;
+            if input.is_empty() {
+                self.decoder.eof().map_err(into_io_error)?;
+                continue;
+            }
+            match self.decoder.advance_data(input.len().min(buf.len())) {
+                Some(n) => {
+                    buf[..n].copy_from_slice(&input[..n]);
+                    self.inner.consume(n);
+                    return Ok(n);
+                }
+                None => {
+                    let (n, _) = self.decoder.decode(input).map_err(into_io_error)?;
+                    self.inner.consume(n);
+                }
+            }
+        }
+        Ok(0)
+    }
+}
+
+// リクエストボディの送り方。Content-Length 分だけ送るか、chunked で送る
+pub struct BodyEncoder {
+    length: Option<u64>,
+    written: u64,
+}
+
+impl BodyEncoder {
+    pub fn new(length: Option<u64>) -> Self {
+        Self { length, written: 0 }
+    }
+
+    // 送るバイト列を返す。Content-Length を超えた分は捨てる
+    pub fn encode(&mut self, data: &[u8]) -> Vec<u8> {
+        match self.length {
+            Some(length) => {
+                let n = data.len().min((length - self.written) as usize);
+                self.written += n as u64;
+                data[..n].to_vec()
+            }
+            None if data.is_empty() => Vec::new(),
+            None => {
+                self.written += data.len() as u64;
+                let mut out = format!("{:x}\r\n", data.len()).into_bytes();
+                out.extend_from_slice(data);
+                out.extend_from_slice(b"\r\n");
+                out
+            }
+        }
+    }
+
+    // Content-Length 分送り終えたら、それ以上読む必要はない
+    pub fn is_full(&self) -> bool {
+        self.length == Some(self.written)
+    }
+
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    pub fn finish(&self) -> Result<Vec<u8>> {
+        match self.length {
+            Some(length) if self.written != length => bail!(
+                "request body stream ended after {} of {} bytes",
+                self.written,
+                length
+            ),
+            Some(_) => Ok(Vec::new()),
+            None => Ok(b"0\r\n\r\n".to_vec()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // 1 バイトずつ渡してすべてのイベントを集める
+    fn decode_bytewise(decoder: &mut ResponseDecoder, data: &[u8]) -> Result<(Vec<Event>, usize)> {
+        let mut events = Vec::new();
+        let mut pos = 0;
+        while !decoder.is_done() {
+            let input = &data[pos..(pos + 1).min(data.len())];
+            let (n, event) = decoder.decode(input)?;
+            pos += n;
+            if let Some(event) = event {
+                events.push(event);
+            } else if input.is_empty() {
+                events.push(decoder.eof()?);
+            }
+        }
+        Ok((events, pos))
+    }
+
+    #[test]
+    fn decode_chunked_byte_by_byte() -> Result<()> {
+        let data = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n4\r\nWiki\r\n5;ext=1\r\npedia\r\n0\r\nExpires: never\r\n\r\nHTTP/1.1";
+        let mut decoder = ResponseDecoder::new(&HttpMethod::Get);
+        let (events, consumed) = decode_bytewise(&mut decoder, data)?;
+
+        let want = vec![
+            Event::Status(HttpVersion::Http11, 200),
+            Event::Header("transfer-encoding".into(), "chunked".into()),
+            Event::HeadersComplete,
+            Event::Data(b"W".to_vec()),
+            Event::Data(b"i".to_vec()),
+            Event::Data(b"k".to_vec()),
+            Event::Data(b"i".to_vec()),
+            Event::Data(b"p".to_vec()),
+            Event::Data(b"e".to_vec()),
+            Event::Data(b"d".to_vec()),
+            Event::Data(b"i".to_vec()),
+            Event::Data(b"a".to_vec()),
+            Event::Trailer("expires".into(), "never".into()),
+            Event::End,
+        ];
+        assert_eq!(events, want);
+        assert_eq!(decoder.body_kind(), Some(BodyKind::Chunked));
+        // 次のレスポンスは消費しない
+        assert_eq!(&data[consumed..], b"HTTP/1.1");
+        Ok(())
+    }
+
+    #[test]
+    fn decode_length_body() -> Result<()> {
+        let data = b"HTTP/1.1 201 Created\r\nContent-Length: 5\r\n\r\nhellorest";
+        let mut decoder = ResponseDecoder::new(&HttpMethod::Post);
+
+        let mut events = Vec::new();
+        let mut pos = 0;
+        while !decoder.is_done() {
+            let (n, event) = decoder.decode(&data[pos..])?;
+            pos += n;
+            events.extend(event);
+        }
+        assert_eq!(events[0], Event::Status(HttpVersion::Http11, 201));
+        assert_eq!(events[3], Event::Data(b"hello".to_vec()));
+        assert_eq!(events[4], Event::End);
+        assert_eq!(decoder.header().get("content-length").unwrap(), "5");
+        assert_eq!(&data[pos..], b"rest");
+        Ok(())
+    }
+
+    #[test]
+    fn decode_head_response_without_body() -> Result<()> {
+        let data = b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\n";
+        let mut decoder = ResponseDecoder::new(&HttpMethod::Head);
+        let (events, _) = decode_bytewise(&mut decoder, data)?;
+        assert_eq!(events.last(), Some(&Event::End));
+        assert!(!events.iter().any(|e| matches!(e, Event::Data(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn decode_close_delimited() -> Result<()> {
+        let data = b"HTTP/1.0 200 OK\r\nServer: old\r\n\r\nuntil close";
+        let mut decoder = ResponseDecoder::new(&HttpMethod::Get);
+        let (events, consumed) = decode_bytewise(&mut decoder, data)?;
+        assert_eq!(events[0], Event::Status(HttpVersion::Http10, 200));
+        let body: Vec<u8> = events
+            .iter()
+            .filter_map(|e| match e {
+                Event::Data(d) => Some(d.clone()),
+                _ => None,
+            })
+            .flatten()
+            .collect();
+        assert_eq!(body, b"until close");
+        assert_eq!(events.last(), Some(&Event::End));
+        assert_eq!(decoder.body_kind(), Some(BodyKind::Close));
+        assert_eq!(decoder.version(), HttpVersion::Http10);
+        assert_eq!(consumed, data.len());
+        Ok(())
+    }
+
+    #[test]
+    fn decode_interim_responses() -> Result<()> {
+        let data = b"HTTP/1.1 100 Continue\r\n\r\n\
+                     HTTP/1.1 103 Early Hints\r\nLink: </style.css>\r\n\r\n\
+                     HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok";
+        let mut decoder = ResponseDecoder::new(&HttpMethod::Get);
+        let (events, _) = decode_bytewise(&mut decoder, data)?;
+
+        let mut hints = HttpHeader::new();
+        hints.add("link", "</style.css>");
+        let interim: Vec<&Event> = events
+            .iter()
+            .filter(|e| matches!(e, Event::Interim(..)))
+            .collect();
+        assert_eq!(
+            interim,
+            vec![
+                &Event::Interim(100, HttpHeader::new()),
+                &Event::Interim(103, hints)
+            ]
+        );
+        assert_eq!(decoder.status(), 200);
+        assert_eq!(decoder.header().get("link"), None);
+        assert_eq!(
+            events[events.len() - 3..],
+            [
+                Event::Data(b"o".to_vec()),
+                Event::Data(b"k".to_vec()),
+                Event::End
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn read_body_directly() -> Result<()> {
+        let data = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+            5\r\nhello\r\n6\r\n world\r\n0\r\nX-Sum: 1\r\n\r\nHTTP/1.1";
+        // NOTE: BufReader の容量を小さくして、チャンクが複数回に分かれて読まれる場合も確かめる
+        let mut r = io::BufReader::with_capacity(3, &data[..]);
+        let mut decoder = ResponseDecoder::new(&HttpMethod::Get);
+        while decoder.body_kind().is_none() {
+            read_event(&mut decoder, &mut r)?;
+        }
+        assert_eq!(read_body(&mut decoder, &mut r)?, b"hello world");
+        assert!(decoder.is_done());
+        // 次のレスポンスは読まずに残っている
+        let mut rest = String::new();
+        r.read_to_string(&mut rest)?;
+        assert_eq!(rest, "HTTP/1.1");
+
+        let mut decoder = ResponseDecoder::body(BodyKind::Length(10));
+        let err = read_body(&mut decoder, &mut &b"short"[..]).unwrap_err();
+        assert_eq!(err.to_string(), "unexpected endof");
+        Ok(())
+    }
+
+    #[test]
+    fn decoder_reader_small_buffer() -> Result<()> {
+        let data = b"4\r\nabcd\r\n3\r\nefg\r\n0\r\n\r\n";
+        let mut reader = DecoderReader::new(ResponseDecoder::body(BodyKind::Chunked), &data[..]);
+        let mut buf = [0; 3];
+        let mut out = Vec::new();
+        loop {
+            let n = reader.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            out.extend_from_slice(&buf[..n]);
+        }
+        assert_eq!(out, b"abcdefg");
+        Ok(())
+    }
+
+    #[test]
+    fn decode_truncated() {
+        let mut decoder = ResponseDecoder::new(&HttpMethod::Get);
+        let data = b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nshort";
+        let err = decode_bytewise(&mut decoder, data).unwrap_err();
+        assert_eq!(err.to_string(), "unexpected endof");
+
+        let mut decoder = ResponseDecoder::new(&HttpMethod::Get);
+        let err = decoder.eof().unwrap_err();
+        assert_eq!(err.to_string(), "connection closed before status line");
+    }
+
+    #[test]
+    fn encode_body() -> Result<()> {
+        let mut encoder = BodyEncoder::new(None);
+        let mut out = encoder.encode(b"gorilla");
+        out.extend(encoder.encode(b""));
+        out.extend(encoder.finish()?);
+        assert_eq!(out, b"7\r\ngorilla\r\n0\r\n\r\n");
+
+        let mut encoder = BodyEncoder::new(Some(4));
+        assert_eq!(encoder.encode(b"gorilla"), b"gori");
+        assert!(encoder.is_full());
+        assert!(encoder.finish()?.is_empty());
+
+        let mut encoder = BodyEncoder::new(Some(10));
+        encoder.encode(b"go");
+        assert!(encoder.finish().is_err());
+        Ok(())
+    }
+}
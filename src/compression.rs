@@ -0,0 +1,368 @@
+use anyhow::{bail, Result};
+use std::fmt::Display;
+use std::io::{self, BufRead, BufReader, Read};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+pub const ACCEPT_ENCODING: &str = "gzip, deflate, br, zstd";
+
+// NOTE: gzip でも 0 だけのデータなら 1000 倍を少し超えるくらいに縮むが、正常なレスポンスでそこまで縮むことはまずない。
+// 展開後のサイズが圧縮されたサイズのこの倍数を超えたら zip bomb とみなす
+pub const DEFAULT_MAX_DECOMPRESSION_RATIO: u64 = 1000;
+
+// NOTE: 小さいボディは正常でも圧縮率が高くなりやすいので、展開後のサイズがこれを超えてから比率を確認する
+const RATIO_CHECK_THRESHOLD: u64 = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContentEncoding {
+    Gzip,
+    Deflate,
+    Brotli,
+    Zstd,
+    Identity,
+}
+
+impl Display for ContentEncoding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let encoding = match self {
+            Self::Gzip => "gzip",
+            Self::Deflate => "deflate",
+            Self::Brotli => "br",
+            Self::Zstd => "zstd",
+            Self::Identity => "identity",
+        };
+        write!(f, "{}", encoding)
+    }
+}
+
+impl ContentEncoding {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "gzip" | "x-gzip" => Some(Self::Gzip),
+            "deflate" => Some(Self::Deflate),
+            "br" => Some(Self::Brotli),
+            "zstd" => Some(Self::Zstd),
+            "identity" | "" => Some(Self::Identity),
+            _ => None,
+        }
+    }
+}
+
+// `Content-Encoding: gzip, br` は gzip してから br した順番で並んでいる
+pub fn parse_encodings(value: &str) -> Result<Vec<ContentEncoding>> {
+    let mut encodings = Vec::new();
+    for v in value.split(',') {
+        match ContentEncoding::parse(v) {
+            Some(ContentEncoding::Identity) => {}
+            Some(e) => encodings.push(e),
+            None => bail!("unsupported content-encoding: {}", v.trim()),
+        }
+    }
+    Ok(encodings)
+}
+
+struct CountingReader<R> {
+    inner: R,
+    count: Arc<AtomicU64>,
+}
+
+impl<R: BufRead> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+}
+
+impl<R: BufRead> BufRead for CountingReader<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.count.fetch_add(amt as u64, Ordering::Relaxed);
+        self.inner.consume(amt)
+    }
+}
+
+struct RatioLimit<R> {
+    inner: R,
+    compressed: Arc<AtomicU64>,
+    decompressed: u64,
+    max_ratio: u64,
+}
+
+impl<R: Read> Read for RatioLimit<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.decompressed += n as u64;
+
+        check_ratio(
+            self.decompressed,
+            self.compressed.load(Ordering::Relaxed),
+            self.max_ratio,
+        )?;
+        Ok(n)
+    }
+}
+
+pub(crate) fn check_ratio(decompressed: u64, compressed: u64, max_ratio: u64) -> io::Result<()> {
+    let compressed = compressed.max(1);
+    if decompressed > RATIO_CHECK_THRESHOLD && decompressed / compressed > max_ratio {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "decompression ratio exceeded the limit of {}: {} bytes from {} bytes",
+                max_ratio, decompressed, compressed
+            ),
+        ));
+    }
+    Ok(())
+}
+
+// NOTE: HTTP の deflate は本来 zlib 形式だが、生の deflate を返すサーバーもあるので
+// 先頭 2 バイトが zlib ヘッダかどうかで切り替える
+pub(crate) fn is_zlib(buf: &[u8]) -> bool {
+    if buf.len() < 2 {
+        return false;
+    }
+    let (cmf, flg) = (buf[0] as u16, buf[1] as u16);
+    cmf & 0x0f == 8 && (cmf << 8 | flg) % 31 == 0
+}
+
+fn decode_one<'a>(
+    mut r: Box<dyn BufRead + 'a>,
+    encoding: ContentEncoding,
+) -> io::Result<Box<dyn BufRead + 'a>> {
+    use flate2::bufread::{DeflateDecoder, MultiGzDecoder, ZlibDecoder};
+
+    Ok(match encoding {
+        ContentEncoding::Gzip => Box::new(BufReader::new(MultiGzDecoder::new(r))),
+        ContentEncoding::Deflate => {
+            if is_zlib(r.fill_buf()?) {
+                Box::new(BufReader::new(ZlibDecoder::new(r)))
+            } else {
+                Box::new(BufReader::new(DeflateDecoder::new(r)))
+            }
+        }
+        ContentEncoding::Brotli => Box::new(BufReader::new(brotli::Decompressor::new(r, 8192))),
+        ContentEncoding::Zstd => {
+            Box::new(BufReader::new(zstd::stream::read::Decoder::with_buffer(r)?))
+        }
+        ContentEncoding::Identity => r,
+    })
+}
+
+// encodings は Content-Encoding に書かれた順番のまま渡し、最後にかけられたものから順に外していく
+pub fn decoder<'a, R: BufRead + 'a>(
+    r: R,
+    encodings: &[ContentEncoding],
+    max_ratio: Option<u64>,
+) -> io::Result<Box<dyn BufRead + 'a>> {
+    let compressed = Arc::new(AtomicU64::new(0));
+    let mut r: Box<dyn BufRead + 'a> = Box::new(CountingReader {
+        inner: r,
+        count: compressed.clone(),
+    });
+
+    for encoding in encodings.iter().rev() {
+        r = decode_one(r, *encoding)?;
+    }
+
+    Ok(match max_ratio {
+        Some(max_ratio) => Box::new(BufReader::new(RatioLimit {
+            inner: r,
+            compressed,
+            decompressed: 0,
+            max_ratio,
+        })),
+        None => r,
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Compression {
+    pub encoding: ContentEncoding,
+    pub level: u32,
+}
+
+impl Compression {
+    // level は 0-9
+    pub fn gzip(level: u32) -> Self {
+        Self {
+            encoding: ContentEncoding::Gzip,
+            level,
+        }
+    }
+
+    // level は 1-22
+    pub fn zstd(level: u32) -> Self {
+        Self {
+            encoding: ContentEncoding::Zstd,
+            level,
+        }
+    }
+
+    // level は 0-11
+    pub fn brotli(level: u32) -> Self {
+        Self {
+            encoding: ContentEncoding::Brotli,
+            level,
+        }
+    }
+
+    // level は 0-9
+    pub fn deflate(level: u32) -> Self {
+        Self {
+            encoding: ContentEncoding::Deflate,
+            level,
+        }
+    }
+}
+
+// 読み出すと r を圧縮したバイト列が得られる Read を返す
+pub fn encoder<'a, R: Read + Send + 'a>(
+    r: R,
+    c: Compression,
+) -> io::Result<Box<dyn Read + Send + 'a>> {
+    use flate2::read::{GzEncoder, ZlibEncoder};
+
+    let level = flate2::Compression::new(c.level.min(9));
+    Ok(match c.encoding {
+        ContentEncoding::Gzip => Box::new(GzEncoder::new(r, level)),
+        ContentEncoding::Deflate => Box::new(ZlibEncoder::new(r, level)),
+        ContentEncoding::Brotli => {
+            Box::new(brotli::CompressorReader::new(r, 8192, c.level.min(11), 22))
+        }
+        ContentEncoding::Zstd => {
+            // NOTE: zstd は範囲外のレベルを黙って丸めるので、ここでエラーにする
+            let level = i32::try_from(c.level)
+                .ok()
+                .filter(|l| zstd::compression_level_range().contains(l))
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("invalid zstd compression level: {}", c.level),
+                    )
+                })?;
+            Box::new(zstd::stream::read::Encoder::new(r, level)?)
+        }
+        ContentEncoding::Identity => Box::new(r),
+    })
+}
+
+pub fn encode(data: &[u8], c: Compression) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    encoder(data, c)?.read_to_end(&mut out)?;
+    Ok(out)
+}
+
+pub fn decode(
+    data: &[u8],
+    encodings: &[ContentEncoding],
+    max_ratio: Option<u64>,
+) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    decoder(data, encodings, max_ratio)?.read_to_end(&mut out)?;
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Write;
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut e = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        e.write_all(data).unwrap();
+        e.finish().unwrap()
+    }
+
+    fn brotli(data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        {
+            let mut e = brotli::CompressorWriter::new(&mut out, 4096, 5, 22);
+            e.write_all(data).unwrap();
+        }
+        out
+    }
+
+    #[test]
+    fn parse_content_encoding() -> Result<()> {
+        assert_eq!(
+            parse_encodings("gzip, identity, BR")?,
+            vec![ContentEncoding::Gzip, ContentEncoding::Brotli]
+        );
+        assert!(parse_encodings("compress").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn decode_each_encoding() -> Result<()> {
+        let data = b"hello gorilla hello gorilla hello gorilla";
+
+        let mut zlib = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+        zlib.write_all(data)?;
+        let zlib = zlib.finish()?;
+
+        let mut raw =
+            flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+        raw.write_all(data)?;
+        let raw = raw.finish()?;
+
+        let zstd = zstd::encode_all(&data[..], 3)?;
+
+        let cases = vec![
+            (gzip(data), ContentEncoding::Gzip),
+            (zlib, ContentEncoding::Deflate),
+            (raw, ContentEncoding::Deflate),
+            (brotli(data), ContentEncoding::Brotli),
+            (zstd, ContentEncoding::Zstd),
+        ];
+        for (encoded, encoding) in cases {
+            assert_eq!(decode(&encoded, &[encoding], None)?, data, "{}", encoding);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn decode_stacked() -> Result<()> {
+        let data = b"stacked encodings";
+        // gzip してから br したもの
+        let encoded = brotli(&gzip(data));
+        let encodings = parse_encodings("gzip, br")?;
+        assert_eq!(decode(&encoded, &encodings, None)?, data);
+        Ok(())
+    }
+
+    #[test]
+    fn encode_roundtrip() -> Result<()> {
+        let data = br#"{"name": "gorilla", "age": 5}"#.repeat(100);
+        for c in [
+            Compression::gzip(9),
+            Compression::deflate(1),
+            Compression::brotli(5),
+            Compression::zstd(3),
+        ] {
+            let encoded = encode(&data, c)?;
+            assert!(encoded.len() < data.len(), "{}", c.encoding);
+            assert_eq!(decode(&encoded, &[c.encoding], None)?, data);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn reject_zip_bomb() -> Result<()> {
+        let data = vec![0u8; 10 * 1024 * 1024];
+        let encoded = gzip(&data);
+
+        let err = decode(&encoded, &[ContentEncoding::Gzip], Some(100)).unwrap_err();
+        assert!(err.to_string().contains("decompression ratio exceeded"));
+
+        assert_eq!(
+            decode(&encoded, &[ContentEncoding::Gzip], None)?.len(),
+            data.len()
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,394 @@
+use crate::client::{HttpClient, ReadWriter};
+use crate::resolve::{HappyEyeballs, IpPreference, Resolve, SystemResolver};
+#[cfg(unix)]
+use anyhow::Context;
+use anyhow::{bail, Result};
+use socket2::{Domain, Protocol, Socket, TcpKeepalive, Type};
+use std::io;
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::time::Duration;
+
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(unix)]
+use std::path::PathBuf;
+
+pub trait Connector {
+    type Conn: ReadWriter;
+
+    fn connect(&self, host: &str, port: u16) -> Result<Self::Conn>;
+}
+
+// NOTE: クロージャをそのまま Connector として使えるようにしておくと、
+// テスト用のインメモリ接続や TLS/SOCKS でラップした接続を簡単に差し込める
+impl<F, T> Connector for F
+where
+    F: Fn(&str, u16) -> Result<T>,
+    T: ReadWriter,
+{
+    type Conn = T;
+
+    fn connect(&self, host: &str, port: u16) -> Result<T> {
+        self(host, port)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct SocketOptions {
+    nodelay: bool,
+    keepalive: Option<Duration>,
+    keepalive_interval: Option<Duration>,
+    keepalive_retries: Option<u32>,
+    send_buffer_size: Option<usize>,
+    recv_buffer_size: Option<usize>,
+    local_addr: Option<IpAddr>,
+    interface: Option<String>,
+    connect_timeout: Option<Duration>,
+}
+
+impl SocketOptions {
+    fn keepalive(&self) -> Option<TcpKeepalive> {
+        let time = self.keepalive?;
+        #[allow(unused_mut)]
+        let mut keepalive = TcpKeepalive::new().with_time(time);
+        #[cfg(any(
+            target_os = "android",
+            target_os = "freebsd",
+            target_os = "ios",
+            target_os = "linux",
+            target_os = "macos",
+            target_os = "windows",
+        ))]
+        {
+            if let Some(interval) = self.keepalive_interval {
+                keepalive = keepalive.with_interval(interval);
+            }
+        }
+        #[cfg(any(
+            target_os = "android",
+            target_os = "freebsd",
+            target_os = "ios",
+            target_os = "linux",
+            target_os = "macos",
+        ))]
+        {
+            if let Some(retries) = self.keepalive_retries {
+                keepalive = keepalive.with_retries(retries);
+            }
+        }
+        Some(keepalive)
+    }
+
+    fn bind_device(&self, _socket: &Socket) -> io::Result<()> {
+        let interface = match &self.interface {
+            Some(interface) => interface,
+            None => return Ok(()),
+        };
+
+        #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+        return _socket.bind_device(Some(interface.as_bytes()));
+
+        #[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("cannot bind to interface {} on this platform", interface),
+        ));
+    }
+
+    fn dial(&self, addr: SocketAddr) -> io::Result<TcpStream> {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+
+        self.bind_device(&socket)?;
+        if let Some(ip) = self.local_addr {
+            socket.bind(&SocketAddr::new(ip, 0).into())?;
+        }
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+        if let Some(keepalive) = self.keepalive() {
+            socket.set_tcp_keepalive(&keepalive)?;
+        }
+        socket.set_tcp_nodelay(self.nodelay)?;
+
+        match self.connect_timeout {
+            Some(timeout) => socket.connect_timeout(&addr.into(), timeout)?,
+            None => socket.connect(&addr.into())?,
+        }
+        Ok(socket.into())
+    }
+}
+
+#[derive(Clone)]
+pub struct TcpConnector {
+    resolver: Arc<dyn Resolve>,
+    happy_eyeballs: HappyEyeballs,
+    options: SocketOptions,
+}
+
+impl Default for TcpConnector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TcpConnector {
+    pub fn new() -> Self {
+        Self {
+            resolver: Arc::new(SystemResolver),
+            happy_eyeballs: HappyEyeballs::default(),
+            options: SocketOptions::default(),
+        }
+    }
+
+    pub fn resolver<R: Resolve + 'static>(&mut self, p: R) -> &mut Self {
+        self.resolver = Arc::new(p);
+        self
+    }
+
+    pub fn ip_preference(&mut self, p: IpPreference) -> &mut Self {
+        self.happy_eyeballs.preference = p;
+        self
+    }
+
+    pub fn attempt_delay(&mut self, p: Duration) -> &mut Self {
+        self.happy_eyeballs.attempt_delay = p;
+        self
+    }
+
+    pub fn connect_timeout(&mut self, p: Duration) -> &mut Self {
+        self.options.connect_timeout = Some(p);
+        self
+    }
+
+    pub fn nodelay(&mut self, p: bool) -> &mut Self {
+        self.options.nodelay = p;
+        self
+    }
+
+    // SO_KEEPALIVE を有効にして、最初のプローブを送るまでのアイドル時間を設定する
+    pub fn keepalive(&mut self, p: Duration) -> &mut Self {
+        self.options.keepalive = Some(p);
+        self
+    }
+
+    pub fn keepalive_interval(&mut self, p: Duration) -> &mut Self {
+        self.options.keepalive_interval = Some(p);
+        self
+    }
+
+    pub fn keepalive_retries(&mut self, p: u32) -> &mut Self {
+        self.options.keepalive_retries = Some(p);
+        self
+    }
+
+    pub fn send_buffer_size(&mut self, p: usize) -> &mut Self {
+        self.options.send_buffer_size = Some(p);
+        self
+    }
+
+    pub fn recv_buffer_size(&mut self, p: usize) -> &mut Self {
+        self.options.recv_buffer_size = Some(p);
+        self
+    }
+
+    pub fn local_addr(&mut self, p: IpAddr) -> &mut Self {
+        self.options.local_addr = Some(p);
+        self
+    }
+
+    // SO_BINDTODEVICE を使うので Linux 系のみ対応
+    pub fn interface(&mut self, p: &str) -> &mut Self {
+        self.options.interface = Some(p.into());
+        self
+    }
+}
+
+impl Connector for TcpConnector {
+    type Conn = TcpStream;
+
+    fn connect(&self, host: &str, port: u16) -> Result<TcpStream> {
+        let mut addrs = self.resolver.resolve(host, port)?;
+
+        // ローカルアドレスを固定する場合は同じファミリのアドレスにしか接続できない
+        if let Some(local) = self.options.local_addr {
+            addrs.retain(|a| a.is_ipv4() == local.is_ipv4());
+            if addrs.is_empty() {
+                bail!("no addresses for {} match local address {}", host, local);
+            }
+        }
+
+        let options = self.options.clone();
+        self.happy_eyeballs
+            .connect_with(&addrs, move |addr| options.dial(addr))
+    }
+}
+
+#[cfg(unix)]
+#[derive(Debug, Clone)]
+pub struct UnixConnector {
+    path: PathBuf,
+}
+
+#[cfg(unix)]
+impl UnixConnector {
+    pub fn new<P: AsRef<std::path::Path>>(path: P) -> Self {
+        Self {
+            path: crate::unix::socket_path(path),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Connector for UnixConnector {
+    type Conn = UnixStream;
+
+    // host と port は使わない
+    fn connect(&self, _host: &str, _port: u16) -> Result<UnixStream> {
+        UnixStream::connect(&self.path)
+            .with_context(|| format!("cannot connect to {}", self.path.display()))
+    }
+}
+
+impl<T: ReadWriter> HttpClient<T> {
+    pub fn from_connector<C: Connector<Conn = T>>(
+        connector: &C,
+        host: &str,
+        port: u16,
+    ) -> Result<Self> {
+        Ok(Self::new(connector.connect(host, port)?))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::request::Request;
+    use crate::resolve::OverrideResolver;
+    use httptest::{matchers::*, responders::*, Expectation, ServerBuilder};
+    use std::io::{Cursor, Read, Write};
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    #[test]
+    fn tcp_socket_options() -> Result<()> {
+        let addr: SocketAddr = ([127, 0, 0, 1], 0).into();
+        let server = ServerBuilder::new().bind_addr(addr).run()?;
+        server.expect(
+            Expectation::matching(request::method_path("GET", "/hello"))
+                .respond_with(status_code(200).body("hello")),
+        );
+
+        let mut resolver = OverrideResolver::new();
+        resolver.add("api.internal", Ipv4Addr::LOCALHOST.into());
+
+        let mut connector = TcpConnector::new();
+        connector
+            .resolver(resolver)
+            .nodelay(true)
+            .keepalive(Duration::from_secs(30))
+            .keepalive_interval(Duration::from_secs(5))
+            .keepalive_retries(3)
+            .send_buffer_size(64 * 1024)
+            .recv_buffer_size(64 * 1024)
+            .local_addr(Ipv4Addr::LOCALHOST.into())
+            .connect_timeout(Duration::from_secs(1));
+
+        let conn = connector.connect("api.internal", server.addr().port())?;
+        let sock = socket2::SockRef::from(&conn);
+        assert!(sock.tcp_nodelay()?);
+        assert!(sock.keepalive()?);
+        assert_eq!(sock.tcp_keepalive_time()?, Duration::from_secs(30));
+        #[cfg(target_os = "linux")]
+        {
+            assert_eq!(sock.tcp_keepalive_interval()?, Duration::from_secs(5));
+            assert_eq!(sock.tcp_keepalive_retries()?, 3);
+        }
+        assert!(sock.send_buffer_size()? >= 64 * 1024);
+        assert_eq!(conn.local_addr()?.ip(), IpAddr::from(Ipv4Addr::LOCALHOST));
+
+        let mut client = HttpClient::new(conn);
+        let resp = client.execute_request(&Request::get("/hello"))?;
+        assert_eq!(resp.body.unwrap().text()?, "hello");
+        Ok(())
+    }
+
+    #[test]
+    fn client_from_connector() -> Result<()> {
+        let addr: SocketAddr = ([127, 0, 0, 1], 0).into();
+        let server = ServerBuilder::new().bind_addr(addr).run()?;
+        server.expect(
+            Expectation::matching(request::method_path("GET", "/hello"))
+                .respond_with(status_code(200).body("hello")),
+        );
+
+        let mut client =
+            HttpClient::from_connector(&TcpConnector::new(), "127.0.0.1", server.addr().port())?;
+        let resp = client.execute_request(&Request::get("/hello"))?;
+        assert_eq!(resp.status, 200);
+        Ok(())
+    }
+
+    #[test]
+    fn local_addr_family_mismatch() {
+        let mut connector = TcpConnector::new();
+        connector.local_addr(Ipv6Addr::LOCALHOST.into());
+        let err = connector.connect("127.0.0.1", 80).unwrap_err();
+        assert!(err.to_string().contains("match local address"));
+    }
+
+    // 書き込みを捨てて決まったレスポンスを返すだけのインメモリ接続
+    struct InMemory(Cursor<Vec<u8>>);
+
+    impl Read for InMemory {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    impl Write for InMemory {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn custom_connector() -> Result<()> {
+        let connector = |host: &str, port: u16| -> Result<InMemory> {
+            let body = format!("{}:{}", host, port);
+            let resp = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            Ok(InMemory(Cursor::new(resp.into_bytes())))
+        };
+
+        let mut client = HttpClient::from_connector(&connector, "in-memory", 1234)?;
+        let resp = client.execute_request(&Request::get("/"))?;
+        assert_eq!(resp.body.unwrap().text()?, "in-memory:1234");
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unix_connector() -> Result<()> {
+        let path =
+            std::env::temp_dir().join(format!("http_client-connector-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = std::os::unix::net::UnixListener::bind(&path)?;
+
+        let connector = UnixConnector::new(format!("unix://{}", path.display()));
+        let _conn = connector.connect("localhost", 0)?;
+        assert!(listener.accept().is_ok());
+
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+}
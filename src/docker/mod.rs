@@ -0,0 +1,688 @@
+mod models;
+
+pub use models::*;
+
+use crate::body::BodyReader;
+use crate::client::{HttpClient, ReadWriter};
+use crate::header::HttpHeader;
+use crate::method::HttpMethod;
+use crate::params::HttpParams;
+use crate::request::Request;
+use crate::response::Response;
+use anyhow::{anyhow, bail, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::io::{BufRead, Read};
+
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(unix)]
+use std::path::Path;
+
+pub struct Docker<T: ReadWriter> {
+    client: HttpClient<T>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogStream {
+    Stdin,
+    Stdout,
+    Stderr,
+    // TTY 付きのコンテナは stdout/stderr が多重化されずにそのまま流れてくる
+    Console,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogOutput {
+    pub stream: LogStream,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone)]
+pub struct LogsOptions {
+    pub stdout: bool,
+    pub stderr: bool,
+    pub follow: bool,
+    pub timestamps: bool,
+    pub tail: Option<String>,
+}
+
+impl Default for LogsOptions {
+    fn default() -> Self {
+        Self {
+            stdout: true,
+            stderr: true,
+            follow: false,
+            timestamps: false,
+            tail: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct EventsOptions {
+    pub since: Option<String>,
+    pub until: Option<String>,
+}
+
+pub struct Logs<'a> {
+    body: BodyReader<'a>,
+    multiplexed: bool,
+}
+
+pub struct Events<'a> {
+    body: BodyReader<'a>,
+}
+
+fn flag(b: bool) -> &'static str {
+    if b {
+        "true"
+    } else {
+        "false"
+    }
+}
+
+fn error_message(status: u32, body: &[u8]) -> anyhow::Error {
+    match serde_json::from_slice::<ErrorMessage>(body) {
+        Ok(e) => anyhow!("docker error ({}): {}", status, e.message),
+        Err(_) => anyhow!(
+            "docker error ({}): {}",
+            status,
+            String::from_utf8_lossy(body).trim()
+        ),
+    }
+}
+
+fn check(resp: Response) -> Result<Response> {
+    if resp.status >= 400 {
+        let body = resp.body.map(|b| b.into_bytes()).unwrap_or_default();
+        return Err(error_message(resp.status, &body));
+    }
+    Ok(resp)
+}
+
+fn json_request<B: Serialize>(method: HttpMethod, url: &str, body: &B) -> Result<Request> {
+    let data = serde_json::to_vec(body)?;
+    let header: HttpHeader = [
+        ("Content-Type", "application/json"),
+        ("Content-Length", data.len().to_string().as_str()),
+    ]
+    .into_iter()
+    .collect();
+
+    let mut req = Request::new(url.into());
+    req.method(method).header(header).body(data);
+    Ok(req)
+}
+
+// NOTE: 8 バイトのヘッダ [stream, 0, 0, 0, size(u32 BE)] の後に size バイトのデータが続く
+pub fn read_log_frame<R: BufRead>(r: &mut R) -> Result<Option<LogOutput>> {
+    if r.fill_buf()?.is_empty() {
+        return Ok(None);
+    }
+
+    let mut header = [0u8; 8];
+    r.read_exact(&mut header)?;
+    let stream = match header[0] {
+        0 => LogStream::Stdin,
+        1 => LogStream::Stdout,
+        2 => LogStream::Stderr,
+        n => bail!("unknown log stream type: {}", n),
+    };
+    let size = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+
+    let mut data = vec![0u8; size as usize];
+    r.read_exact(&mut data)?;
+    Ok(Some(LogOutput { stream, data }))
+}
+
+impl Iterator for Logs<'_> {
+    type Item = Result<LogOutput>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.multiplexed {
+            return read_log_frame(&mut self.body).transpose();
+        }
+
+        let data = match self.body.fill_buf() {
+            Ok([]) => return None,
+            Ok(buf) => buf.to_vec(),
+            Err(e) => return Some(Err(e.into())),
+        };
+        self.body.consume(data.len());
+        Some(Ok(LogOutput {
+            stream: LogStream::Console,
+            data,
+        }))
+    }
+}
+
+impl Iterator for Events<'_> {
+    type Item = Result<Event>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let mut line = String::new();
+            match self.body.read_line(&mut line) {
+                Ok(0) => return None,
+                Ok(_) if line.trim().is_empty() => continue,
+                Ok(_) => return Some(serde_json::from_str(&line).map_err(|e| anyhow!("{}", e))),
+                Err(e) => return Some(Err(e.into())),
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Docker<UnixStream> {
+    pub fn connect() -> Result<Self> {
+        Ok(Self::new(HttpClient::docker()?))
+    }
+
+    pub fn unix<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Ok(Self::new(HttpClient::unix(path)?))
+    }
+}
+
+impl<T: ReadWriter> Docker<T> {
+    pub fn new(client: HttpClient<T>) -> Self {
+        Self { client }
+    }
+
+    fn get_json<R: DeserializeOwned>(&mut self, req: &Request) -> Result<R> {
+        let resp = check(self.client.execute_request(req)?)?;
+        if resp.body.is_none() {
+            bail!("empty response body");
+        }
+        resp.json()
+    }
+
+    fn send(&mut self, req: &Request) -> Result<Response> {
+        check(self.client.execute_request(req)?)
+    }
+
+    fn stream(&mut self, req: &Request) -> Result<(HttpHeader, BodyReader<'_>)> {
+        let mut resp = self.client.execute_request_stream(req)?;
+        if resp.status >= 400 {
+            let mut body = Vec::new();
+            resp.body.read_to_end(&mut body)?;
+            return Err(error_message(resp.status, &body));
+        }
+        Ok((resp.header, resp.body))
+    }
+
+    pub fn containers(&mut self, all: bool) -> Result<Vec<ContainerSummary>> {
+        let params: HttpParams = [("all", flag(all))].into_iter().collect();
+        let mut req = Request::get("/containers/json");
+        req.params(params);
+        self.get_json(&req)
+    }
+
+    pub fn inspect_container(&mut self, id: &str) -> Result<ContainerInspect> {
+        self.get_json(&Request::get(&format!("/containers/{}/json", id)))
+    }
+
+    pub fn create_container(
+        &mut self,
+        name: Option<&str>,
+        config: &ContainerConfig,
+    ) -> Result<ContainerCreated> {
+        let mut req = json_request(HttpMethod::Post, "/containers/create", config)?;
+        if let Some(name) = name {
+            let params: HttpParams = [("name", name)].into_iter().collect();
+            req.params(params);
+        }
+        self.get_json(&req)
+    }
+
+    // 204 (started) と 304 (already started) はどちらも成功として扱う
+    pub fn start_container(&mut self, id: &str) -> Result<()> {
+        let mut req = Request::new(format!("/containers/{}/start", id));
+        req.method(HttpMethod::Post);
+        self.send(&req)?;
+        Ok(())
+    }
+
+    pub fn stop_container(&mut self, id: &str, timeout: Option<u64>) -> Result<()> {
+        let mut req = Request::new(format!("/containers/{}/stop", id));
+        req.method(HttpMethod::Post);
+        if let Some(t) = timeout {
+            let t = t.to_string();
+            let params: HttpParams = [("t", t.as_str())].into_iter().collect();
+            req.params(params);
+        }
+        self.send(&req)?;
+        Ok(())
+    }
+
+    pub fn remove_container(&mut self, id: &str, force: bool) -> Result<()> {
+        let params: HttpParams = [("force", flag(force))].into_iter().collect();
+        let mut req = Request::delete(&format!("/containers/{}", id));
+        req.params(params);
+        self.send(&req)?;
+        Ok(())
+    }
+
+    pub fn images(&mut self) -> Result<Vec<ImageSummary>> {
+        self.get_json(&Request::get("/images/json"))
+    }
+
+    // NOTE: pull の進捗は JSON が 1 行ずつ流れてくる。失敗してもステータスは 200 のまま
+    // error フィールドで通知されるので、それもエラーとして返す
+    pub fn pull_image(&mut self, image: &str, tag: &str) -> Result<Vec<PullProgress>> {
+        let params: HttpParams = [("fromImage", image), ("tag", tag)].into_iter().collect();
+        let mut req = Request::new("/images/create".into());
+        req.method(HttpMethod::Post).params(params);
+
+        let (_, body) = self.stream(&req)?;
+        let mut progress = Vec::new();
+        for line in body.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let p: PullProgress = serde_json::from_str(&line)?;
+            if let Some(e) = &p.error {
+                bail!("cannot pull {}:{}: {}", image, tag, e);
+            }
+            progress.push(p);
+        }
+        Ok(progress)
+    }
+
+    pub fn remove_image(&mut self, name: &str, force: bool) -> Result<Vec<ImageDeleted>> {
+        let params: HttpParams = [("force", flag(force))].into_iter().collect();
+        let mut req = Request::delete(&format!("/images/{}", name));
+        req.params(params);
+        self.get_json(&req)
+    }
+
+    pub fn logs(&mut self, id: &str, opts: &LogsOptions) -> Result<Logs<'_>> {
+        let mut params = vec![
+            ("stdout", flag(opts.stdout)),
+            ("stderr", flag(opts.stderr)),
+            ("follow", flag(opts.follow)),
+            ("timestamps", flag(opts.timestamps)),
+        ];
+        if let Some(tail) = &opts.tail {
+            params.push(("tail", tail.as_str()));
+        }
+        let params: HttpParams = params.into_iter().collect();
+        let mut req = Request::get(&format!("/containers/{}/logs", id));
+        req.params(params);
+
+        let (header, body) = self.stream(&req)?;
+        let multiplexed = header
+            .get("content-type")
+            .map(|ct| ct != "application/vnd.docker.raw-stream")
+            .unwrap_or(true);
+        Ok(Logs { body, multiplexed })
+    }
+
+    pub fn events(&mut self, opts: &EventsOptions) -> Result<Events<'_>> {
+        let mut params = Vec::new();
+        if let Some(since) = &opts.since {
+            params.push(("since", since.as_str()));
+        }
+        if let Some(until) = &opts.until {
+            params.push(("until", until.as_str()));
+        }
+        let mut req = Request::get("/events");
+        if !params.is_empty() {
+            req.params(params.into_iter().collect());
+        }
+
+        let (_, body) = self.stream(&req)?;
+        Ok(Events { body })
+    }
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+    use std::io::{BufReader, Write};
+    use std::os::unix::net::UnixListener;
+    use std::path::PathBuf;
+    use std::thread;
+
+    #[derive(Debug)]
+    struct Recorded {
+        line: String,
+        body: String,
+    }
+
+    fn chunked(chunks: &[&[u8]]) -> Vec<u8> {
+        let mut body = Vec::new();
+        for c in chunks {
+            body.extend(format!("{:x}\r\n", c.len()).as_bytes());
+            body.extend(*c);
+            body.extend(b"\r\n");
+        }
+        body.extend(b"0\r\n\r\n");
+        body
+    }
+
+    fn response(status: &str, header: &[(&str, &str)], body: &[u8]) -> Vec<u8> {
+        let mut resp = format!("HTTP/1.1 {}\r\n", status);
+        for (k, v) in header {
+            resp.push_str(&format!("{}: {}\r\n", k, v));
+        }
+        if !header.iter().any(|(k, _)| *k == "Transfer-Encoding") {
+            resp.push_str(&format!("Content-Length: {}\r\n", body.len()));
+        }
+        resp.push_str("\r\n");
+        let mut resp = resp.into_bytes();
+        resp.extend(body);
+        resp
+    }
+
+    // 一時ソケットで待ち受け、受け取ったリクエストに順番に responses を返すモックデーモン
+    fn daemon(name: &str, responses: Vec<Vec<u8>>) -> (PathBuf, thread::JoinHandle<Vec<Recorded>>) {
+        let path = std::env::temp_dir().join(format!(
+            "http_client-docker-{}-{}.sock",
+            std::process::id(),
+            name
+        ));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+
+        let handle = thread::spawn(move || {
+            let (conn, _) = listener.accept().unwrap();
+            let mut r = BufReader::new(conn.try_clone().unwrap());
+            let mut w = conn;
+            let mut recorded = Vec::new();
+            for resp in responses {
+                let mut line = String::new();
+                // Request::build はボディの後ろに CRLF を付けるので空行は読み飛ばす
+                while line.trim().is_empty() {
+                    line.clear();
+                    r.read_line(&mut line).unwrap();
+                }
+                let mut length = 0;
+                loop {
+                    let mut h = String::new();
+                    r.read_line(&mut h).unwrap();
+                    if h == "\r\n" {
+                        break;
+                    }
+                    if let Some(v) = h.to_lowercase().strip_prefix("content-length: ") {
+                        length = v.trim().parse().unwrap();
+                    }
+                }
+                let mut body = vec![0u8; length];
+                r.read_exact(&mut body).unwrap();
+                recorded.push(Recorded {
+                    line: line.trim().to_string(),
+                    body: String::from_utf8(body).unwrap(),
+                });
+                w.write_all(&resp).unwrap();
+            }
+            recorded
+        });
+        (path, handle)
+    }
+
+    #[test]
+    fn list_and_inspect_containers() -> Result<()> {
+        let list = r#"[{"Id":"8dfafdbc3a40","Names":["/boring_feynman"],"Image":"ubuntu:latest","ImageID":"sha256:d74508","Command":"echo 1","Created":1367854155,"State":"exited","Status":"Exit 0","Labels":{"a":"b"}}]"#;
+        let inspect = r#"{"Id":"8dfafdbc3a40","Created":"2015-01-06T15:47:31.485331387Z","Path":"/bin/sh","Args":["-c","exit 9"],"State":{"Status":"running","Running":true,"Pid":42,"ExitCode":0},"Image":"sha256:d74508","Name":"/boring_feynman","Config":{"Image":"ubuntu","Tty":false}}"#;
+        let (path, server) = daemon(
+            "list",
+            vec![
+                response(
+                    "200 OK",
+                    &[("Content-Type", "application/json")],
+                    list.as_bytes(),
+                ),
+                response(
+                    "200 OK",
+                    &[("Content-Type", "application/json")],
+                    inspect.as_bytes(),
+                ),
+            ],
+        );
+
+        let mut docker = Docker::unix(&path)?;
+        let containers = docker.containers(true)?;
+        assert_eq!(containers.len(), 1);
+        assert_eq!(containers[0].id, "8dfafdbc3a40");
+        assert_eq!(containers[0].names, vec!["/boring_feynman"]);
+        assert_eq!(containers[0].image_id, "sha256:d74508");
+
+        let c = docker.inspect_container("8dfafdbc3a40")?;
+        assert!(c.state.running);
+        assert_eq!(c.state.pid, 42);
+        assert_eq!(c.args, vec!["-c", "exit 9"]);
+        assert_eq!(c.config.unwrap().image, "ubuntu");
+
+        let recorded = server.join().unwrap();
+        assert_eq!(recorded[0].line, "GET /containers/json?all=true HTTP/1.1");
+        assert_eq!(
+            recorded[1].line,
+            "GET /containers/8dfafdbc3a40/json HTTP/1.1"
+        );
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn container_lifecycle() -> Result<()> {
+        let (path, server) = daemon(
+            "lifecycle",
+            vec![
+                response(
+                    "201 Created",
+                    &[],
+                    br#"{"Id":"e90e34656806","Warnings":[]}"#,
+                ),
+                response("204 No Content", &[], b""),
+                response("304 Not Modified", &[], b""),
+                response("204 No Content", &[], b""),
+            ],
+        );
+
+        let mut docker = Docker::unix(&path)?;
+        let config = ContainerConfig {
+            image: "ubuntu".into(),
+            cmd: Some(vec!["date".into()]),
+            ..Default::default()
+        };
+        let created = docker.create_container(Some("gorilla"), &config)?;
+        assert_eq!(created.id, "e90e34656806");
+
+        docker.start_container(&created.id)?;
+        docker.stop_container(&created.id, Some(5))?;
+        docker.remove_container(&created.id, true)?;
+
+        let recorded = server.join().unwrap();
+        assert_eq!(
+            recorded[0].line,
+            "POST /containers/create?name=gorilla HTTP/1.1"
+        );
+        let body: serde_json::Value = serde_json::from_str(&recorded[0].body)?;
+        assert_eq!(body["Image"], "ubuntu");
+        assert_eq!(body["Cmd"], serde_json::json!(["date"]));
+        assert!(body.get("Env").is_none());
+        assert_eq!(
+            recorded[1].line,
+            "POST /containers/e90e34656806/start HTTP/1.1"
+        );
+        assert_eq!(
+            recorded[2].line,
+            "POST /containers/e90e34656806/stop?t=5 HTTP/1.1"
+        );
+        assert_eq!(
+            recorded[3].line,
+            "DELETE /containers/e90e34656806?force=true HTTP/1.1"
+        );
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn images() -> Result<()> {
+        let list = r#"[{"Id":"sha256:e216a0","ParentId":"","RepoTags":["ubuntu:12.04"],"Created":1474925151,"Size":103579269}]"#;
+        let pull = chunked(&[
+            b"{\"status\":\"Pulling from library/ubuntu\",\"id\":\"latest\"}\r\n",
+            b"{\"status\":\"Download complete\",\"id\":\"a3ed95caeb02\"}\r\n",
+        ]);
+        let (path, server) = daemon(
+            "images",
+            vec![
+                response("200 OK", &[], list.as_bytes()),
+                response("200 OK", &[("Transfer-Encoding", "chunked")], &pull),
+                response(
+                    "200 OK",
+                    &[],
+                    br#"[{"Untagged":"ubuntu:12.04"},{"Deleted":"sha256:e216a0"}]"#,
+                ),
+            ],
+        );
+
+        let mut docker = Docker::unix(&path)?;
+        let images = docker.images()?;
+        assert_eq!(images[0].repo_tags, Some(vec!["ubuntu:12.04".into()]));
+        assert_eq!(images[0].size, 103579269);
+
+        let progress = docker.pull_image("ubuntu", "latest")?;
+        assert_eq!(progress.len(), 2);
+        assert_eq!(progress[1].status.as_deref(), Some("Download complete"));
+
+        let deleted = docker.remove_image("ubuntu:12.04", false)?;
+        assert_eq!(deleted[1].deleted.as_deref(), Some("sha256:e216a0"));
+
+        let recorded = server.join().unwrap();
+        assert_eq!(
+            recorded[1].line,
+            "POST /images/create?fromImage=ubuntu&tag=latest HTTP/1.1"
+        );
+        assert_eq!(
+            recorded[2].line,
+            "DELETE /images/ubuntu:12.04?force=false HTTP/1.1"
+        );
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn pull_error_in_progress() -> Result<()> {
+        let pull = chunked(&[b"{\"error\":\"manifest unknown\"}\r\n"]);
+        let (path, _server) = daemon(
+            "pull-error",
+            vec![response(
+                "200 OK",
+                &[("Transfer-Encoding", "chunked")],
+                &pull,
+            )],
+        );
+
+        let mut docker = Docker::unix(&path)?;
+        let err = docker.pull_image("ubuntu", "nope").unwrap_err();
+        assert!(err.to_string().contains("manifest unknown"));
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn multiplexed_logs() -> Result<()> {
+        let mut stdout = vec![1, 0, 0, 0, 0, 0, 0, 6];
+        stdout.extend(b"hello\n");
+        let mut stderr = vec![2, 0, 0, 0, 0, 0, 0, 5];
+        stderr.extend(b"oops\n");
+        // フレームがチャンクの境界をまたいでも読めること
+        let body = chunked(&[&stdout[..3], &stdout[3..], &stderr]);
+        let (path, server) = daemon(
+            "logs",
+            vec![response(
+                "200 OK",
+                &[
+                    ("Content-Type", "application/vnd.docker.multiplexed-stream"),
+                    ("Transfer-Encoding", "chunked"),
+                ],
+                &body,
+            )],
+        );
+
+        let mut docker = Docker::unix(&path)?;
+        let opts = LogsOptions {
+            tail: Some("10".into()),
+            ..Default::default()
+        };
+        let logs = docker
+            .logs("e90e34656806", &opts)?
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(
+            logs,
+            vec![
+                LogOutput {
+                    stream: LogStream::Stdout,
+                    data: b"hello\n".to_vec()
+                },
+                LogOutput {
+                    stream: LogStream::Stderr,
+                    data: b"oops\n".to_vec()
+                },
+            ]
+        );
+
+        let recorded = server.join().unwrap();
+        assert_eq!(
+            recorded[0].line,
+            "GET /containers/e90e34656806/logs?stdout=true&stderr=true&follow=false&timestamps=false&tail=10 HTTP/1.1"
+        );
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn events_stream() -> Result<()> {
+        let body = chunked(&[
+            br#"{"Type":"container","Action":"start","Actor":{"ID":"ede54ee1afda","Attributes":{"image":"alpine"}},"scope":"local","time":1461943101,"timeNano":1461943101381709551}"#,
+            b"\n",
+            br#"{"Type":"container","Action":"die","Actor":{"ID":"ede54ee1afda","Attributes":{}},"time":1461943105}"#,
+            b"\n",
+        ]);
+        let (path, _server) = daemon(
+            "events",
+            vec![response(
+                "200 OK",
+                &[("Transfer-Encoding", "chunked")],
+                &body,
+            )],
+        );
+
+        let mut docker = Docker::unix(&path)?;
+        let mut events = docker.events(&EventsOptions::default())?;
+
+        let e = events.next().unwrap()?;
+        assert_eq!(e.typ, "container");
+        assert_eq!(e.action, "start");
+        assert_eq!(e.actor.attributes["image"], "alpine");
+        let e = events.next().unwrap()?;
+        assert_eq!(e.action, "die");
+        assert!(events.next().is_none());
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn error_message_from_daemon() -> Result<()> {
+        let (path, _server) = daemon(
+            "error",
+            vec![response(
+                "404 Not Found",
+                &[("Content-Type", "application/json")],
+                br#"{"message":"No such container: nope"}"#,
+            )],
+        );
+
+        let mut docker = Docker::unix(&path)?;
+        let err = docker.inspect_container("nope").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "docker error (404): No such container: nope"
+        );
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,110 @@
+use crate::params::{form_decode, form_encode, HttpParams};
+use crate::query::{self, ArrayStyle};
+use anyhow::Result;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fmt::Display;
+use std::iter::FromIterator;
+use std::str::FromStr;
+
+pub const CONTENT_TYPE: &str = "application/x-www-form-urlencoded";
+
+// application/x-www-form-urlencoded のボディ。HttpParams と違い、空白は + でエンコードする
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Form(HttpParams);
+
+impl Display for Form {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut buf = Vec::<String>::new();
+        for (k, v) in self.0.iter() {
+            buf.push(format!("{}={}", form_encode(k), form_encode(v)));
+        }
+        write!(f, "{}", buf.join("&"))
+    }
+}
+
+impl<'a> FromIterator<(&'a str, &'a str)> for Form {
+    fn from_iter<T: IntoIterator<Item = (&'a str, &'a str)>>(iter: T) -> Self {
+        Self(iter.into_iter().collect())
+    }
+}
+
+impl FromStr for Form {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::parse(s)
+    }
+}
+
+impl Form {
+    pub fn new() -> Self {
+        Self(HttpParams::new())
+    }
+
+    pub fn parse(body: &str) -> Result<Self> {
+        let mut form = Self::new();
+        for pair in body.split('&').filter(|s| !s.is_empty()) {
+            let (k, v) = pair.split_once('=').unwrap_or((pair, ""));
+            form.append(&form_decode(k)?, &form_decode(v)?);
+        }
+        Ok(form)
+    }
+
+    // 構造体をフォームにする。配列はキーを繰り返し、None のフィールドは付けない
+    pub fn from_serialize<T: Serialize>(value: &T) -> Result<Self> {
+        Ok(Self(query::to_params(value, ArrayStyle::Repeat)?))
+    }
+
+    pub fn deserialize<T: DeserializeOwned>(&self) -> Result<T> {
+        query::from_params(&self.0, ArrayStyle::Repeat)
+    }
+
+    pub fn append(&mut self, key: &str, value: &str) -> &mut Self {
+        self.0.append(key, value);
+        self
+    }
+
+    pub fn params(&self) -> &HttpParams {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Token {
+        grant_type: String,
+        scope: Vec<String>,
+        code: Option<String>,
+    }
+
+    #[test]
+    fn encode_and_parse() -> Result<()> {
+        let mut form = Form::new();
+        form.append("user name", "a+b c").append("note", "日本*~");
+        let encoded = form.to_string();
+        assert_eq!(encoded, "user+name=a%2Bb+c&note=%E6%97%A5%E6%9C%AC*%7E");
+        assert_eq!(Form::parse(&encoded)?, form);
+        Ok(())
+    }
+
+    #[test]
+    fn serde_roundtrip() -> Result<()> {
+        let token = Token {
+            grant_type: "client credentials".into(),
+            scope: vec!["read".into(), "write".into()],
+            code: None,
+        };
+        let form = Form::from_serialize(&token)?;
+        assert_eq!(
+            form.to_string(),
+            "grant_type=client+credentials&scope=read&scope=write"
+        );
+        assert_eq!(form.deserialize::<Token>()?, token);
+        Ok(())
+    }
+}
@@ -0,0 +1,97 @@
+use crate::form;
+
+pub const JSON: &str = "application/json";
+pub const MSGPACK: &str = "application/msgpack";
+pub const CBOR: &str = "application/cbor";
+pub const XML: &str = "application/xml";
+
+// serde でエンコード・デコードできるボディの形式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Form,
+    MsgPack,
+    Cbor,
+    Xml,
+}
+
+impl Format {
+    // Content-Type から形式を決める。application/problem+json のような構造化構文の接尾辞 (RFC 6839) も見る
+    pub fn from_content_type(content_type: &str) -> Option<Self> {
+        let mime = content_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+        let format = match mime.as_str() {
+            JSON => Self::Json,
+            form::CONTENT_TYPE => Self::Form,
+            MSGPACK | "application/x-msgpack" | "application/vnd.msgpack" => Self::MsgPack,
+            CBOR => Self::Cbor,
+            XML | "text/xml" => Self::Xml,
+            m if m.ends_with("+json") => Self::Json,
+            m if m.ends_with("+cbor") => Self::Cbor,
+            m if m.ends_with("+xml") => Self::Xml,
+            _ => return None,
+        };
+        Some(format)
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Json => JSON,
+            Self::Form => form::CONTENT_TYPE,
+            Self::MsgPack => MSGPACK,
+            Self::Cbor => CBOR,
+            Self::Xml => XML,
+        }
+    }
+
+    // この形式を使うのに必要な cargo の feature
+    pub fn feature(&self) -> Option<&'static str> {
+        match self {
+            Self::Json | Self::Form => None,
+            Self::MsgPack => Some("msgpack"),
+            Self::Cbor => Some("cbor"),
+            Self::Xml => Some("xml"),
+        }
+    }
+}
+
+impl std::fmt::Display for Format {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Self::Json => "JSON",
+            Self::Form => "form",
+            Self::MsgPack => "MessagePack",
+            Self::Cbor => "CBOR",
+            Self::Xml => "XML",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn from_content_type() {
+        let format = Format::from_content_type;
+        assert_eq!(
+            format("application/json; charset=utf-8"),
+            Some(Format::Json)
+        );
+        assert_eq!(format("Application/Problem+JSON"), Some(Format::Json));
+        assert_eq!(
+            format("application/x-www-form-urlencoded"),
+            Some(Format::Form)
+        );
+        assert_eq!(format("application/x-msgpack"), Some(Format::MsgPack));
+        assert_eq!(format("application/cbor"), Some(Format::Cbor));
+        assert_eq!(format("text/xml; charset=Shift_JIS"), Some(Format::Xml));
+        assert_eq!(format("application/atom+xml"), Some(Format::Xml));
+        assert_eq!(format("text/plain"), None);
+    }
+}
@@ -0,0 +1,378 @@
+use anyhow::{anyhow, bail, Result};
+use std::io::{Read, Write};
+
+pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+pub const DEFAULT_MAX_FRAME_SIZE: u32 = 16_384;
+pub const DEFAULT_WINDOW_SIZE: u32 = 65_535;
+const MAX_WINDOW_SIZE: u32 = (1 << 31) - 1;
+
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const PRIORITY: u8 = 0x2;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const PUSH_PROMISE: u8 = 0x5;
+const PING: u8 = 0x6;
+const GOAWAY: u8 = 0x7;
+const WINDOW_UPDATE: u8 = 0x8;
+const CONTINUATION: u8 = 0x9;
+
+const END_STREAM: u8 = 0x1;
+const ACK: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+const PADDED: u8 = 0x8;
+const PRIORITY_FLAG: u8 = 0x20;
+
+pub const SETTINGS_HEADER_TABLE_SIZE: u16 = 0x1;
+pub const SETTINGS_ENABLE_PUSH: u16 = 0x2;
+pub const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+pub const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+pub const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+
+// RFC 9113 7. Error Codes
+pub fn error_name(code: u32) -> &'static str {
+    match code {
+        0x0 => "NO_ERROR",
+        0x1 => "PROTOCOL_ERROR",
+        0x2 => "INTERNAL_ERROR",
+        0x3 => "FLOW_CONTROL_ERROR",
+        0x4 => "SETTINGS_TIMEOUT",
+        0x5 => "STREAM_CLOSED",
+        0x6 => "FRAME_SIZE_ERROR",
+        0x7 => "REFUSED_STREAM",
+        0x8 => "CANCEL",
+        0x9 => "COMPRESSION_ERROR",
+        0xa => "CONNECT_ERROR",
+        0xb => "ENHANCE_YOUR_CALM",
+        0xc => "INADEQUATE_SECURITY",
+        0xd => "HTTP_1_1_REQUIRED",
+        _ => "UNKNOWN_ERROR",
+    }
+}
+
+pub const PROTOCOL_ERROR: u32 = 0x1;
+pub const FLOW_CONTROL_ERROR: u32 = 0x3;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Frame {
+    Data {
+        stream_id: u32,
+        data: Vec<u8>,
+        end_stream: bool,
+        // パディングも含めたフロー制御上の長さ
+        flow_len: u32,
+    },
+    Headers {
+        stream_id: u32,
+        block: Vec<u8>,
+        end_stream: bool,
+        end_headers: bool,
+    },
+    Continuation {
+        stream_id: u32,
+        block: Vec<u8>,
+        end_headers: bool,
+    },
+    RstStream {
+        stream_id: u32,
+        error_code: u32,
+    },
+    Settings {
+        ack: bool,
+        values: Vec<(u16, u32)>,
+    },
+    PushPromise {
+        stream_id: u32,
+    },
+    Ping {
+        ack: bool,
+        data: [u8; 8],
+    },
+    GoAway {
+        last_stream_id: u32,
+        error_code: u32,
+        debug: Vec<u8>,
+    },
+    WindowUpdate {
+        stream_id: u32,
+        increment: u32,
+    },
+    // PRIORITY や知らない種類のフレームは読み飛ばす
+    Ignored,
+}
+
+fn u32_at(buf: &[u8], i: usize) -> u32 {
+    u32::from_be_bytes([buf[i], buf[i + 1], buf[i + 2], buf[i + 3]]) & MAX_WINDOW_SIZE
+}
+
+// PADDED フラグがあればパディングを取り除く
+fn strip_padding(flags: u8, mut payload: Vec<u8>) -> Result<Vec<u8>> {
+    if flags & PADDED == 0 {
+        return Ok(payload);
+    }
+    let pad = *payload
+        .first()
+        .ok_or_else(|| anyhow!("invalid padded frame"))? as usize;
+    if pad + 1 > payload.len() {
+        bail!("padding exceeds frame payload");
+    }
+    payload.truncate(payload.len() - pad);
+    payload.remove(0);
+    Ok(payload)
+}
+
+pub fn read_frame<R: Read>(r: &mut R, max_frame_size: u32) -> Result<Frame> {
+    let mut head = [0u8; 9];
+    r.read_exact(&mut head)?;
+    let len = u32::from_be_bytes([0, head[0], head[1], head[2]]);
+    let (kind, flags) = (head[3], head[4]);
+    let stream_id = u32_at(&head, 5);
+    if len > max_frame_size {
+        bail!("frame too large: {} bytes", len);
+    }
+
+    let mut payload = vec![0u8; len as usize];
+    r.read_exact(&mut payload)?;
+
+    let frame = match kind {
+        DATA => Frame::Data {
+            stream_id,
+            data: strip_padding(flags, payload)?,
+            end_stream: flags & END_STREAM != 0,
+            flow_len: len,
+        },
+        HEADERS => {
+            let mut block = strip_padding(flags, payload)?;
+            if flags & PRIORITY_FLAG != 0 {
+                if block.len() < 5 {
+                    bail!("invalid headers frame priority");
+                }
+                block.drain(..5);
+            }
+            Frame::Headers {
+                stream_id,
+                block,
+                end_stream: flags & END_STREAM != 0,
+                end_headers: flags & END_HEADERS != 0,
+            }
+        }
+        CONTINUATION => Frame::Continuation {
+            stream_id,
+            block: payload,
+            end_headers: flags & END_HEADERS != 0,
+        },
+        RST_STREAM if len == 4 => Frame::RstStream {
+            stream_id,
+            error_code: u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]),
+        },
+        SETTINGS if len.is_multiple_of(6) => Frame::Settings {
+            ack: flags & ACK != 0,
+            values: payload
+                .chunks(6)
+                .map(|c| {
+                    (
+                        u16::from_be_bytes([c[0], c[1]]),
+                        u32::from_be_bytes([c[2], c[3], c[4], c[5]]),
+                    )
+                })
+                .collect(),
+        },
+        PUSH_PROMISE => Frame::PushPromise { stream_id },
+        PING if len == 8 => {
+            let mut data = [0u8; 8];
+            data.copy_from_slice(&payload);
+            Frame::Ping {
+                ack: flags & ACK != 0,
+                data,
+            }
+        }
+        GOAWAY if len >= 8 => Frame::GoAway {
+            last_stream_id: u32_at(&payload, 0),
+            error_code: u32::from_be_bytes([payload[4], payload[5], payload[6], payload[7]]),
+            debug: payload[8..].to_vec(),
+        },
+        WINDOW_UPDATE if len == 4 => Frame::WindowUpdate {
+            stream_id,
+            increment: u32_at(&payload, 0),
+        },
+        RST_STREAM | SETTINGS | PING | GOAWAY | WINDOW_UPDATE => {
+            bail!("invalid frame size {} for frame type {}", len, kind)
+        }
+        PRIORITY => Frame::Ignored,
+        _ => Frame::Ignored,
+    };
+    Ok(frame)
+}
+
+fn write_raw<W: Write>(
+    w: &mut W,
+    kind: u8,
+    flags: u8,
+    stream_id: u32,
+    payload: &[u8],
+) -> Result<()> {
+    let len = (payload.len() as u32).to_be_bytes();
+    let mut head = [0u8; 9];
+    head[..3].copy_from_slice(&len[1..]);
+    head[3] = kind;
+    head[4] = flags;
+    head[5..].copy_from_slice(&stream_id.to_be_bytes());
+    w.write_all(&head)?;
+    w.write_all(payload)?;
+    Ok(())
+}
+
+pub fn write_frame<W: Write>(w: &mut W, frame: &Frame) -> Result<()> {
+    match frame {
+        Frame::Data {
+            stream_id,
+            data,
+            end_stream,
+            ..
+        } => write_raw(
+            w,
+            DATA,
+            if *end_stream { END_STREAM } else { 0 },
+            *stream_id,
+            data,
+        ),
+        Frame::Headers {
+            stream_id,
+            block,
+            end_stream,
+            end_headers,
+        } => {
+            let mut flags = 0;
+            if *end_stream {
+                flags |= END_STREAM;
+            }
+            if *end_headers {
+                flags |= END_HEADERS;
+            }
+            write_raw(w, HEADERS, flags, *stream_id, block)
+        }
+        Frame::Continuation {
+            stream_id,
+            block,
+            end_headers,
+        } => write_raw(
+            w,
+            CONTINUATION,
+            if *end_headers { END_HEADERS } else { 0 },
+            *stream_id,
+            block,
+        ),
+        Frame::RstStream {
+            stream_id,
+            error_code,
+        } => write_raw(w, RST_STREAM, 0, *stream_id, &error_code.to_be_bytes()),
+        Frame::Settings { ack, values } => {
+            let mut payload = Vec::new();
+            for (id, value) in values {
+                payload.extend_from_slice(&id.to_be_bytes());
+                payload.extend_from_slice(&value.to_be_bytes());
+            }
+            write_raw(w, SETTINGS, if *ack { ACK } else { 0 }, 0, &payload)
+        }
+        Frame::Ping { ack, data } => write_raw(w, PING, if *ack { ACK } else { 0 }, 0, data),
+        Frame::GoAway {
+            last_stream_id,
+            error_code,
+            debug,
+        } => {
+            let mut payload = last_stream_id.to_be_bytes().to_vec();
+            payload.extend_from_slice(&error_code.to_be_bytes());
+            payload.extend_from_slice(debug);
+            write_raw(w, GOAWAY, 0, 0, &payload)
+        }
+        Frame::WindowUpdate {
+            stream_id,
+            increment,
+        } => write_raw(w, WINDOW_UPDATE, 0, *stream_id, &increment.to_be_bytes()),
+        Frame::PushPromise { .. } | Frame::Ignored => bail!("cannot send this frame"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn roundtrip() -> Result<()> {
+        let frames = vec![
+            Frame::Headers {
+                stream_id: 1,
+                block: vec![0x82, 0x84],
+                end_stream: true,
+                end_headers: true,
+            },
+            Frame::Data {
+                stream_id: 3,
+                data: b"gorilla".to_vec(),
+                end_stream: false,
+                flow_len: 7,
+            },
+            Frame::Settings {
+                ack: false,
+                values: vec![
+                    (SETTINGS_ENABLE_PUSH, 0),
+                    (SETTINGS_INITIAL_WINDOW_SIZE, 1 << 20),
+                ],
+            },
+            Frame::GoAway {
+                last_stream_id: 5,
+                error_code: 0,
+                debug: b"bye".to_vec(),
+            },
+            Frame::WindowUpdate {
+                stream_id: 0,
+                increment: 1000,
+            },
+        ];
+
+        let mut buf = Vec::new();
+        for f in &frames {
+            write_frame(&mut buf, f)?;
+        }
+        let mut r = buf.as_slice();
+        for f in frames {
+            assert_eq!(read_frame(&mut r, DEFAULT_MAX_FRAME_SIZE)?, f);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn read_padded_data() -> Result<()> {
+        // 長さ 8 = パディング長 1 + データ 4 + パディング 3
+        let buf = [
+            0,
+            0,
+            8,
+            DATA,
+            PADDED | END_STREAM,
+            0,
+            0,
+            0,
+            1,
+            3,
+            b'a',
+            b'b',
+            b'c',
+            b'd',
+            0,
+            0,
+            0,
+        ];
+        let frame = read_frame(&mut &buf[..], DEFAULT_MAX_FRAME_SIZE)?;
+        assert_eq!(
+            frame,
+            Frame::Data {
+                stream_id: 1,
+                data: b"abcd".to_vec(),
+                end_stream: true,
+                flow_len: 8,
+            }
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,454 @@
+use super::huffman_table::CODES;
+use anyhow::{anyhow, bail, Result};
+use std::collections::{HashMap, VecDeque};
+use std::sync::OnceLock;
+
+// RFC 7541 Appendix A
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+pub const DEFAULT_TABLE_SIZE: usize = 4096;
+
+// NOTE: エントリのサイズは名前と値の長さに 32 を足したもの (RFC 7541 4.1)
+fn entry_size(name: &str, value: &str) -> usize {
+    name.len() + value.len() + 32
+}
+
+struct DynamicTable {
+    entries: VecDeque<(String, String)>,
+    size: usize,
+    max_size: usize,
+}
+
+impl DynamicTable {
+    fn new(max_size: usize) -> Self {
+        Self {
+            entries: VecDeque::new(),
+            size: 0,
+            max_size,
+        }
+    }
+
+    fn evict(&mut self) {
+        while self.size > self.max_size {
+            match self.entries.pop_back() {
+                Some((name, value)) => self.size -= entry_size(&name, &value),
+                None => break,
+            }
+        }
+    }
+
+    fn insert(&mut self, name: String, value: String) {
+        self.size += entry_size(&name, &value);
+        self.entries.push_front((name, value));
+        self.evict();
+    }
+
+    fn set_max_size(&mut self, max_size: usize) {
+        self.max_size = max_size;
+        self.evict();
+    }
+}
+
+fn encode_int(value: usize, prefix: u8, first: u8, out: &mut Vec<u8>) {
+    let max = (1usize << prefix) - 1;
+    if value < max {
+        out.push(first | value as u8);
+        return;
+    }
+    out.push(first | max as u8);
+    let mut value = value - max;
+    while value >= 128 {
+        out.push((value % 128) as u8 | 0x80);
+        value /= 128;
+    }
+    out.push(value as u8);
+}
+
+fn decode_int(buf: &[u8], pos: &mut usize, prefix: u8) -> Result<usize> {
+    let max = (1usize << prefix) - 1;
+    let first = *buf
+        .get(*pos)
+        .ok_or_else(|| anyhow!("truncated hpack integer"))?;
+    *pos += 1;
+    let mut value = first as usize & max;
+    if value < max {
+        return Ok(value);
+    }
+
+    let mut shift = 0;
+    loop {
+        let b = *buf
+            .get(*pos)
+            .ok_or_else(|| anyhow!("truncated hpack integer"))?;
+        *pos += 1;
+        if shift > 28 {
+            bail!("hpack integer overflow");
+        }
+        value += ((b & 0x7f) as usize) << shift;
+        shift += 7;
+        if b & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+}
+
+pub fn huffman_encode(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut bits: u64 = 0;
+    let mut len = 0;
+    for b in data {
+        let (code, n) = CODES[*b as usize];
+        bits = bits << n | code as u64;
+        len += n as u32;
+        while len >= 8 {
+            len -= 8;
+            out.push((bits >> len) as u8);
+        }
+    }
+    // 残りは EOS の先頭 (1 のビット) で埋める
+    if len > 0 {
+        out.push((bits << (8 - len)) as u8 | (0xff >> len));
+    }
+    out
+}
+
+fn huffman_len(data: &[u8]) -> usize {
+    let bits: usize = data.iter().map(|b| CODES[*b as usize].1 as usize).sum();
+    bits.div_ceil(8)
+}
+
+// (ビット数, 符号) からシンボルを引く
+fn decode_map() -> &'static HashMap<(u8, u32), u16> {
+    static MAP: OnceLock<HashMap<(u8, u32), u16>> = OnceLock::new();
+    MAP.get_or_init(|| {
+        CODES
+            .iter()
+            .enumerate()
+            .map(|(sym, (code, n))| ((*n, *code), sym as u16))
+            .collect()
+    })
+}
+
+pub fn huffman_decode(data: &[u8]) -> Result<Vec<u8>> {
+    let map = decode_map();
+    let mut out = Vec::new();
+    let mut code: u32 = 0;
+    let mut len: u8 = 0;
+    for b in data {
+        for i in (0..8).rev() {
+            code = code << 1 | ((b >> i) & 1) as u32;
+            len += 1;
+            match map.get(&(len, code)) {
+                Some(256) => bail!("hpack huffman string contains EOS"),
+                Some(sym) => {
+                    out.push(*sym as u8);
+                    code = 0;
+                    len = 0;
+                }
+                None if len >= 30 => bail!("invalid hpack huffman code"),
+                None => {}
+            }
+        }
+    }
+    // パディングは 7 ビット以下で、すべて 1 でなければならない
+    if len > 7 || code != (1 << len) - 1 {
+        bail!("invalid hpack huffman padding");
+    }
+    Ok(out)
+}
+
+fn encode_string(s: &str, out: &mut Vec<u8>) {
+    let huffman = huffman_len(s.as_bytes());
+    if huffman < s.len() {
+        encode_int(huffman, 7, 0x80, out);
+        out.extend(huffman_encode(s.as_bytes()));
+    } else {
+        encode_int(s.len(), 7, 0, out);
+        out.extend_from_slice(s.as_bytes());
+    }
+}
+
+fn decode_string(buf: &[u8], pos: &mut usize) -> Result<String> {
+    let huffman = buf.get(*pos).map(|b| b & 0x80 != 0).unwrap_or(false);
+    let len = decode_int(buf, pos, 7)?;
+    let data = buf
+        .get(*pos..*pos + len)
+        .ok_or_else(|| anyhow!("truncated hpack string"))?;
+    *pos += len;
+    let data = if huffman {
+        huffman_decode(data)?
+    } else {
+        data.to_vec()
+    };
+    Ok(String::from_utf8(data)?)
+}
+
+// NOTE: 動的テーブルは使わず、静的テーブルにあるものだけ参照する。
+// 相手の SETTINGS_HEADER_TABLE_SIZE に関係なく正しく動く
+#[derive(Default)]
+pub struct Encoder {}
+
+impl Encoder {
+    pub fn new() -> Self {
+        Self {}
+    }
+
+    pub fn encode<'a, I: IntoIterator<Item = (&'a str, &'a str)>>(
+        &mut self,
+        headers: I,
+    ) -> Vec<u8> {
+        let mut out = Vec::new();
+        for (name, value) in headers {
+            if let Some(i) = STATIC_TABLE
+                .iter()
+                .position(|(n, v)| *n == name && *v == value)
+            {
+                // Indexed Header Field
+                encode_int(i + 1, 7, 0x80, &mut out);
+                continue;
+            }
+
+            // 認証情報などは中継するプロキシにもインデックスさせない
+            let first = match name {
+                "authorization" | "proxy-authorization" | "cookie" | "set-cookie" => 0x10,
+                _ => 0x00,
+            };
+            match STATIC_TABLE.iter().position(|(n, _)| *n == name) {
+                Some(i) => encode_int(i + 1, 4, first, &mut out),
+                None => {
+                    out.push(first);
+                    encode_string(name, &mut out);
+                }
+            }
+            encode_string(value, &mut out);
+        }
+        out
+    }
+}
+
+pub struct Decoder {
+    table: DynamicTable,
+    // SETTINGS_HEADER_TABLE_SIZE で相手に伝えた上限
+    max_table_size: usize,
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Self::new(DEFAULT_TABLE_SIZE)
+    }
+}
+
+impl Decoder {
+    pub fn new(max_table_size: usize) -> Self {
+        Self {
+            table: DynamicTable::new(max_table_size),
+            max_table_size,
+        }
+    }
+
+    fn get(&self, index: usize) -> Result<(String, String)> {
+        if index == 0 {
+            bail!("invalid hpack index 0");
+        }
+        if let Some((n, v)) = STATIC_TABLE.get(index - 1) {
+            return Ok((n.to_string(), v.to_string()));
+        }
+        self.table
+            .entries
+            .get(index - STATIC_TABLE.len() - 1)
+            .cloned()
+            .ok_or_else(|| anyhow!("invalid hpack index {}", index))
+    }
+
+    pub fn decode(&mut self, buf: &[u8]) -> Result<Vec<(String, String)>> {
+        let mut headers = Vec::new();
+        let mut pos = 0;
+        while pos < buf.len() {
+            let b = buf[pos];
+            if b & 0x80 != 0 {
+                // Indexed Header Field
+                let index = decode_int(buf, &mut pos, 7)?;
+                headers.push(self.get(index)?);
+            } else if b & 0xc0 == 0x40 {
+                // Literal Header Field with Incremental Indexing
+                let (name, value) = self.decode_literal(buf, &mut pos, 6)?;
+                self.table.insert(name.clone(), value.clone());
+                headers.push((name, value));
+            } else if b & 0xe0 == 0x20 {
+                // Dynamic Table Size Update
+                let size = decode_int(buf, &mut pos, 5)?;
+                if size > self.max_table_size {
+                    bail!("hpack table size update exceeds the limit: {}", size);
+                }
+                self.table.set_max_size(size);
+            } else {
+                // Literal Header Field without Indexing / Never Indexed
+                headers.push(self.decode_literal(buf, &mut pos, 4)?);
+            }
+        }
+        Ok(headers)
+    }
+
+    fn decode_literal(&self, buf: &[u8], pos: &mut usize, prefix: u8) -> Result<(String, String)> {
+        let index = decode_int(buf, pos, prefix)?;
+        let name = match index {
+            0 => decode_string(buf, pos)?,
+            i => self.get(i)?.0,
+        };
+        let value = decode_string(buf, pos)?;
+        Ok((name, value))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        let s: String = s.split_whitespace().collect();
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn pairs(headers: &[(&str, &str)]) -> Vec<(String, String)> {
+        headers
+            .iter()
+            .map(|(n, v)| (n.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn integer() -> Result<()> {
+        // RFC 7541 C.1.2
+        let mut out = Vec::new();
+        encode_int(1337, 5, 0, &mut out);
+        assert_eq!(out, vec![0x1f, 0x9a, 0x0a]);
+        assert_eq!(decode_int(&out, &mut 0, 5)?, 1337);
+        Ok(())
+    }
+
+    #[test]
+    fn huffman() -> Result<()> {
+        let encoded = huffman_encode(b"www.example.com");
+        assert_eq!(encoded, hex("f1e3 c2e5 f23a 6ba0 ab90 f4ff"));
+        assert_eq!(huffman_decode(&encoded)?, b"www.example.com");
+
+        let all: Vec<u8> = (0..=255).collect();
+        assert_eq!(huffman_decode(&huffman_encode(&all))?, all);
+        Ok(())
+    }
+
+    #[test]
+    fn decode_requests_with_huffman() -> Result<()> {
+        // RFC 7541 C.4
+        let mut decoder = Decoder::default();
+        let got = decoder.decode(&hex("8286 8441 8cf1 e3c2 e5f2 3a6b a0ab 90f4 ff"))?;
+        assert_eq!(
+            got,
+            pairs(&[
+                (":method", "GET"),
+                (":scheme", "http"),
+                (":path", "/"),
+                (":authority", "www.example.com"),
+            ])
+        );
+
+        let got = decoder.decode(&hex("8286 84be 5886 a8eb 1064 9cbf"))?;
+        assert_eq!(got[3], (":authority".into(), "www.example.com".into()));
+        assert_eq!(got[4], ("cache-control".into(), "no-cache".into()));
+
+        let got = decoder.decode(&hex(
+            "8287 85bf 4088 25a8 49e9 5ba9 7d7f 8925 a849 e95b b8e8 b4bf",
+        ))?;
+        assert_eq!(
+            got,
+            pairs(&[
+                (":method", "GET"),
+                (":scheme", "https"),
+                (":path", "/index.html"),
+                (":authority", "www.example.com"),
+                ("custom-key", "custom-value"),
+            ])
+        );
+        assert_eq!(decoder.table.size, 164);
+        Ok(())
+    }
+
+    #[test]
+    fn encode_roundtrip() -> Result<()> {
+        let headers = [
+            (":method", "GET"),
+            (":path", "/hello?name=gorilla"),
+            ("authorization", "Bearer secret"),
+            ("x-gorilla", "banana"),
+        ];
+        let encoded = Encoder::new().encode(headers);
+        assert_eq!(encoded[0], 0x82);
+        assert_eq!(Decoder::default().decode(&encoded)?, pairs(&headers));
+        Ok(())
+    }
+}
@@ -0,0 +1,829 @@
+pub mod frame;
+pub mod hpack;
+mod huffman_table;
+
+use crate::client::{take_encodings, HttpClient, ReadWriter};
+use crate::codec::BodyKind;
+use crate::compression::{self, ACCEPT_ENCODING, DEFAULT_MAX_DECOMPRESSION_RATIO};
+use crate::header::HttpHeader;
+use crate::request::Request;
+use crate::response::Response;
+use crate::version::HttpVersion;
+use anyhow::{anyhow, bail, Result};
+use frame::*;
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{BufReader, Read};
+
+// NOTE: 受信側のウィンドウ。受け取ったぶんはすぐに WINDOW_UPDATE で戻すので、常にこの大きさになる
+const RECV_WINDOW: u32 = 1 << 20;
+
+// HTTP/2 では使えないコネクション固有のヘッダ (RFC 9113 8.2.2)
+const CONNECTION_HEADERS: [&str; 6] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "upgrade",
+    "host",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Http1,
+    Http2,
+}
+
+impl Protocol {
+    // TLS の ClientHello で提示する ALPN のプロトコル名 (優先順)
+    pub const ALPN: [&'static [u8]; 2] = [b"h2", b"http/1.1"];
+
+    // TLS ハンドシェイクで合意したプロトコルから選ぶ。ALPN が使われなければ HTTP/1.1
+    pub fn from_alpn(protocol: Option<&[u8]>) -> Result<Self> {
+        match protocol {
+            Some(b"h2") => Ok(Self::Http2),
+            Some(b"http/1.1") | None => Ok(Self::Http1),
+            Some(p) => bail!("unsupported ALPN protocol: {}", String::from_utf8_lossy(p)),
+        }
+    }
+}
+
+// 送信中のリクエストボディ
+struct Outgoing {
+    buf: Vec<u8>,
+    pos: usize,
+    reader: Option<Box<dyn Read + Send>>,
+}
+
+impl Outgoing {
+    fn is_empty(&self) -> bool {
+        self.pos == self.buf.len() && self.reader.is_none()
+    }
+
+    // 送れるデータを max バイトまで取り出す。reader が終わっていれば最後のデータかどうかも返す
+    fn next(&mut self, max: usize) -> Result<(Vec<u8>, bool)> {
+        if self.pos == self.buf.len() {
+            if let Some(reader) = &mut self.reader {
+                self.buf.resize(max, 0);
+                let n = reader.read(&mut self.buf)?;
+                self.buf.truncate(n);
+                self.pos = 0;
+                if n == 0 {
+                    self.reader = None;
+                }
+            }
+        }
+        let n = max.min(self.buf.len() - self.pos);
+        let data = self.buf[self.pos..self.pos + n].to_vec();
+        self.pos += n;
+        Ok((data, self.is_empty()))
+    }
+}
+
+struct Stream {
+    index: usize,
+    send_window: i64,
+    body: Option<Outgoing>,
+    status: Option<u32>,
+    header: HttpHeader,
+    data: Vec<u8>,
+}
+
+pub struct H2Client<T: ReadWriter> {
+    conn: BufReader<T>,
+    encoder: hpack::Encoder,
+    decoder: hpack::Decoder,
+    scheme: String,
+    decompress: bool,
+    max_decompression_ratio: Option<u64>,
+    next_stream_id: u32,
+    send_window: i64,
+    initial_window_size: u32,
+    max_frame_size: u32,
+    max_concurrent_streams: Option<u32>,
+    // GOAWAY で通知された、サーバーが処理する最後のストリームとエラーコード
+    goaway: Option<(u32, u32)>,
+}
+
+impl<T: ReadWriter> H2Client<T> {
+    // コネクションプリフェイスと SETTINGS を送る。平文なら事前知識 (prior knowledge) で h2c を使う
+    pub fn handshake(conn: T) -> Result<Self> {
+        let mut client = Self {
+            conn: BufReader::new(conn),
+            encoder: hpack::Encoder::new(),
+            decoder: hpack::Decoder::default(),
+            scheme: "http".into(),
+            decompress: true,
+            max_decompression_ratio: Some(DEFAULT_MAX_DECOMPRESSION_RATIO),
+            next_stream_id: 1,
+            send_window: DEFAULT_WINDOW_SIZE as i64,
+            initial_window_size: DEFAULT_WINDOW_SIZE,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            max_concurrent_streams: None,
+            goaway: None,
+        };
+
+        let w = client.conn.get_mut();
+        w.write_all(PREFACE)?;
+        write_frame(
+            w,
+            &Frame::Settings {
+                ack: false,
+                values: vec![
+                    (SETTINGS_ENABLE_PUSH, 0),
+                    (SETTINGS_INITIAL_WINDOW_SIZE, RECV_WINDOW),
+                ],
+            },
+        )?;
+        write_frame(
+            w,
+            &Frame::WindowUpdate {
+                stream_id: 0,
+                increment: RECV_WINDOW - DEFAULT_WINDOW_SIZE,
+            },
+        )?;
+        w.flush()?;
+        Ok(client)
+    }
+
+    // TLS の上で使う場合は "https" にする
+    pub fn scheme(&mut self, p: &str) -> &mut Self {
+        self.scheme = p.into();
+        self
+    }
+
+    pub fn decompress(&mut self, p: bool) -> &mut Self {
+        self.decompress = p;
+        self
+    }
+
+    pub fn max_decompression_ratio(&mut self, p: Option<u64>) -> &mut Self {
+        self.max_decompression_ratio = p;
+        self
+    }
+
+    pub fn execute_request(&mut self, req: &Request) -> Result<Response> {
+        self.execute_requests(std::slice::from_ref(req))?
+            .pop()
+            .unwrap_or_else(|| Err(anyhow!("no response")))
+    }
+
+    // requests をそれぞれ別のストリームで同時に送り、requests と同じ順番で結果を返す。
+    // RST_STREAM や GOAWAY で処理されなかったリクエストは、そのリクエストの結果だけがエラーになる
+    pub fn execute_requests(&mut self, requests: &[Request]) -> Result<Vec<Result<Response>>> {
+        let mut results: Vec<Option<Result<Response>>> = requests.iter().map(|_| None).collect();
+        let mut queue: VecDeque<usize> = (0..requests.len()).collect();
+        let mut streams: BTreeMap<u32, Stream> = BTreeMap::new();
+        // ヘッダブロックは 1 つずつしか送られないので、CONTINUATION を待っているものを 1 つだけ持つ
+        let mut continuing: Option<(u32, bool)> = None;
+        let mut header_block = Vec::new();
+
+        loop {
+            while let Some(&index) = queue.front() {
+                if self.goaway.is_some() {
+                    break;
+                }
+                let limit = self.max_concurrent_streams.unwrap_or(u32::MAX) as usize;
+                if streams.len() >= limit {
+                    break;
+                }
+                queue.pop_front();
+                match self.open_stream(&requests[index], index) {
+                    Ok((id, stream)) => {
+                        streams.insert(id, stream);
+                    }
+                    Err(e) => results[index] = Some(Err(e)),
+                }
+            }
+            self.send_data(&mut streams)?;
+            self.conn.get_mut().flush()?;
+
+            if let Some((last, error_code)) = self.goaway {
+                // GOAWAY より後のストリームと、まだ開いていないリクエストは処理されない
+                let refused: Vec<u32> = streams.keys().copied().filter(|id| *id > last).collect();
+                for id in refused {
+                    let stream = streams.remove(&id).unwrap();
+                    results[stream.index] = Some(Err(not_processed(error_code)));
+                }
+                for index in queue.drain(..) {
+                    results[index] = Some(Err(not_processed(error_code)));
+                }
+            }
+            if streams.is_empty() && queue.is_empty() {
+                break;
+            }
+
+            let frame = match read_frame(&mut self.conn, DEFAULT_MAX_FRAME_SIZE) {
+                Ok(frame) => frame,
+                Err(e) if self.goaway.is_some() => {
+                    for (_, stream) in std::mem::take(&mut streams) {
+                        results[stream.index] =
+                            Some(Err(anyhow!("connection closed after GOAWAY: {}", e)));
+                    }
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+
+            if let Some((id, _)) = continuing {
+                if !matches!(frame, Frame::Continuation { stream_id, .. } if stream_id == id) {
+                    bail!("expected CONTINUATION frame for stream {}", id);
+                }
+            }
+
+            match frame {
+                Frame::Settings { ack: false, values } => {
+                    self.apply_settings(&values, &mut streams)?
+                }
+                Frame::Settings { ack: true, .. } => {}
+                Frame::Ping { ack: false, data } => {
+                    write_frame(self.conn.get_mut(), &Frame::Ping { ack: true, data })?
+                }
+                Frame::Ping { ack: true, .. } => {}
+                Frame::WindowUpdate {
+                    stream_id,
+                    increment,
+                } => {
+                    if stream_id == 0 {
+                        if increment == 0 {
+                            self.go_away(PROTOCOL_ERROR)?;
+                            bail!("received WINDOW_UPDATE with zero increment");
+                        }
+                        self.send_window += increment as i64;
+                        if self.send_window > (1 << 31) - 1 {
+                            self.go_away(FLOW_CONTROL_ERROR)?;
+                            bail!("connection flow control window overflow");
+                        }
+                    } else if let Some(stream) = streams.get_mut(&stream_id) {
+                        stream.send_window += increment as i64;
+                        if increment == 0 || stream.send_window > (1 << 31) - 1 {
+                            let code = match increment {
+                                0 => PROTOCOL_ERROR,
+                                _ => FLOW_CONTROL_ERROR,
+                            };
+                            self.reset(stream_id, code)?;
+                            let stream = streams.remove(&stream_id).unwrap();
+                            results[stream.index] = Some(Err(anyhow!(
+                                "invalid WINDOW_UPDATE on stream {}",
+                                stream_id
+                            )));
+                        }
+                    }
+                }
+                Frame::Headers {
+                    stream_id,
+                    block,
+                    end_stream,
+                    end_headers,
+                } => {
+                    if end_headers {
+                        self.finish_headers(
+                            stream_id,
+                            &block,
+                            end_stream,
+                            &mut streams,
+                            &mut results,
+                        )?;
+                    } else {
+                        header_block = block;
+                        continuing = Some((stream_id, end_stream));
+                    }
+                }
+                Frame::Continuation {
+                    stream_id,
+                    block,
+                    end_headers,
+                } => {
+                    header_block.extend(block);
+                    if end_headers {
+                        let end_stream = continuing.take().map(|(_, end)| end).unwrap_or(false);
+                        let block = std::mem::take(&mut header_block);
+                        self.finish_headers(
+                            stream_id,
+                            &block,
+                            end_stream,
+                            &mut streams,
+                            &mut results,
+                        )?;
+                    }
+                }
+                Frame::Data {
+                    stream_id,
+                    data,
+                    end_stream,
+                    flow_len,
+                } => {
+                    if flow_len > 0 {
+                        let w = self.conn.get_mut();
+                        write_frame(
+                            w,
+                            &Frame::WindowUpdate {
+                                stream_id: 0,
+                                increment: flow_len,
+                            },
+                        )?;
+                        if !end_stream && streams.contains_key(&stream_id) {
+                            write_frame(
+                                w,
+                                &Frame::WindowUpdate {
+                                    stream_id,
+                                    increment: flow_len,
+                                },
+                            )?;
+                        }
+                    }
+                    if let Some(stream) = streams.get_mut(&stream_id) {
+                        if stream.status.is_none() {
+                            self.reset(stream_id, PROTOCOL_ERROR)?;
+                            let stream = streams.remove(&stream_id).unwrap();
+                            results[stream.index] =
+                                Some(Err(anyhow!("received DATA before HEADERS")));
+                            continue;
+                        }
+                        stream.data.extend(data);
+                        if end_stream {
+                            let stream = streams.remove(&stream_id).unwrap();
+                            let index = stream.index;
+                            results[index] = Some(self.make_response(stream));
+                        }
+                    }
+                }
+                Frame::RstStream {
+                    stream_id,
+                    error_code,
+                } => {
+                    if let Some(stream) = streams.remove(&stream_id) {
+                        results[stream.index] = Some(Err(anyhow!(
+                            "stream {} was reset by the server: {}",
+                            stream_id,
+                            error_name(error_code)
+                        )));
+                    }
+                }
+                Frame::GoAway {
+                    last_stream_id,
+                    error_code,
+                    ..
+                } => self.goaway = Some((last_stream_id, error_code)),
+                Frame::PushPromise { .. } => {
+                    self.go_away(PROTOCOL_ERROR)?;
+                    bail!("received PUSH_PROMISE although push is disabled");
+                }
+                Frame::Ignored => {}
+            }
+        }
+
+        Ok(results
+            .into_iter()
+            .map(|r| r.unwrap_or_else(|| Err(anyhow!("no response"))))
+            .collect())
+    }
+
+    fn open_stream(&mut self, req: &Request, index: usize) -> Result<(u32, Stream)> {
+        let mut extra = HttpHeader::new();
+        if self.decompress {
+            extra.add("accept-encoding", ACCEPT_ENCODING);
+        }
+        req.check()?;
+        let (header, body) = req.message_parts(&extra)?;
+        let stream = req.take_stream()?;
+
+        let method = req.method.to_string();
+        let authority = req.authority();
+        let path = req.target();
+        let mut fields: Vec<(String, String)> = vec![
+            (":method".into(), method),
+            (":scheme".into(), self.scheme.clone()),
+            (":authority".into(), authority),
+            (":path".into(), path),
+        ];
+        for (k, v) in header.iter() {
+            let k = k.to_lowercase();
+            if !CONNECTION_HEADERS.contains(&k.as_str()) {
+                fields.push((k, v.clone()));
+            }
+        }
+        let block = self
+            .encoder
+            .encode(fields.iter().map(|(k, v)| (k.as_str(), v.as_str())));
+
+        let id = self.next_stream_id;
+        self.next_stream_id += 2;
+
+        let body = match (body, stream) {
+            (_, Some(stream)) => Some(Outgoing {
+                buf: Vec::new(),
+                pos: 0,
+                reader: Some(stream.reader),
+            }),
+            (Some(data), None) if !data.is_empty() => Some(Outgoing {
+                buf: data,
+                pos: 0,
+                reader: None,
+            }),
+            _ => None,
+        };
+
+        // ヘッダブロックが大きければ CONTINUATION に分ける
+        let max = self.max_frame_size as usize;
+        let mut chunks = block.chunks(max).peekable();
+        let first = chunks.next().unwrap_or_default().to_vec();
+        let w = self.conn.get_mut();
+        write_frame(
+            w,
+            &Frame::Headers {
+                stream_id: id,
+                block: first,
+                end_stream: body.is_none(),
+                end_headers: chunks.peek().is_none(),
+            },
+        )?;
+        while let Some(chunk) = chunks.next() {
+            write_frame(
+                w,
+                &Frame::Continuation {
+                    stream_id: id,
+                    block: chunk.to_vec(),
+                    end_headers: chunks.peek().is_none(),
+                },
+            )?;
+        }
+
+        Ok((
+            id,
+            Stream {
+                index,
+                send_window: self.initial_window_size as i64,
+                body,
+                status: None,
+                header: HttpHeader::new(),
+                data: Vec::new(),
+            },
+        ))
+    }
+
+    // フロー制御のウィンドウが許す範囲でリクエストボディを送る
+    fn send_data(&mut self, streams: &mut BTreeMap<u32, Stream>) -> Result<()> {
+        for (id, stream) in streams.iter_mut() {
+            while let Some(body) = &mut stream.body {
+                let window = self.send_window.min(stream.send_window);
+                if window <= 0 {
+                    break;
+                }
+                let max = (window as usize).min(self.max_frame_size as usize);
+                let (data, end_stream) = body.next(max)?;
+                if data.is_empty() && !end_stream {
+                    continue;
+                }
+                self.send_window -= data.len() as i64;
+                stream.send_window -= data.len() as i64;
+                write_frame(
+                    self.conn.get_mut(),
+                    &Frame::Data {
+                        stream_id: *id,
+                        flow_len: data.len() as u32,
+                        data,
+                        end_stream,
+                    },
+                )?;
+                if end_stream {
+                    stream.body = None;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn apply_settings(
+        &mut self,
+        values: &[(u16, u32)],
+        streams: &mut BTreeMap<u32, Stream>,
+    ) -> Result<()> {
+        for (id, value) in values {
+            match *id {
+                SETTINGS_INITIAL_WINDOW_SIZE => {
+                    if *value > (1 << 31) - 1 {
+                        self.go_away(FLOW_CONTROL_ERROR)?;
+                        bail!("invalid SETTINGS_INITIAL_WINDOW_SIZE: {}", value);
+                    }
+                    // 開いているストリームのウィンドウも差分だけ変える
+                    let delta = *value as i64 - self.initial_window_size as i64;
+                    for stream in streams.values_mut() {
+                        stream.send_window += delta;
+                    }
+                    self.initial_window_size = *value;
+                }
+                SETTINGS_MAX_FRAME_SIZE => {
+                    if !(DEFAULT_MAX_FRAME_SIZE..=(1 << 24) - 1).contains(value) {
+                        self.go_away(PROTOCOL_ERROR)?;
+                        bail!("invalid SETTINGS_MAX_FRAME_SIZE: {}", value);
+                    }
+                    self.max_frame_size = *value;
+                }
+                SETTINGS_MAX_CONCURRENT_STREAMS => self.max_concurrent_streams = Some(*value),
+                // NOTE: エンコーダは動的テーブルを使わないので HEADER_TABLE_SIZE は気にしなくてよい
+                _ => {}
+            }
+        }
+        write_frame(
+            self.conn.get_mut(),
+            &Frame::Settings {
+                ack: true,
+                values: vec![],
+            },
+        )
+    }
+
+    fn finish_headers(
+        &mut self,
+        stream_id: u32,
+        block: &[u8],
+        end_stream: bool,
+        streams: &mut BTreeMap<u32, Stream>,
+        results: &mut [Option<Result<Response>>],
+    ) -> Result<()> {
+        // NOTE: 動的テーブルの状態を揃えるため、知らないストリームのヘッダもデコードする
+        let fields = self.decoder.decode(block)?;
+        let stream = match streams.get_mut(&stream_id) {
+            Some(stream) => stream,
+            None => return Ok(()),
+        };
+
+        match stream.status {
+            None => {
+                let status = fields
+                    .iter()
+                    .find(|(k, _)| k == ":status")
+                    .and_then(|(_, v)| v.parse::<u32>().ok());
+                let status = match status {
+                    Some(status) => status,
+                    None => {
+                        self.reset(stream_id, PROTOCOL_ERROR)?;
+                        let stream = streams.remove(&stream_id).unwrap();
+                        results[stream.index] = Some(Err(anyhow!("missing :status in response")));
+                        return Ok(());
+                    }
+                };
+                // 1xx は読み捨てて、最終的なレスポンスを待つ
+                if (100..200).contains(&status) {
+                    return Ok(());
+                }
+                stream.status = Some(status);
+                for (k, v) in fields.iter().filter(|(k, _)| !k.starts_with(':')) {
+                    stream.header.add(k, v);
+                }
+            }
+            // トレイラー
+            Some(_) => {
+                for (k, v) in &fields {
+                    stream.header.add(k, v);
+                }
+            }
+        }
+
+        if end_stream {
+            let stream = streams.remove(&stream_id).unwrap();
+            let index = stream.index;
+            results[index] = Some(self.make_response(stream));
+        }
+        Ok(())
+    }
+
+    fn make_response(&self, stream: Stream) -> Result<Response> {
+        let status = stream
+            .status
+            .ok_or_else(|| anyhow!("missing response headers"))?;
+        let mut header = stream.header;
+        let mut data = stream.data;
+
+        let kind = BodyKind::Length(data.len() as u64);
+        let encodings = take_encodings(self.decompress, &kind, &mut header);
+        if !encodings.is_empty() {
+            data = compression::decode(&data, &encodings, self.max_decompression_ratio)?;
+        }
+        if header.get("content-length").is_none() && !data.is_empty() {
+            header.add("content-length", data.len().to_string().as_str());
+        }
+
+        let body = match data.is_empty() {
+            true => None,
+            false => Some(crate::body::Body::with_header(data, &header)),
+        };
+        Ok(Response {
+            version: HttpVersion::Http2,
+            status,
+            header,
+            body,
+        })
+    }
+
+    fn reset(&mut self, stream_id: u32, error_code: u32) -> Result<()> {
+        write_frame(
+            self.conn.get_mut(),
+            &Frame::RstStream {
+                stream_id,
+                error_code,
+            },
+        )
+    }
+
+    fn go_away(&mut self, error_code: u32) -> Result<()> {
+        let w = self.conn.get_mut();
+        write_frame(
+            w,
+            &Frame::GoAway {
+                last_stream_id: 0,
+                error_code,
+                debug: vec![],
+            },
+        )?;
+        w.flush()?;
+        Ok(())
+    }
+}
+
+fn not_processed(error_code: u32) -> anyhow::Error {
+    anyhow!(
+        "request was not processed by the server (GOAWAY {}) and can be retried",
+        error_name(error_code)
+    )
+}
+
+// ALPN の結果で HTTP/1.1 と HTTP/2 を切り替える
+pub enum NegotiatedClient<T: ReadWriter> {
+    Http1(HttpClient<T>),
+    Http2(H2Client<T>),
+}
+
+impl<T: ReadWriter> NegotiatedClient<T> {
+    pub fn new(conn: T, protocol: Protocol) -> Result<Self> {
+        Ok(match protocol {
+            Protocol::Http1 => Self::Http1(HttpClient::new(conn)),
+            Protocol::Http2 => Self::Http2(H2Client::handshake(conn)?),
+        })
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        match self {
+            Self::Http1(_) => Protocol::Http1,
+            Self::Http2(_) => Protocol::Http2,
+        }
+    }
+
+    pub fn execute_request(&mut self, req: &Request) -> Result<Response> {
+        match self {
+            Self::Http1(client) => client.execute_request(req),
+            Self::Http2(client) => client.execute_request(req),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    use warp::Filter;
+
+    // h2c (prior knowledge) を受け付ける warp のサーバーを立てる
+    fn server(rt: &tokio::runtime::Runtime) -> SocketAddr {
+        let hello = warp::path!("hello" / String).map(|name| format!("hello {}", name));
+        let echo = warp::post()
+            .and(warp::path("echo"))
+            .and(warp::body::bytes())
+            .map(|body: bytes::Bytes| body.to_vec());
+        let big = warp::path("big").map(|| "gorilla".repeat(100_000));
+
+        let _guard = rt.enter();
+        let (addr, server) =
+            warp::serve(hello.or(echo).or(big)).bind_ephemeral(([127, 0, 0, 1], 0));
+        rt.spawn(server);
+        addr
+    }
+
+    #[test]
+    fn multiplexed_requests() -> Result<()> {
+        let rt = tokio::runtime::Runtime::new()?;
+        let addr = server(&rt);
+
+        let mut client = H2Client::handshake(TcpStream::connect(addr)?)?;
+        let requests: Vec<Request> = ["gorilla", "monkey", "chimpanzee"]
+            .iter()
+            .map(|name| Request::get(&format!("/hello/{}", name)))
+            .chain([Request::get("/big")])
+            .collect();
+        let results = client.execute_requests(&requests)?;
+
+        let bodies: Vec<String> = results
+            .into_iter()
+            .map(|r| r.unwrap().body.unwrap().text().unwrap())
+            .collect();
+        assert_eq!(bodies[0], "hello gorilla");
+        assert_eq!(bodies[2], "hello chimpanzee");
+        // 受信側のフロー制御で止まらずに最後まで読めること
+        assert_eq!(bodies[3].len(), 700_000);
+
+        // 同じコネクションで続けて送れること
+        let resp = client.execute_request(&Request::get("/hello/again"))?;
+        assert_eq!(resp.status, 200);
+        assert_eq!(resp.body.unwrap().text()?, "hello again");
+        Ok(())
+    }
+
+    #[test]
+    fn send_large_body() -> Result<()> {
+        let rt = tokio::runtime::Runtime::new()?;
+        let addr = server(&rt);
+        let mut client = H2Client::handshake(TcpStream::connect(addr)?)?;
+
+        // 相手の初期ウィンドウ (65535) より大きいボディは WINDOW_UPDATE を待って送る
+        let data = vec![b'x'; 300_000];
+        let mut req = Request::new("/echo".into());
+        req.method(crate::method::HttpMethod::Post)
+            .body(data.clone());
+        let resp = client.execute_request(&req)?;
+        assert_eq!(resp.body.unwrap().raw(), data);
+
+        let mut req = Request::new("/echo".into());
+        req.method(crate::method::HttpMethod::Post)
+            .body_reader(std::io::Cursor::new(data.clone()), None);
+        let resp = client.execute_request(&req)?;
+        assert_eq!(resp.body.unwrap().raw(), data);
+        Ok(())
+    }
+
+    #[test]
+    fn reset_and_goaway() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        std::thread::spawn(move || -> Result<()> {
+            let (mut conn, _) = listener.accept()?;
+            let mut preface = [0u8; 24];
+            conn.read_exact(&mut preface)?;
+            assert_eq!(preface, PREFACE);
+
+            let mut streams = 0;
+            while streams < 3 {
+                if let Frame::Headers { .. } = read_frame(&mut conn, DEFAULT_MAX_FRAME_SIZE)? {
+                    streams += 1;
+                }
+            }
+
+            let block = hpack::Encoder::new().encode([(":status", "200")]);
+            let frames = [
+                Frame::Settings {
+                    ack: false,
+                    values: vec![],
+                },
+                Frame::Headers {
+                    stream_id: 1,
+                    block,
+                    end_stream: false,
+                    end_headers: true,
+                },
+                Frame::Data {
+                    stream_id: 1,
+                    data: b"ok".to_vec(),
+                    end_stream: true,
+                    flow_len: 2,
+                },
+                Frame::RstStream {
+                    stream_id: 3,
+                    error_code: 0x7,
+                },
+                Frame::GoAway {
+                    last_stream_id: 3,
+                    error_code: 0,
+                    debug: vec![],
+                },
+            ];
+            for f in &frames {
+                write_frame(&mut conn, f)?;
+            }
+            // クライアントが閉じるまで読み捨てる
+            std::io::copy(&mut conn, &mut std::io::sink())?;
+            Ok(())
+        });
+
+        let mut client = H2Client::handshake(TcpStream::connect(addr)?)?;
+        let requests = [Request::get("/1"), Request::get("/2"), Request::get("/3")];
+        let results = client.execute_requests(&requests)?;
+
+        assert_eq!(
+            results[0].as_ref().unwrap().body.as_ref().unwrap().text()?,
+            "ok"
+        );
+        let err = results[1].as_ref().unwrap_err().to_string();
+        assert_eq!(err, "stream 3 was reset by the server: REFUSED_STREAM");
+        let err = results[2].as_ref().unwrap_err().to_string();
+        assert!(err.contains("can be retried"), "{}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn select_by_alpn() -> Result<()> {
+        assert_eq!(Protocol::from_alpn(Some(b"h2"))?, Protocol::Http2);
+        assert_eq!(Protocol::from_alpn(Some(b"http/1.1"))?, Protocol::Http1);
+        assert_eq!(Protocol::from_alpn(None)?, Protocol::Http1);
+        assert!(Protocol::from_alpn(Some(b"spdy/3")).is_err());
+        Ok(())
+    }
+}
@@ -2,7 +2,7 @@ use std::collections::BTreeMap;
 use std::fmt::Display;
 use std::iter::FromIterator;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct HttpHeader(BTreeMap<String, String>);
 
 impl Display for HttpHeader {
@@ -25,9 +25,22 @@ impl HttpHeader {
     pub fn get(&self, key: &str) -> Option<&String> {
         self.0.get(key)
     }
+    // リクエストヘッダはユーザーが好きな大文字小文字で追加するので、それを探すときに使う
+    pub fn get_ignore_case(&self, key: &str) -> Option<&String> {
+        self.0
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v)
+    }
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &String)> {
+        self.0.iter()
+    }
     pub fn remove(&mut self, key: &str) {
         self.0.remove(key);
     }
+    pub fn remove_ignore_case(&mut self, key: &str) {
+        self.0.retain(|k, _| !k.eq_ignore_ascii_case(key));
+    }
 }
 
 impl Default for HttpHeader {
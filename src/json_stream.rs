@@ -0,0 +1,390 @@
+use crate::body::BodyReader;
+use crate::response::StreamResponse;
+use anyhow::{anyhow, bail, Result};
+use serde::de::DeserializeOwned;
+use std::io::BufRead;
+use std::marker::PhantomData;
+
+// NOTE: 1 行や配列の 1 要素をすべてメモリに読むので、これより大きければエラーにする
+const DEFAULT_MAX_VALUE_SIZE: usize = 16 * 1024 * 1024;
+
+// application/x-ndjson (JSON Lines) を 1 行ずつ T にデコードする。空行は読み飛ばす
+pub struct JsonLines<R, T> {
+    inner: R,
+    line: Vec<u8>,
+    line_number: usize,
+    max_line_size: usize,
+    done: bool,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<R: BufRead, T: DeserializeOwned> JsonLines<R, T> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            line: Vec::new(),
+            line_number: 0,
+            max_line_size: DEFAULT_MAX_VALUE_SIZE,
+            done: false,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn max_line_size(&mut self, size: usize) -> &mut Self {
+        self.max_line_size = size;
+        self
+    }
+
+    // LF まで読む。ストリームが終わっていれば false を返す
+    fn read_line(&mut self) -> Result<bool> {
+        self.line.clear();
+        loop {
+            let buf = self.inner.fill_buf()?;
+            if buf.is_empty() {
+                return Ok(!self.line.is_empty());
+            }
+            let (n, found) = match buf.iter().position(|&b| b == b'\n') {
+                Some(i) => (i, true),
+                None => (buf.len(), false),
+            };
+            if self.line.len() + n > self.max_line_size {
+                bail!(
+                    "line {} exceeds {} bytes",
+                    self.line_number + 1,
+                    self.max_line_size
+                );
+            }
+            self.line.extend_from_slice(&buf[..n]);
+            self.inner.consume(if found { n + 1 } else { n });
+            if found {
+                return Ok(true);
+            }
+        }
+    }
+
+    pub fn next_value(&mut self) -> Result<Option<T>> {
+        while !self.done {
+            let read = self.read_line();
+            if !matches!(read, Ok(true)) {
+                // NOTE: 読み込みのエラーや長すぎる行のあとは続きを読めないので終わりにする
+                self.done = true;
+                return read.map(|_| None);
+            }
+            self.line_number += 1;
+            if self.line.iter().all(|b| b.is_ascii_whitespace()) {
+                continue;
+            }
+            // NOTE: デコードに失敗しても次の行からは読めるので、終わりにはしない
+            return serde_json::from_slice(&self.line)
+                .map(Some)
+                .map_err(|e| anyhow!("line {}: {}", self.line_number, e));
+        }
+        Ok(None)
+    }
+}
+
+impl<'a, T: DeserializeOwned> JsonLines<BodyReader<'a>, T> {
+    pub fn from_response(resp: StreamResponse<'a>) -> Self {
+        Self::new(resp.body)
+    }
+}
+
+impl<R: BufRead, T: DeserializeOwned> Iterator for JsonLines<R, T> {
+    type Item = Result<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_value().transpose()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ArrayState {
+    // `[` の前
+    Start,
+    // `[` の直後。`]` か要素が来る
+    First,
+    // `,` の直後。要素が来る
+    Value,
+    // 要素の直後。`,` か `]` が来る
+    Next,
+    // `]` の後
+    End,
+    Done,
+}
+
+// トップレベルの JSON 配列を要素ごとに T にデコードする。
+// 配列全体ではなく 1 要素ずつメモリに読むので、大きな配列でも使える
+pub struct JsonArray<R, T> {
+    inner: R,
+    element: Vec<u8>,
+    state: ArrayState,
+    // これまでに読んだバイト数。エラーの位置に使う
+    offset: usize,
+    index: usize,
+    max_element_size: usize,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<R: BufRead, T: DeserializeOwned> JsonArray<R, T> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            element: Vec::new(),
+            state: ArrayState::Start,
+            offset: 0,
+            index: 0,
+            max_element_size: DEFAULT_MAX_VALUE_SIZE,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn max_element_size(&mut self, size: usize) -> &mut Self {
+        self.max_element_size = size;
+        self
+    }
+
+    // 空白を読み飛ばして次のバイトを返す。そのバイトは consume しない
+    fn peek(&mut self) -> Result<Option<u8>> {
+        loop {
+            let buf = self.inner.fill_buf()?;
+            if buf.is_empty() {
+                return Ok(None);
+            }
+            match buf.iter().position(|b| !b.is_ascii_whitespace()) {
+                Some(i) => {
+                    let b = buf[i];
+                    self.inner.consume(i);
+                    self.offset += i;
+                    return Ok(Some(b));
+                }
+                None => {
+                    let n = buf.len();
+                    self.inner.consume(n);
+                    self.offset += n;
+                }
+            }
+        }
+    }
+
+    fn consume_byte(&mut self) {
+        self.inner.consume(1);
+        self.offset += 1;
+    }
+
+    // トップレベルの `,` か `]` の手前までを 1 要素として読む。
+    // NOTE: 要素が正しい JSON かどうかは serde_json に任せ、ここでは括弧と文字列だけを追う
+    fn read_element(&mut self) -> Result<()> {
+        self.element.clear();
+        let mut depth = 0usize;
+        let mut in_string = false;
+        let mut escaped = false;
+        loop {
+            let buf = self.inner.fill_buf()?;
+            if buf.is_empty() {
+                bail!("unexpected end of JSON array at byte {}", self.offset);
+            }
+            let mut end = None;
+            for (i, &b) in buf.iter().enumerate() {
+                if in_string {
+                    match b {
+                        _ if escaped => escaped = false,
+                        b'\\' => escaped = true,
+                        b'"' => in_string = false,
+                        _ => {}
+                    }
+                    continue;
+                }
+                match b {
+                    b'"' => in_string = true,
+                    b'{' | b'[' => depth += 1,
+                    b',' | b']' if depth == 0 => {
+                        end = Some(i);
+                        break;
+                    }
+                    b'}' | b']' => depth = depth.saturating_sub(1),
+                    _ => {}
+                }
+            }
+            let n = end.unwrap_or(buf.len());
+            if self.element.len() + n > self.max_element_size {
+                bail!(
+                    "JSON array element {} exceeds {} bytes",
+                    self.index,
+                    self.max_element_size
+                );
+            }
+            self.element.extend_from_slice(&buf[..n]);
+            self.inner.consume(n);
+            self.offset += n;
+            if end.is_some() {
+                return Ok(());
+            }
+        }
+    }
+
+    fn next_element(&mut self) -> Result<Option<T>> {
+        loop {
+            // NOTE: 終わったあとに peek するとコネクションを待ってしまうので先に調べる
+            if self.state == ArrayState::Done {
+                return Ok(None);
+            }
+            let next = self.peek()?;
+            match (self.state, next) {
+                (ArrayState::Done, _) | (ArrayState::End, None) => {
+                    self.state = ArrayState::Done;
+                    return Ok(None);
+                }
+                (ArrayState::End, Some(_)) => {
+                    bail!(
+                        "trailing characters after JSON array at byte {}",
+                        self.offset
+                    )
+                }
+                (_, None) => bail!("unexpected end of JSON array at byte {}", self.offset),
+                (ArrayState::Start, Some(b'[')) => {
+                    self.consume_byte();
+                    self.state = ArrayState::First;
+                }
+                (ArrayState::Start, Some(_)) => {
+                    bail!("expected '[' at byte {}", self.offset)
+                }
+                (ArrayState::First | ArrayState::Next, Some(b']')) => {
+                    self.consume_byte();
+                    self.state = ArrayState::End;
+                }
+                (ArrayState::Next, Some(b',')) => {
+                    self.consume_byte();
+                    self.state = ArrayState::Value;
+                }
+                (ArrayState::Next, Some(_)) => {
+                    bail!("expected ',' or ']' at byte {}", self.offset)
+                }
+                (ArrayState::First | ArrayState::Value, Some(_)) => {
+                    let start = self.offset;
+                    self.read_element()?;
+                    self.state = ArrayState::Next;
+                    let index = self.index;
+                    self.index += 1;
+                    // NOTE: 要素の区切りはわかっているので、デコードに失敗しても次の要素は読める
+                    return serde_json::from_slice(&self.element)
+                        .map(Some)
+                        .map_err(|e| {
+                            anyhow!("JSON array element {} at byte {}: {}", index, start, e)
+                        });
+                }
+            }
+        }
+    }
+}
+
+impl<'a, T: DeserializeOwned> JsonArray<BodyReader<'a>, T> {
+    pub fn from_response(resp: StreamResponse<'a>) -> Self {
+        Self::new(resp.body)
+    }
+}
+
+impl<R: BufRead, T: DeserializeOwned> Iterator for JsonArray<R, T> {
+    type Item = Result<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let element = self.next_element();
+        if element.is_err() && self.state != ArrayState::Next {
+            // NOTE: 区切りが壊れていたら続きは読めないので終わりにする
+            self.state = ArrayState::Done;
+        }
+        element.transpose()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde::Deserialize;
+    use std::io::BufReader;
+
+    #[derive(Debug, Deserialize, PartialEq, Eq)]
+    struct Log {
+        level: String,
+        msg: String,
+    }
+
+    fn log(level: &str, msg: &str) -> Log {
+        Log {
+            level: level.into(),
+            msg: msg.into(),
+        }
+    }
+
+    #[test]
+    fn json_lines() -> Result<()> {
+        let data = "{\"level\":\"info\",\"msg\":\"a\"}\r\n\n  \n{\"level\":1}\n{\"level\":\"warn\",\"msg\":\"b\"}";
+        // NOTE: 容量を小さくして、行が複数回に分かれて読まれる場合も確かめる
+        let mut lines = JsonLines::<_, Log>::new(BufReader::with_capacity(4, data.as_bytes()));
+        assert_eq!(lines.next().unwrap()?, log("info", "a"));
+        let err = lines.next().unwrap().unwrap_err();
+        assert!(err.to_string().starts_with("line 4: "), "{}", err);
+        assert_eq!(lines.next().unwrap()?, log("warn", "b"));
+        assert!(lines.next().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn json_lines_too_long() {
+        let data = "[1]\n[1,2,3,4]\n[5]\n";
+        let mut lines = JsonLines::<_, Vec<u32>>::new(data.as_bytes());
+        lines.max_line_size(5);
+        assert_eq!(lines.next().unwrap().unwrap(), vec![1]);
+        let err = lines.next().unwrap().unwrap_err();
+        assert_eq!(err.to_string(), "line 2 exceeds 5 bytes");
+        assert!(lines.next().is_none());
+    }
+
+    #[test]
+    fn json_array() -> Result<()> {
+        let data = r#" [ {"level":"info","msg":"a, ] \" {"},
+            {"level":"warn","msg":"[b]"} , {"level":[]} ,{"level":"x","msg":"c"}] "#;
+        let mut array = JsonArray::<_, Log>::new(BufReader::with_capacity(3, data.as_bytes()));
+        assert_eq!(array.next().unwrap()?, log("info", "a, ] \" {"));
+        assert_eq!(array.next().unwrap()?, log("warn", "[b]"));
+        let err = array.next().unwrap().unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("JSON array element 2 at byte 82: "),
+            "{}",
+            err
+        );
+        assert_eq!(array.next().unwrap()?, log("x", "c"));
+        assert!(array.next().is_none());
+
+        let empty: Vec<u32> = JsonArray::new(&b"[]"[..]).collect::<Result<_>>()?;
+        assert!(empty.is_empty());
+        let scalars: Vec<u32> = JsonArray::new(&b"[1,2 , 3]"[..]).collect::<Result<_>>()?;
+        assert_eq!(scalars, vec![1, 2, 3]);
+        Ok(())
+    }
+
+    #[test]
+    fn json_array_invalid() {
+        let errors = |data: &'static str| -> Vec<String> {
+            JsonArray::<_, u32>::new(data.as_bytes())
+                .filter_map(|x| x.err().map(|e| e.to_string()))
+                .collect()
+        };
+        assert_eq!(errors("{}"), vec!["expected '[' at byte 0"]);
+        assert!(errors("[1 2]")[0].starts_with("JSON array element 0 at byte 1: "));
+        assert_eq!(
+            errors("[1,"),
+            vec!["unexpected end of JSON array at byte 3"]
+        );
+        assert_eq!(
+            errors("[1] [2]"),
+            vec!["trailing characters after JSON array at byte 4"]
+        );
+
+        let mut array = JsonArray::<_, Vec<u32>>::new(&b"[[1,2,3,4,5],[6]]"[..]);
+        array.max_element_size(5);
+        let err = array.next().unwrap().unwrap_err();
+        assert_eq!(err.to_string(), "JSON array element 0 exceeds 5 bytes");
+        assert!(array.next().is_none());
+    }
+}
@@ -1,7 +1,25 @@
+pub mod async_client;
 pub mod body;
 pub mod client;
+pub mod codec;
+pub mod compression;
+pub mod connector;
+pub mod docker;
+pub mod form;
+pub mod format;
+pub mod h2;
 pub mod header;
+pub mod json_stream;
 pub mod method;
+pub mod multipart;
 pub mod params;
+pub mod query;
 pub mod request;
+pub mod resolve;
 pub mod response;
+pub mod sse;
+#[cfg(unix)]
+pub mod unix;
+pub mod upgrade;
+pub mod version;
+pub mod websocket;
@@ -1,7 +1,8 @@
 use std::fmt::Display;
 
-#[derive(Eq, PartialEq)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Default)]
 pub enum HttpMethod {
+    #[default]
     Get,
     Post,
     Put,
@@ -11,12 +12,6 @@ pub enum HttpMethod {
     Options,
 }
 
-impl Default for HttpMethod {
-    fn default() -> Self {
-        Self::Get
-    }
-}
-
 impl Display for HttpMethod {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let method = match self {
@@ -31,3 +26,13 @@ impl Display for HttpMethod {
         write!(f, "{}", method)
     }
 }
+
+impl HttpMethod {
+    // 同じリクエストを何度送っても結果が変わらないメソッド (RFC 9110 9.2.2)
+    pub fn is_idempotent(&self) -> bool {
+        matches!(
+            self,
+            Self::Get | Self::Head | Self::Options | Self::Put | Self::Delete
+        )
+    }
+}
@@ -0,0 +1,621 @@
+use crate::body::{Body, BodyReader};
+use crate::codec::parse_header_line;
+use crate::header::HttpHeader;
+use crate::response::StreamResponse;
+use anyhow::{anyhow, bail, Result};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufRead, Cursor, Read};
+use std::path::Path;
+
+enum Content {
+    Bytes(Vec<u8>),
+    Reader(Box<dyn Read + Send>, Option<u64>),
+}
+
+// multipart/form-data の 1 つのパート
+pub struct Part {
+    content: Content,
+    file_name: Option<String>,
+    content_type: Option<String>,
+    header: HttpHeader,
+}
+
+impl Part {
+    fn new(content: Content) -> Self {
+        Self {
+            content,
+            file_name: None,
+            content_type: None,
+            header: HttpHeader::new(),
+        }
+    }
+
+    pub fn text(value: &str) -> Self {
+        Self::new(Content::Bytes(value.as_bytes().to_vec()))
+    }
+
+    pub fn bytes(data: Vec<u8>) -> Self {
+        Self::new(Content::Bytes(data))
+    }
+
+    // length が分からないパートがあると、ボディ全体が chunked で送られる
+    pub fn reader<R: Read + Send + 'static>(reader: R, length: Option<u64>) -> Self {
+        Self::new(Content::Reader(Box::new(reader), length))
+    }
+
+    // ファイルは送るときに少しずつ読む。ファイル名と Content-Type はパスから決める
+    pub fn file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path)?;
+        let length = file.metadata()?.len();
+        let mut part = Self::reader(file, Some(length));
+        if let Some(name) = path.file_name() {
+            part.file_name(&name.to_string_lossy());
+        }
+        part.content_type(guess_content_type(path));
+        Ok(part)
+    }
+
+    pub fn file_name(&mut self, p: &str) -> &mut Self {
+        self.file_name = Some(p.into());
+        self
+    }
+
+    pub fn content_type(&mut self, p: &str) -> &mut Self {
+        self.content_type = Some(p.into());
+        self
+    }
+
+    // Content-Disposition と Content-Type 以外のヘッダを追加する
+    pub fn header(&mut self, key: &str, value: &str) -> &mut Self {
+        self.header.add(key, value);
+        self
+    }
+
+    fn length(&self) -> Option<u64> {
+        match &self.content {
+            Content::Bytes(data) => Some(data.len() as u64),
+            Content::Reader(_, length) => *length,
+        }
+    }
+
+    fn head(&self, boundary: &str, name: &str) -> Vec<u8> {
+        let mut disposition = format!("form-data; name=\"{}\"", escape(name));
+        if let Some(file_name) = &self.file_name {
+            disposition.push_str(&format!("; filename=\"{}\"", escape(file_name)));
+        }
+        let mut head = format!("--{}\r\nContent-Disposition: {}\r\n", boundary, disposition);
+        if let Some(content_type) = &self.content_type {
+            head.push_str(&format!("Content-Type: {}\r\n", content_type));
+        }
+        for (k, v) in self.header.iter() {
+            head.push_str(&format!("{}: {}\r\n", k, v));
+        }
+        head.push_str("\r\n");
+        head.into_bytes()
+    }
+}
+
+// NOTE: WHATWG HTML の multipart/form-data と同じく、" と改行はパーセントエンコードする
+fn escape(value: &str) -> String {
+    value
+        .replace('"', "%22")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+fn guess_content_type(path: &Path) -> &'static str {
+    let ext = path
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    match ext.as_str() {
+        "txt" => "text/plain",
+        "html" | "htm" => "text/html",
+        "css" => "text/css",
+        "csv" => "text/csv",
+        "json" => "application/json",
+        "xml" => "application/xml",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        "tar" => "application/x-tar",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "svg" => "image/svg+xml",
+        _ => "application/octet-stream",
+    }
+}
+
+// multipart/form-data のボディを組み立てる。Request::multipart で送る
+pub struct Multipart {
+    boundary: String,
+    parts: Vec<(String, Part)>,
+}
+
+impl Default for Multipart {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Multipart {
+    pub fn new() -> Self {
+        let random: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(24)
+            .map(char::from)
+            .collect();
+        Self {
+            boundary: format!("------------------------{}", random),
+            parts: Vec::new(),
+        }
+    }
+
+    pub fn boundary(&mut self, p: &str) -> &mut Self {
+        self.boundary = p.into();
+        self
+    }
+
+    pub fn text(&mut self, name: &str, value: &str) -> &mut Self {
+        self.part(name, Part::text(value))
+    }
+
+    pub fn bytes(&mut self, name: &str, data: Vec<u8>) -> &mut Self {
+        self.part(name, Part::bytes(data))
+    }
+
+    pub fn file<P: AsRef<Path>>(&mut self, name: &str, path: P) -> Result<&mut Self> {
+        Ok(self.part(name, Part::file(path)?))
+    }
+
+    pub fn part(&mut self, name: &str, part: Part) -> &mut Self {
+        self.parts.push((name.into(), part));
+        self
+    }
+
+    pub fn content_type(&self) -> String {
+        format!("multipart/form-data; boundary={}", self.boundary)
+    }
+
+    fn tail(&self) -> Vec<u8> {
+        format!("--{}--\r\n", self.boundary).into_bytes()
+    }
+
+    // すべてのパートの長さが分かっていれば、ボディ全体の長さを返す
+    pub fn content_length(&self) -> Option<u64> {
+        let mut length = self.tail().len() as u64;
+        for (name, part) in &self.parts {
+            // パートの後ろの \r\n の分も足す
+            length += part.head(&self.boundary, name).len() as u64 + part.length()? + 2;
+        }
+        Some(length)
+    }
+
+    // ボディを順番に読む Read にする
+    pub fn into_reader(self) -> MultipartReader {
+        let tail = self.tail();
+        let mut readers: VecDeque<Box<dyn Read + Send>> = VecDeque::new();
+        for (name, part) in self.parts {
+            readers.push_back(Box::new(Cursor::new(part.head(&self.boundary, &name))));
+            match part.content {
+                Content::Bytes(data) => readers.push_back(Box::new(Cursor::new(data))),
+                Content::Reader(reader, _) => readers.push_back(reader),
+            }
+            readers.push_back(Box::new(Cursor::new(b"\r\n".to_vec())));
+        }
+        readers.push_back(Box::new(Cursor::new(tail)));
+        MultipartReader { readers }
+    }
+}
+
+// Multipart::into_reader が返す、パートを順番につないだ Read
+pub struct MultipartReader {
+    readers: VecDeque<Box<dyn Read + Send>>,
+}
+
+impl Read for MultipartReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while let Some(reader) = self.readers.front_mut() {
+            let n = reader.read(buf)?;
+            if n > 0 || buf.is_empty() {
+                return Ok(n);
+            }
+            self.readers.pop_front();
+        }
+        Ok(0)
+    }
+}
+
+// Content-Type の boundary パラメータを取り出す
+pub fn boundary(content_type: &str) -> Result<String> {
+    let mut params = content_type.split(';');
+    let mime = params.next().unwrap_or_default().trim();
+    if !mime.to_ascii_lowercase().starts_with("multipart/") {
+        bail!("not a multipart content-type: {}", content_type);
+    }
+    params
+        .filter_map(|p| p.trim().split_once('='))
+        .find(|(k, _)| k.trim().eq_ignore_ascii_case("boundary"))
+        .map(|(_, v)| v.trim().trim_matches('"').to_string())
+        .filter(|b| !b.is_empty())
+        .ok_or_else(|| anyhow!("boundary is missing in content-type: {}", content_type))
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    // 最初の区切りの前 (プリアンブル)
+    Preamble,
+    // 区切りの直後。次がパートのヘッダか、終わりの -- か
+    Delimiter,
+    Body,
+    Done,
+}
+
+// multipart/mixed や multipart/byteranges のボディを、パートごとにバッファせずに読む
+pub struct MultipartStream<R> {
+    inner: R,
+    // \r\n--boundary
+    delimiter: Vec<u8>,
+    buf: Vec<u8>,
+    state: State,
+}
+
+impl<R: BufRead> MultipartStream<R> {
+    pub fn new(inner: R, boundary: &str) -> Self {
+        Self {
+            inner,
+            delimiter: format!("\r\n--{}", boundary).into_bytes(),
+            // NOTE: 先頭に \r\n を足しておくと、ボディの最初の区切りも同じように探せる
+            buf: b"\r\n".to_vec(),
+            state: State::Preamble,
+        }
+    }
+
+    // バッファに読み足す。ボディが終わっていたら false を返す
+    fn fill(&mut self) -> io::Result<bool> {
+        let data = self.inner.fill_buf()?;
+        if data.is_empty() {
+            return Ok(false);
+        }
+        let n = data.len();
+        self.buf.extend_from_slice(data);
+        self.inner.consume(n);
+        Ok(true)
+    }
+
+    // 区切りの位置を返す。区切りの後ろが -- か空白か改行でなければボディの一部とみなす
+    fn find_delimiter(&mut self) -> io::Result<Option<usize>> {
+        let mut from = 0;
+        loop {
+            let i = match self.buf[from..]
+                .windows(self.delimiter.len())
+                .position(|w| w == self.delimiter.as_slice())
+            {
+                Some(i) => from + i,
+                None => return Ok(None),
+            };
+            let end = i + self.delimiter.len();
+            while self.buf.len() < end + 2 && self.fill()? {}
+            match self.buf[end..] {
+                [b'-', b'-', ..] | [b' ' | b'\t' | b'\r' | b'\n', ..] => return Ok(Some(i)),
+                // NOTE: 途中で終わっている場合は区切りとみなし、あとで EOF のエラーにする
+                [] | [b'-'] => return Ok(Some(i)),
+                _ => from = i + 1,
+            }
+        }
+    }
+
+    fn read_line(&mut self) -> io::Result<Vec<u8>> {
+        loop {
+            if let Some(i) = self.buf.iter().position(|b| *b == b'\n') {
+                return Ok(self.buf.drain(..=i).collect());
+            }
+            if !self.fill()? {
+                return Err(invalid("unexpected end of multipart body"));
+            }
+        }
+    }
+
+    // パートのボディを読む。区切りに着いたら 0 を返す
+    fn read_body(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if self.state != State::Body || out.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            // 区切りの途中かもしれない末尾のバイトは残しておく
+            let available = match self.find_delimiter()? {
+                Some(0) => {
+                    self.buf.drain(..self.delimiter.len());
+                    self.state = State::Delimiter;
+                    return Ok(0);
+                }
+                Some(i) => i,
+                None => self.buf.len().saturating_sub(self.delimiter.len() - 1),
+            };
+            if available > 0 {
+                let n = available.min(out.len());
+                out[..n].copy_from_slice(&self.buf[..n]);
+                self.buf.drain(..n);
+                return Ok(n);
+            }
+            if !self.fill()? {
+                return Err(invalid("unexpected end of multipart body"));
+            }
+        }
+    }
+
+    // 次のパートのヘッダまで読む。前のパートのボディが残っていれば読み捨てる
+    pub fn next_part(&mut self) -> Result<Option<PartReader<'_, R>>> {
+        loop {
+            match self.state {
+                State::Preamble => match self.find_delimiter()? {
+                    Some(i) => {
+                        self.buf.drain(..i + self.delimiter.len());
+                        self.state = State::Delimiter;
+                    }
+                    None => {
+                        let keep = self.delimiter.len() - 1;
+                        if self.buf.len() > keep {
+                            self.buf.drain(..self.buf.len() - keep);
+                        }
+                        if !self.fill()? {
+                            bail!("multipart boundary not found");
+                        }
+                    }
+                },
+                State::Body => {
+                    let mut rest = vec![0u8; 8 * 1024];
+                    while self.read_body(&mut rest)? > 0 {}
+                }
+                State::Delimiter => {
+                    while self.buf.len() < 2 {
+                        if !self.fill()? {
+                            bail!("unexpected end of multipart body");
+                        }
+                    }
+                    if self.buf.starts_with(b"--") {
+                        // NOTE: 終わりの区切りのあと (エピローグ) は無視する
+                        self.state = State::Done;
+                        continue;
+                    }
+                    // 区切りの行の残り (空白があってもよい) を読み捨てる
+                    let line = self.read_line()?;
+                    if !line
+                        .iter()
+                        .all(|b| matches!(b, b' ' | b'\t' | b'\r' | b'\n'))
+                    {
+                        bail!("invalid multipart delimiter line");
+                    }
+
+                    let mut header = HttpHeader::new();
+                    loop {
+                        let line = self.read_line()?;
+                        match parse_header_line(&line)? {
+                            Some((key, value)) => header.add(&key, &value),
+                            None => break,
+                        }
+                    }
+                    self.state = State::Body;
+                    return Ok(Some(PartReader {
+                        header,
+                        stream: self,
+                    }));
+                }
+                State::Done => return Ok(None),
+            }
+        }
+    }
+}
+
+impl<'a> MultipartStream<BodyReader<'a>> {
+    // Content-Type の boundary でレスポンスのボディを分ける
+    pub fn from_response(resp: StreamResponse<'a>) -> Result<Self> {
+        let content_type = resp
+            .header
+            .get("content-type")
+            .ok_or_else(|| anyhow!("content-type is missing"))?;
+        let boundary = boundary(content_type)?;
+        Ok(Self::new(resp.body, &boundary))
+    }
+}
+
+// MultipartStream::next_part が返すパート。ボディは Read で読む
+pub struct PartReader<'a, R> {
+    header: HttpHeader,
+    stream: &'a mut MultipartStream<R>,
+}
+
+impl<R: BufRead> PartReader<'_, R> {
+    // キーは小文字
+    pub fn header(&self) -> &HttpHeader {
+        &self.header
+    }
+
+    pub fn into_part(mut self) -> Result<BodyPart> {
+        let mut data = Vec::new();
+        self.read_to_end(&mut data)?;
+        Ok(BodyPart {
+            body: Body::with_header(data, &self.header),
+            header: self.header,
+        })
+    }
+}
+
+impl<R: BufRead> Read for PartReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.read_body(buf)
+    }
+}
+
+// バッファしたパート
+#[derive(Debug, Clone)]
+pub struct BodyPart {
+    pub header: HttpHeader,
+    pub body: Body,
+}
+
+// バッファ済みのボディをパートに分ける
+pub fn parse(content_type: &str, body: &[u8]) -> Result<Vec<BodyPart>> {
+    let mut stream = MultipartStream::new(body, &boundary(content_type)?);
+    let mut parts = Vec::new();
+    while let Some(part) = stream.next_part()? {
+        parts.push(part.into_part()?);
+    }
+    Ok(parts)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn build_body() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("multipart-{}.txt", std::process::id()));
+        File::create(&path)?.write_all(b"file content")?;
+
+        let mut data = Part::bytes(vec![0, 1, 2]);
+        data.file_name("a\"b.bin")
+            .content_type("application/octet-stream")
+            .header("X-Checksum", "abc");
+
+        let mut form = Multipart::new();
+        form.boundary("XYZ")
+            .text("title", "gorilla")
+            .part("data", data)
+            .file("upload", &path)?;
+        let file_name = path.file_name().unwrap().to_string_lossy().to_string();
+
+        let length = form.content_length();
+        let mut got = Vec::new();
+        form.into_reader().read_to_end(&mut got)?;
+        std::fs::remove_file(&path)?;
+
+        let mut want = [
+            "--XYZ",
+            "Content-Disposition: form-data; name=\"title\"",
+            "",
+            "gorilla",
+            "--XYZ",
+            "Content-Disposition: form-data; name=\"data\"; filename=\"a%22b.bin\"",
+            "Content-Type: application/octet-stream",
+            "X-Checksum: abc",
+            "",
+            "\u{0}\u{1}\u{2}",
+            "--XYZ",
+        ]
+        .join("\r\n");
+        want.push_str(&format!(
+            "\r\nContent-Disposition: form-data; name=\"upload\"; filename=\"{}\"\r\n\
+             Content-Type: text/plain\r\n\r\nfile content\r\n--XYZ--\r\n",
+            file_name
+        ));
+        assert_eq!(String::from_utf8(got.clone())?, want);
+        assert_eq!(length, Some(got.len() as u64));
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_length() {
+        let mut form = Multipart::new();
+        form.text("a", "b")
+            .part("stream", Part::reader(Cursor::new(b"x".to_vec()), None));
+        assert_eq!(form.content_length(), None);
+        assert!(form
+            .content_type()
+            .starts_with("multipart/form-data; boundary=---"));
+    }
+
+    const MIXED: &[u8] = b"preamble\r\n\
+        --sep \t\r\n\
+        Content-Type: text/plain\r\n\r\n\
+        first\r\n--sepX\r\n\
+        --sep\r\n\
+        Content-Type: application/json\r\n\
+        Content-ID: <b>\r\n\r\n\
+        {\"a\":1}\r\n\
+        --sep--\r\nepilogue";
+
+    #[test]
+    fn parse_streaming() -> Result<()> {
+        assert_eq!(
+            boundary("multipart/mixed; charset=utf-8; boundary=\"sep\"")?,
+            "sep"
+        );
+        assert!(boundary("text/plain; boundary=sep").is_err());
+
+        // 1 バイトずつしか読めなくてもパートに分けられること
+        let mut stream = MultipartStream::new(io::BufReader::with_capacity(1, MIXED), "sep");
+        let mut part = stream.next_part()?.unwrap();
+        assert_eq!(part.header().get("content-type").unwrap(), "text/plain");
+        let mut first = String::new();
+        part.read_to_string(&mut first)?;
+        assert_eq!(first, "first\r\n--sepX");
+
+        // 読みかけのパートは next_part で読み捨てられる
+        let part = stream.next_part()?.unwrap();
+        assert_eq!(part.header().get("content-id").unwrap(), "<b>");
+        assert!(stream.next_part()?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn parse_byteranges_response() -> Result<()> {
+        let mut header = HttpHeader::new();
+        header.add("content-type", "multipart/byteranges; boundary=THIS");
+        let resp = crate::response::Response {
+            version: crate::version::HttpVersion::Http11,
+            status: 206,
+            header,
+            body: Some(Body::new(
+                b"--THIS\r\nContent-Range: bytes 0-3/20\r\n\r\nabcd\r\n\
+                  --THIS\r\nContent-Range: bytes 10-11/20\r\n\r\nkl\r\n--THIS--"
+                    .to_vec(),
+            )),
+        };
+        let parts = resp.parts()?;
+        assert_eq!(parts.len(), 2);
+        assert_eq!(
+            parts[1].header.get("content-range").unwrap(),
+            "bytes 10-11/20"
+        );
+        assert_eq!(parts[0].body.text()?, "abcd");
+        assert_eq!(parts[1].body.text()?, "kl");
+        Ok(())
+    }
+
+    #[test]
+    fn parse_built_body() -> Result<()> {
+        let mut form = Multipart::new();
+        form.text("a", "1").bytes("b", b"\r\n--".to_vec());
+        let content_type = form.content_type();
+        let mut body = Vec::new();
+        form.into_reader().read_to_end(&mut body)?;
+
+        let parts = parse(&content_type, &body)?;
+        assert_eq!(parts[0].body.text()?, "1");
+        assert_eq!(parts[1].body.raw(), b"\r\n--");
+        assert_eq!(
+            parts[1].header.get("content-disposition").unwrap(),
+            "form-data; name=\"b\""
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn parse_truncated() {
+        let err = parse("multipart/mixed; boundary=sep", b"--sep\r\n\r\nno end").unwrap_err();
+        assert_eq!(err.to_string(), "unexpected end of multipart body");
+        let err = parse("multipart/mixed; boundary=sep", b"nothing").unwrap_err();
+        assert_eq!(err.to_string(), "multipart boundary not found");
+    }
+}
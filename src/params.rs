@@ -1,15 +1,75 @@
-use std::collections::BTreeMap;
+use anyhow::{anyhow, bail, Result};
 use std::fmt::Display;
 use std::iter::FromIterator;
+use std::str::FromStr;
 
-#[derive(Debug)]
-pub struct HttpParams(BTreeMap<String, String>);
+// NOTE: RFC 3986 の unreserved (ALPHA / DIGIT / "-" / "." / "_" / "~") 以外はすべてエンコードする
+fn is_unreserved(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.' | b'_' | b'~')
+}
+
+pub(crate) fn percent_encode(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        if is_unreserved(b) {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{:02X}", b));
+        }
+    }
+    out
+}
+
+// application/x-www-form-urlencoded (WHATWG URL 5.2) のエンコード。空白は + にする
+pub(crate) fn form_encode(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b' ' => out.push('+'),
+            b'*' | b'-' | b'.' | b'_' => out.push(b as char),
+            b if b.is_ascii_alphanumeric() => out.push(b as char),
+            b => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
+}
+
+pub(crate) fn form_decode(s: &str) -> Result<String> {
+    percent_decode(&s.replace('+', " "))
+}
+
+pub(crate) fn percent_decode(s: &str) -> Result<String> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != b'%' {
+            out.push(bytes[i]);
+            i += 1;
+            continue;
+        }
+        // NOTE: from_str_radix は先頭の + を許すので、先に 16 進数の数字だけか確かめる
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .filter(|h| h.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok())
+            .ok_or_else(|| anyhow!("invalid percent-encoding: {}", s))?;
+        out.push(hex);
+        i += 3;
+    }
+    String::from_utf8(out).map_err(|_| anyhow!("percent-decoded value is not utf-8: {}", s))
+}
+
+// クエリ文字列。同じキーを複数持てて、追加した順に並ぶ
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HttpParams(Vec<(String, String)>);
 
 impl Display for HttpParams {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let mut buf = Vec::<String>::new();
         for (k, v) in self.0.iter() {
-            buf.push(format!("{}={}", k, v));
+            buf.push(format!("{}={}", percent_encode(k), percent_encode(v)));
         }
         write!(f, "{}", buf.join("&"))
     }
@@ -19,17 +79,103 @@ impl<'a> FromIterator<(&'a str, &'a str)> for HttpParams {
     fn from_iter<T: IntoIterator<Item = (&'a str, &'a str)>>(iter: T) -> Self {
         let mut p = Self::new();
         for (k, v) in iter {
-            p.add(k, v);
+            p.append(k, v);
         }
         p
     }
 }
 
+impl FromStr for HttpParams {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::parse(s)
+    }
+}
+
 impl HttpParams {
-    fn new() -> Self {
-        Self(BTreeMap::new())
+    pub fn new() -> Self {
+        Self(Vec::new())
     }
-    fn add(&mut self, key: &str, value: &str) {
-        self.0.insert(key.into(), value.into());
+
+    // `a=1&b=2` のようなクエリ文字列をパースする。先頭の `?` は無視する
+    pub fn parse(query: &str) -> Result<Self> {
+        let query = query.strip_prefix('?').unwrap_or(query);
+        let mut p = Self::new();
+        for pair in query.split('&').filter(|s| !s.is_empty()) {
+            let (k, v) = pair.split_once('=').unwrap_or((pair, ""));
+            if k.is_empty() {
+                bail!("empty key in query string: {}", query);
+            }
+            p.append(&percent_decode(k)?, &percent_decode(v)?);
+        }
+        Ok(p)
+    }
+
+    pub fn append(&mut self, key: &str, value: &str) {
+        self.0.push((key.into(), value.into()));
+    }
+
+    // key のすべての値を消す
+    pub fn remove(&mut self, key: &str) {
+        self.0.retain(|(k, _)| k != key);
+    }
+
+    // key の最初の値
+    pub fn get(&self, key: &str) -> Option<&String> {
+        self.0.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+    }
+
+    pub fn get_all(&self, key: &str) -> Vec<&String> {
+        self.0
+            .iter()
+            .filter(|(k, _)| k == key)
+            .map(|(_, v)| v)
+            .collect()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &String)> {
+        self.0.iter().map(|(k, v)| (k, v))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn encode_in_order() {
+        let mut params: HttpParams = [("tag", "a b"), ("q", "x&y=z")].into_iter().collect();
+        params.append("tag", "日本");
+        assert_eq!(
+            params.to_string(),
+            "tag=a%20b&q=x%26y%3Dz&tag=%E6%97%A5%E6%9C%AC"
+        );
+    }
+
+    #[test]
+    fn parse_and_modify() -> Result<()> {
+        let mut params: HttpParams = "?tag=a%20b&tag=c&flag&empty=".parse()?;
+        assert_eq!(params.get("tag").unwrap(), "a b");
+        assert_eq!(params.get_all("tag"), vec!["a b", "c"]);
+        assert_eq!(params.get("flag").unwrap(), "");
+        assert_eq!(params.len(), 4);
+
+        params.remove("tag");
+        assert_eq!(params.get("tag"), None);
+        assert_eq!(params.to_string(), "flag=&empty=");
+
+        assert!(HttpParams::parse("a=%zz").is_err());
+        assert!(HttpParams::parse("a=%ff").is_err());
+        assert!(HttpParams::parse("a=%+f").is_err());
+        Ok(())
     }
 }
@@ -0,0 +1,719 @@
+use crate::params::HttpParams;
+use anyhow::{anyhow, bail, Result};
+use serde::de::{
+    self, value::Error as DeError, DeserializeOwned, DeserializeSeed, IntoDeserializer, MapAccess,
+    SeqAccess, Visitor,
+};
+use serde::ser::{self, Impossible, Serialize};
+use serde_json::Value;
+
+// 配列をクエリ文字列でどう表すか
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ArrayStyle {
+    // tag=a&tag=b
+    #[default]
+    Repeat,
+    // tag[]=a&tag[]=b
+    Bracket,
+    // tag=a,b
+    Comma,
+}
+
+// 構造体やマップをクエリ文字列にする。None のフィールドは付けない。
+// ネストした構造体やマップは表せないのでエラーにする
+pub fn to_params<T: Serialize>(value: &T, style: ArrayStyle) -> Result<HttpParams> {
+    let fields = value
+        .serialize(FieldsSerializer)
+        .map_err(|e| anyhow!("{}", e))?;
+
+    let mut params = HttpParams::new();
+    for (key, value) in fields {
+        match value {
+            Value::Null => {}
+            Value::Array(values) => {
+                let values = values
+                    .into_iter()
+                    .filter(|v| !v.is_null())
+                    .map(|v| scalar(&key, v))
+                    .collect::<Result<Vec<_>>>()?;
+                match style {
+                    ArrayStyle::Repeat => values.iter().for_each(|v| params.append(&key, v)),
+                    ArrayStyle::Bracket => {
+                        let key = format!("{}[]", key);
+                        values.iter().for_each(|v| params.append(&key, v))
+                    }
+                    // NOTE: 空の配列は key= ではなく何も付けない (Repeat と同じ)
+                    ArrayStyle::Comma if values.is_empty() => {}
+                    ArrayStyle::Comma => params.append(&key, &values.join(",")),
+                }
+            }
+            value => params.append(&key, &scalar(&key, value)?),
+        }
+    }
+    Ok(params)
+}
+
+fn scalar(key: &str, value: Value) -> Result<String> {
+    match value {
+        Value::String(s) => Ok(s),
+        Value::Bool(b) => Ok(b.to_string()),
+        Value::Number(n) => Ok(n.to_string()),
+        _ => bail!("cannot serialize nested value in query: {}", key),
+    }
+}
+
+// クエリ文字列を構造体にする。値はすべて文字列なので、数値や bool はフィールドの型に合わせてパースする
+pub fn from_params<T: DeserializeOwned>(params: &HttpParams, style: ArrayStyle) -> Result<T> {
+    let mut entries: Vec<(String, Vec<String>)> = Vec::new();
+    for (key, value) in params.iter() {
+        let key = match style {
+            ArrayStyle::Bracket => key.strip_suffix("[]").unwrap_or(key),
+            _ => key,
+        };
+        // NOTE: Comma でも , で分けるのは配列のフィールドだけなので、ここでは分けずに持っておく
+        match entries.iter_mut().find(|(k, _)| k == key) {
+            Some((_, v)) => v.push(value.clone()),
+            None => entries.push((key.to_string(), vec![value.clone()])),
+        }
+    }
+    T::deserialize(QueryDeserializer {
+        entries: entries.into_iter(),
+        value: None,
+        comma: style == ArrayStyle::Comma,
+    })
+    .map_err(|e| anyhow!("{}", e))
+}
+
+pub fn from_str<T: DeserializeOwned>(query: &str, style: ArrayStyle) -> Result<T> {
+    from_params(&HttpParams::parse(query)?, style)
+}
+
+// トップレベルの構造体やマップのフィールドを、順番を保ったまま取り出す
+struct FieldsSerializer;
+
+type Fields = Vec<(String, Value)>;
+
+fn unsupported() -> serde_json::Error {
+    ser::Error::custom("query must be a struct or map")
+}
+
+impl ser::Serializer for FieldsSerializer {
+    type Ok = Fields;
+    type Error = serde_json::Error;
+    type SerializeSeq = FieldsCollector;
+    type SerializeTuple = FieldsCollector;
+    type SerializeTupleStruct = Impossible<Fields, serde_json::Error>;
+    type SerializeTupleVariant = Impossible<Fields, serde_json::Error>;
+    type SerializeMap = FieldsCollector;
+    type SerializeStruct = FieldsCollector;
+    type SerializeStructVariant = Impossible<Fields, serde_json::Error>;
+
+    fn serialize_struct(self, _: &'static str, _: usize) -> Result<FieldsCollector, Self::Error> {
+        Ok(FieldsCollector::default())
+    }
+
+    fn serialize_map(self, _: Option<usize>) -> Result<FieldsCollector, Self::Error> {
+        Ok(FieldsCollector::default())
+    }
+
+    fn serialize_newtype_struct<T: ?Sized + Serialize>(
+        self,
+        _: &'static str,
+        value: &T,
+    ) -> Result<Fields, Self::Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_some<T: ?Sized + Serialize>(self, value: &T) -> Result<Fields, Self::Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_none(self) -> Result<Fields, Self::Error> {
+        Ok(Vec::new())
+    }
+
+    fn serialize_unit(self) -> Result<Fields, Self::Error> {
+        Ok(Vec::new())
+    }
+
+    fn serialize_unit_struct(self, _: &'static str) -> Result<Fields, Self::Error> {
+        Ok(Vec::new())
+    }
+
+    fn serialize_bool(self, _: bool) -> Result<Fields, Self::Error> {
+        Err(unsupported())
+    }
+
+    fn serialize_i8(self, _: i8) -> Result<Fields, Self::Error> {
+        Err(unsupported())
+    }
+
+    fn serialize_i16(self, _: i16) -> Result<Fields, Self::Error> {
+        Err(unsupported())
+    }
+
+    fn serialize_i32(self, _: i32) -> Result<Fields, Self::Error> {
+        Err(unsupported())
+    }
+
+    fn serialize_i64(self, _: i64) -> Result<Fields, Self::Error> {
+        Err(unsupported())
+    }
+
+    fn serialize_u8(self, _: u8) -> Result<Fields, Self::Error> {
+        Err(unsupported())
+    }
+
+    fn serialize_u16(self, _: u16) -> Result<Fields, Self::Error> {
+        Err(unsupported())
+    }
+
+    fn serialize_u32(self, _: u32) -> Result<Fields, Self::Error> {
+        Err(unsupported())
+    }
+
+    fn serialize_u64(self, _: u64) -> Result<Fields, Self::Error> {
+        Err(unsupported())
+    }
+
+    fn serialize_f32(self, _: f32) -> Result<Fields, Self::Error> {
+        Err(unsupported())
+    }
+
+    fn serialize_f64(self, _: f64) -> Result<Fields, Self::Error> {
+        Err(unsupported())
+    }
+
+    fn serialize_char(self, _: char) -> Result<Fields, Self::Error> {
+        Err(unsupported())
+    }
+
+    fn serialize_str(self, _: &str) -> Result<Fields, Self::Error> {
+        Err(unsupported())
+    }
+
+    fn serialize_bytes(self, _: &[u8]) -> Result<Fields, Self::Error> {
+        Err(unsupported())
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+    ) -> Result<Fields, Self::Error> {
+        Err(unsupported())
+    }
+
+    fn serialize_newtype_variant<T: ?Sized + Serialize>(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: &T,
+    ) -> Result<Fields, Self::Error> {
+        Err(unsupported())
+    }
+
+    // NOTE: serde_urlencoded と同じく、[("a", "1"), ("b", "2")] のような (key, value) の列も受け付ける
+    fn serialize_seq(self, _: Option<usize>) -> Result<Self::SerializeSeq, Self::Error> {
+        Ok(FieldsCollector::default())
+    }
+
+    fn serialize_tuple(self, _: usize) -> Result<Self::SerializeTuple, Self::Error> {
+        Ok(FieldsCollector::default())
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Self::SerializeTupleStruct, Self::Error> {
+        Err(unsupported())
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Self::SerializeTupleVariant, Self::Error> {
+        Err(unsupported())
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Self::SerializeStructVariant, Self::Error> {
+        Err(unsupported())
+    }
+}
+
+fn key_string(key: Value) -> Result<String, serde_json::Error> {
+    match key {
+        Value::String(s) => Ok(s),
+        Value::Number(n) => Ok(n.to_string()),
+        Value::Bool(b) => Ok(b.to_string()),
+        _ => Err(ser::Error::custom("query key must be a string")),
+    }
+}
+
+#[derive(Default)]
+struct FieldsCollector {
+    fields: Fields,
+    key: Option<String>,
+}
+
+impl ser::SerializeStruct for FieldsCollector {
+    type Ok = Fields;
+    type Error = serde_json::Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Self::Error> {
+        self.fields.push((key.into(), serde_json::to_value(value)?));
+        Ok(())
+    }
+
+    fn end(self) -> Result<Fields, Self::Error> {
+        Ok(self.fields)
+    }
+}
+
+impl ser::SerializeSeq for FieldsCollector {
+    type Ok = Fields;
+    type Error = serde_json::Error;
+
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Self::Error> {
+        let (key, value) = match serde_json::to_value(value)? {
+            Value::Array(pair) if pair.len() == 2 => {
+                let mut pair = pair.into_iter();
+                (pair.next().unwrap(), pair.next().unwrap())
+            }
+            _ => {
+                return Err(ser::Error::custom(
+                    "query sequence must contain (key, value) pairs",
+                ))
+            }
+        };
+        self.fields.push((key_string(key)?, value));
+        Ok(())
+    }
+
+    fn end(self) -> Result<Fields, Self::Error> {
+        Ok(self.fields)
+    }
+}
+
+impl ser::SerializeTuple for FieldsCollector {
+    type Ok = Fields;
+    type Error = serde_json::Error;
+
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Self::Error> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<Fields, Self::Error> {
+        Ok(self.fields)
+    }
+}
+
+impl ser::SerializeMap for FieldsCollector {
+    type Ok = Fields;
+    type Error = serde_json::Error;
+
+    fn serialize_key<T: ?Sized + Serialize>(&mut self, key: &T) -> Result<(), Self::Error> {
+        self.key = Some(key_string(serde_json::to_value(key)?)?);
+        Ok(())
+    }
+
+    fn serialize_value<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Self::Error> {
+        let key = self
+            .key
+            .take()
+            .ok_or_else(|| ser::Error::custom("serialize_value called before serialize_key"))?;
+        self.fields.push((key, serde_json::to_value(value)?));
+        Ok(())
+    }
+
+    fn end(self) -> Result<Fields, Self::Error> {
+        Ok(self.fields)
+    }
+}
+
+// キーごとにまとめた値をマップとして渡す
+struct QueryDeserializer {
+    entries: std::vec::IntoIter<(String, Vec<String>)>,
+    value: Option<Vec<String>>,
+    comma: bool,
+}
+
+impl<'de> de::Deserializer<'de> for QueryDeserializer {
+    type Error = DeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        visitor.visit_map(self)
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct enum identifier ignored_any
+    }
+}
+
+impl<'de> MapAccess<'de> for QueryDeserializer {
+    type Error = DeError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, DeError> {
+        match self.entries.next() {
+            Some((key, values)) => {
+                self.value = Some(values);
+                seed.deserialize(key.into_deserializer()).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, DeError> {
+        let values = self
+            .value
+            .take()
+            .ok_or_else(|| de::Error::custom("value is missing"))?;
+        seed.deserialize(ValuesDeserializer {
+            values,
+            comma: self.comma,
+        })
+    }
+}
+
+// 数値などは最後の値の PartDeserializer に任せる
+macro_rules! forward_to_last {
+    ($($method:ident)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+                self.last()?.$method(visitor)
+            }
+        )*
+    };
+}
+
+// 1 つのキーの値。配列なら全部を、それ以外なら最後の値を使う。
+// comma なら配列の値は , で分ける
+struct ValuesDeserializer {
+    values: Vec<String>,
+    comma: bool,
+}
+
+impl ValuesDeserializer {
+    fn last(mut self) -> Result<PartDeserializer, DeError> {
+        self.values
+            .pop()
+            .map(PartDeserializer)
+            .ok_or_else(|| de::Error::custom("missing value"))
+    }
+}
+
+impl<'de> de::Deserializer<'de> for ValuesDeserializer {
+    type Error = DeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        self.last()?.deserialize_any(visitor)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        let values = match self.comma {
+            // NOTE: 空の配列は key= になるので、空文字列は要素なしとみなす
+            true => self
+                .values
+                .iter()
+                .filter(|v| !v.is_empty())
+                .flat_map(|v| v.split(',').map(|p| p.to_string()))
+                .collect(),
+            false => self.values,
+        };
+        visitor.visit_seq(PartsAccess(values.into_iter()))
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, _: usize, visitor: V) -> Result<V::Value, DeError> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        visitor.visit_some(self)
+    }
+
+    forward_to_last! {
+        deserialize_bool deserialize_i8 deserialize_i16 deserialize_i32 deserialize_i64
+        deserialize_u8 deserialize_u16 deserialize_u32 deserialize_u64 deserialize_f32
+        deserialize_f64 deserialize_unit
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        self.last()?.deserialize_enum(name, variants, visitor)
+    }
+
+    serde::forward_to_deserialize_any! {
+        i128 u128 char str string bytes byte_buf unit_struct newtype_struct
+        tuple_struct map struct identifier ignored_any
+    }
+}
+
+struct PartsAccess(std::vec::IntoIter<String>);
+
+impl<'de> SeqAccess<'de> for PartsAccess {
+    type Error = DeError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, DeError> {
+        match self.0.next() {
+            Some(v) => seed.deserialize(PartDeserializer(v)).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+// 1 つの値の文字列
+struct PartDeserializer(String);
+
+impl PartDeserializer {
+    fn parse<T: std::str::FromStr>(&self, kind: &str) -> Result<T, DeError> {
+        self.0
+            .parse()
+            .map_err(|_| de::Error::custom(format!("invalid {}: {:?}", kind, self.0)))
+    }
+}
+
+impl<'de> de::Deserializer<'de> for PartDeserializer {
+    type Error = DeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        visitor.visit_string(self.0)
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        visitor.visit_bool(self.parse("bool")?)
+    }
+
+    fn deserialize_i8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        visitor.visit_i8(self.parse("integer")?)
+    }
+
+    fn deserialize_i16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        visitor.visit_i16(self.parse("integer")?)
+    }
+
+    fn deserialize_i32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        visitor.visit_i32(self.parse("integer")?)
+    }
+
+    fn deserialize_i64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        visitor.visit_i64(self.parse("integer")?)
+    }
+
+    fn deserialize_u8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        visitor.visit_u8(self.parse("integer")?)
+    }
+
+    fn deserialize_u16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        visitor.visit_u16(self.parse("integer")?)
+    }
+
+    fn deserialize_u32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        visitor.visit_u32(self.parse("integer")?)
+    }
+
+    fn deserialize_u64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        visitor.visit_u64(self.parse("integer")?)
+    }
+
+    fn deserialize_f32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        visitor.visit_f32(self.parse("number")?)
+    }
+
+    fn deserialize_f64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        visitor.visit_f64(self.parse("number")?)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        self.0
+            .into_deserializer()
+            .deserialize_enum(name, variants, visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    serde::forward_to_deserialize_any! {
+        i128 u128 char str string bytes byte_buf unit_struct seq tuple
+        tuple_struct map struct identifier ignored_any
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde::{Deserialize, Serialize};
+    use std::collections::BTreeMap;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
+    #[serde(rename_all = "lowercase")]
+    enum State {
+        #[default]
+        Open,
+        Closed,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
+    struct Filter {
+        q: String,
+        page: u32,
+        all: bool,
+        state: State,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        since: Option<String>,
+        limit: Option<u8>,
+        #[serde(default)]
+        tags: Vec<String>,
+    }
+
+    fn filter() -> Filter {
+        Filter {
+            q: "a&b c".into(),
+            page: 2,
+            all: true,
+            state: State::Closed,
+            since: None,
+            limit: Some(10),
+            tags: vec!["x".into(), "y".into()],
+        }
+    }
+
+    #[test]
+    fn serialize_styles() -> Result<()> {
+        let base = "q=a%26b%20c&page=2&all=true&state=closed&limit=10";
+        let want = [
+            (ArrayStyle::Repeat, "tags=x&tags=y"),
+            (ArrayStyle::Bracket, "tags%5B%5D=x&tags%5B%5D=y"),
+            (ArrayStyle::Comma, "tags=x%2Cy"),
+        ];
+        for (style, tags) in want {
+            let params = to_params(&filter(), style)?;
+            assert_eq!(params.to_string(), format!("{}&{}", base, tags));
+            assert_eq!(from_params::<Filter>(&params, style)?, filter());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn comma_splits_only_arrays() -> Result<()> {
+        for q in ["a,b", ""] {
+            let want = Filter {
+                q: q.into(),
+                tags: vec!["x,y".into(), "z".into()],
+                ..filter()
+            };
+            let params = to_params(&want, ArrayStyle::Comma)?;
+            let got = from_params::<Filter>(&params, ArrayStyle::Comma)?;
+            // NOTE: 要素に含まれる , は区切りと区別できない
+            let tags = vec!["x".into(), "y".into(), "z".into()];
+            assert_eq!(got, Filter { tags, ..want });
+        }
+        let got: Filter = from_str("q=&page=1&all=false&state=open&tags=", ArrayStyle::Comma)?;
+        assert_eq!(got.q, "");
+        assert!(got.tags.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn serialize_pairs() -> Result<()> {
+        let pairs = vec![("tag", "a b"), ("page", "2"), ("tag", "c")];
+        let params = to_params(&pairs, ArrayStyle::Repeat)?;
+        assert_eq!(params.to_string(), "tag=a%20b&page=2&tag=c");
+        let params = to_params(&[("ids", vec![1, 2])], ArrayStyle::Comma)?;
+        assert_eq!(params.to_string(), "ids=1%2C2");
+
+        let err = to_params(&vec![1, 2], ArrayStyle::Repeat).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "query sequence must contain (key, value) pairs"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn deserialize_query() -> Result<()> {
+        let got: Filter = from_str("q=rust&page=1&all=false&state=open", ArrayStyle::Repeat)?;
+        assert_eq!(
+            got,
+            Filter {
+                q: "rust".into(),
+                page: 1,
+                ..Default::default()
+            }
+        );
+
+        let err = from_str::<Filter>("q=rust&page=x&all=false&state=open", ArrayStyle::Repeat)
+            .unwrap_err();
+        assert_eq!(err.to_string(), "invalid integer: \"x\"");
+        Ok(())
+    }
+
+    #[test]
+    fn serialize_map_and_reject_nested() -> Result<()> {
+        let map: BTreeMap<&str, u32> = [("b", 2), ("a", 1)].into_iter().collect();
+        assert_eq!(to_params(&map, ArrayStyle::Repeat)?.to_string(), "a=1&b=2");
+
+        #[derive(Serialize)]
+        struct Nested {
+            inner: BTreeMap<String, String>,
+        }
+        let err = to_params(
+            &Nested {
+                inner: BTreeMap::new(),
+            },
+            ArrayStyle::Repeat,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "cannot serialize nested value in query: inner"
+        );
+        assert!(to_params(&vec![1, 2], ArrayStyle::Repeat).is_err());
+        Ok(())
+    }
+}
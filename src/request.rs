@@ -1,10 +1,56 @@
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Result};
 use serde::Serialize;
+use std::io::{self, Read, Write};
+use std::time::Duration;
 
-use crate::body::Body;
+use crate::body::{Body, StreamBody};
+use crate::codec::BodyEncoder;
+use crate::compression::{self, Compression};
+use crate::form::{self, Form};
 use crate::header::*;
 use crate::method::*;
+use crate::multipart::Multipart;
 use crate::params::*;
+use crate::query::{self, ArrayStyle};
+use crate::version::HttpVersion;
+
+pub(crate) struct OutgoingStream {
+    pub reader: Box<dyn Read + Send>,
+    // None なら chunked で送る
+    pub length: Option<u64>,
+}
+
+// ヘッダのあとに送るボディ
+pub(crate) struct OutgoingBody {
+    pub body: Option<Vec<u8>>,
+    pub stream: Option<OutgoingStream>,
+}
+
+impl OutgoingBody {
+    pub fn write_to<W: Write>(self, w: &mut W) -> Result<()> {
+        if let Some(body) = self.body {
+            w.write_all(&body)?;
+        }
+        let OutgoingStream { mut reader, length } = match self.stream {
+            Some(stream) => stream,
+            None => return Ok(()),
+        };
+
+        let mut encoder = BodyEncoder::new(length);
+        let mut buf = vec![0u8; 8 * 1024];
+        while !encoder.is_full() {
+            let n = match reader.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+            w.write_all(&encoder.encode(&buf[..n]))?;
+        }
+        w.write_all(&encoder.finish()?)?;
+        Ok(())
+    }
+}
 
 #[derive(Default)]
 pub struct Request {
@@ -14,6 +60,12 @@ pub struct Request {
     pub header: Option<HttpHeader>,
     pub params: Option<HttpParams>,
     pub body: Option<Body>,
+    pub stream: Option<StreamBody>,
+    pub compression: Option<Compression>,
+    pub version: HttpVersion,
+    pub expect_continue: Option<Duration>,
+    // query や form で値をシリアライズできなかったときのエラー。送るときに返す
+    pub error: Option<String>,
 }
 
 impl Request {
@@ -44,8 +96,58 @@ impl Request {
         self
     }
 
+    // p をクエリ文字列にして params に追加する。配列は tag=a&tag=b のようにキーを繰り返す
+    pub fn query<T: Serialize>(&mut self, p: &T) -> &mut Self {
+        self.query_with_style(p, ArrayStyle::Repeat)
+    }
+
+    // NOTE: シリアライズできなければ panic せずにエラーを覚えておき、送るときに返す
+    pub fn query_with_style<T: Serialize>(&mut self, p: &T, style: ArrayStyle) -> &mut Self {
+        let query = match query::to_params(p, style) {
+            Ok(query) => query,
+            Err(e) => return self.fail(format!("cannot serialize query: {}", e)),
+        };
+        let params = self.params.get_or_insert_with(HttpParams::new);
+        for (k, v) in query.iter() {
+            params.append(k, v);
+        }
+        self
+    }
+
     pub fn body(&mut self, p: Vec<u8>) -> &mut Self {
         self.body = Some(Body::new(p));
+        self.stream = None;
+        self
+    }
+
+    // length を渡すと Content-Length を、None なら Transfer-Encoding: chunked で送る
+    pub fn body_reader<R: Read + Send + 'static>(
+        &mut self,
+        p: R,
+        length: Option<u64>,
+    ) -> &mut Self {
+        self.stream = Some(StreamBody::new(p, length));
+        self.body = None;
+        self
+    }
+
+    // Content-Encoding を付けてボディを圧縮して送る
+    pub fn compress(&mut self, p: Compression) -> &mut Self {
+        self.compression = Some(p);
+        self
+    }
+
+    // HTTP/1.0 で送る場合は Connection: keep-alive を付けない限りコネクションは再利用されない。
+    // Http2 は H2Client でしか送れず、HttpClient で送るとエラーになる
+    pub fn version(&mut self, p: HttpVersion) -> &mut Self {
+        self.version = p;
+        self
+    }
+
+    // ボディがあれば Expect: 100-continue を付けてヘッダだけ先に送り、100 Continue を timeout まで待ってから
+    // ボディを送る。待っている間に最終的なレスポンスが返ればボディは送らない
+    pub fn expect_continue(&mut self, timeout: Duration) -> &mut Self {
+        self.expect_continue = Some(timeout);
         self
     }
 
@@ -94,43 +196,236 @@ impl Request {
     pub fn json<T: Serialize>(&mut self, p: T) -> &mut Self {
         let json = serde_json::to_value(p).unwrap();
         self.body = Some(Body::new(json.to_string().as_bytes().to_vec()));
+        self.stream = None;
         self
     }
 
-    pub fn build(&self) -> Vec<u8> {
-        let url = match &self.params {
+    // p を application/x-www-form-urlencoded のボディにして、Content-Type も付ける。
+    // query と同じく、シリアライズできなければ送るときにエラーを返す
+    pub fn form<T: Serialize>(&mut self, p: &T) -> &mut Self {
+        match Form::from_serialize(p) {
+            Ok(form) => self.form_body(&form),
+            Err(e) => self.fail(format!("cannot serialize form: {}", e)),
+        }
+    }
+
+    pub fn form_body(&mut self, p: &Form) -> &mut Self {
+        self.typed_body(form::CONTENT_TYPE, p.to_string().into_bytes())
+    }
+
+    // p を MessagePack のボディにして、Content-Type も付ける。フィールド名付きの map としてエンコードする
+    #[cfg(feature = "msgpack")]
+    pub fn msgpack<T: Serialize>(&mut self, p: &T) -> &mut Self {
+        match rmp_serde::to_vec_named(p) {
+            Ok(body) => self.typed_body(crate::format::MSGPACK, body),
+            Err(e) => self.fail(format!("cannot serialize msgpack: {}", e)),
+        }
+    }
+
+    #[cfg(feature = "cbor")]
+    pub fn cbor<T: Serialize>(&mut self, p: &T) -> &mut Self {
+        let mut body = Vec::new();
+        match ciborium::into_writer(p, &mut body) {
+            Ok(()) => self.typed_body(crate::format::CBOR, body),
+            Err(e) => self.fail(format!("cannot serialize cbor: {}", e)),
+        }
+    }
+
+    // NOTE: ルート要素の名前は型の名前になる。列や、キーが文字列でないマップはシリアライズできない
+    #[cfg(feature = "xml")]
+    pub fn xml<T: Serialize>(&mut self, p: &T) -> &mut Self {
+        match quick_xml::se::to_string(p) {
+            Ok(body) => self.typed_body(crate::format::XML, body.into_bytes()),
+            Err(e) => self.fail(format!("cannot serialize xml: {}", e)),
+        }
+    }
+
+    // Content-Type と Content-Length を付け替えてボディをセットする
+    fn typed_body(&mut self, content_type: &str, body: Vec<u8>) -> &mut Self {
+        let mut header = self.header.take().unwrap_or_default();
+        header.remove_ignore_case("Content-Type");
+        header.add("Content-Type", content_type);
+        header.remove_ignore_case("Content-Length");
+        header.add("Content-Length", body.len().to_string().as_str());
+        self.header = Some(header);
+        self.body(body)
+    }
+
+    // multipart/form-data で送る。すべてのパートの長さが分かれば Content-Length を、分からなければ chunked で送る
+    pub fn multipart(&mut self, p: Multipart) -> &mut Self {
+        let mut header = self.header.take().unwrap_or_default();
+        header.remove_ignore_case("Content-Type");
+        header.add("Content-Type", &p.content_type());
+        self.header = Some(header);
+        let length = p.content_length();
+        self.body_reader(p.into_reader(), length)
+    }
+
+    pub fn build(&self) -> Result<Vec<u8>> {
+        self.build_with_header(&HttpHeader::new())
+    }
+
+    // リクエストターゲット (パスとクエリ)
+    pub(crate) fn target(&self) -> String {
+        match &self.params {
             Some(params) => {
                 format!("{}?{}", self.url, params)
             }
             None => self.url.clone(),
-        };
+        }
+    }
 
-        let base_url = match &self.base_url {
+    pub(crate) fn authority(&self) -> String {
+        match &self.base_url {
             Some(base_url) => base_url.clone(),
             None => "localhost".into(),
-        };
+        }
+    }
+
+    // 送信するヘッダ (Host 以外) とバッファ済みのボディを返す。
+    // extra のヘッダはリクエストに同じ名前のヘッダが無いときだけ追加する
+    pub(crate) fn message_parts(
+        &self,
+        extra: &HttpHeader,
+    ) -> Result<(HttpHeader, Option<Vec<u8>>)> {
+        let mut header = self.header.clone().unwrap_or_default();
+        for (k, v) in extra.iter() {
+            if header.get_ignore_case(k).is_none() {
+                header.add(k, v);
+            }
+        }
+
+        let mut body = self.body.as_ref().map(|b| b.raw());
+        if let Some(c) = self.compression {
+            // NOTE: 圧縮するとボディの長さが変わるので、ユーザーが付けた Content-Length は使えない
+            if let Some(data) = &body {
+                let data = compression::encode(data, c)
+                    .map_err(|e| anyhow!("cannot compress request body: {}", e))?;
+                header.remove_ignore_case("Content-Length");
+                header.add("Content-Length", data.len().to_string().as_str());
+                body = Some(data);
+            }
+            if body.is_some() || self.stream.is_some() {
+                header.remove_ignore_case("Content-Encoding");
+                header.add("Content-Encoding", c.encoding.to_string().as_str());
+            }
+        }
+        if self.stream.is_some() {
+            header.remove_ignore_case("Content-Length");
+            header.remove_ignore_case("Transfer-Encoding");
+            match self.stream_length() {
+                Some(length) => header.add("Content-Length", length.to_string().as_str()),
+                None => header.add("Transfer-Encoding", "chunked"),
+            }
+        }
+        Ok((header, body))
+    }
 
+    // 100 Continue を待つ必要があるなら、その待ち時間を返す
+    pub(crate) fn continue_timeout(&self) -> Option<Duration> {
+        match self.body.is_some() || self.stream.is_some() {
+            true => self.expect_continue,
+            false => None,
+        }
+    }
+
+    pub(crate) fn build_with_header(&self, extra: &HttpHeader) -> Result<Vec<u8>> {
+        let (mut message, body) = self.build_head(extra)?;
+        if let Some(mut body) = body {
+            message.append(&mut body);
+        }
+        Ok(message)
+    }
+
+    // ヘッダの終わりの空行までと、バッファ済みのボディを分けて返す
+    fn build_head(&self, extra: &HttpHeader) -> Result<(Vec<u8>, Option<Vec<u8>>)> {
         let mut message = vec![
-            format!("{} {} HTTP/1.1", self.method, url),
-            format!("Host: {}", base_url),
+            format!("{} {} {}", self.method, self.target(), self.version),
+            format!("Host: {}", self.authority()),
         ];
-        if let Some(header) = &self.header {
+        let (header, body) = self.message_parts(extra)?;
+        if header.iter().next().is_some() {
             message.push(format!("{}", header));
         }
         message.push("".into());
 
         let mut message = message.join("\r\n").as_bytes().to_vec();
-        let mut newline = b"\r\n".to_vec();
-        if let Some(data) = &self.body {
-            message.append(&mut newline.clone());
-            message.append(&mut data.raw());
+        message.extend_from_slice(b"\r\n");
+        let body = body.map(|mut data| {
+            data.extend_from_slice(b"\r\n");
+            data
+        });
+        Ok((message, body))
+    }
+
+    // 圧縮する場合は送るまで長さが分からないので chunked にする
+    fn stream_length(&self) -> Option<u64> {
+        match self.compression {
+            Some(_) => None,
+            None => self.stream.as_ref().and_then(|s| s.length()),
+        }
+    }
+
+    // ストリームのボディを (圧縮する場合は圧縮して) 取り出す
+    pub(crate) fn take_stream(&self) -> Result<Option<OutgoingStream>> {
+        let stream = match &self.stream {
+            Some(stream) => stream,
+            None => return Ok(None),
+        };
+        // NOTE: HTTP/1.0 には chunked が無いので長さの分からないボディは送れない
+        if self.version == HttpVersion::Http10 && self.stream_length().is_none() {
+            bail!("HTTP/1.0 request body requires a known length");
+        }
+        let reader = stream
+            .take()
+            .ok_or_else(|| anyhow!("request body stream was already sent"))?;
+        let reader = match self.compression {
+            Some(c) => compression::encoder(reader, c)?,
+            None => reader,
+        };
+        Ok(Some(OutgoingStream {
+            reader,
+            length: self.stream_length(),
+        }))
+    }
+
+    // build_with_header の内容を書き込んだあと、ストリームのボディがあればそれも送る
+    pub(crate) fn write_to<W: Write>(&self, w: &mut W, extra: &HttpHeader) -> Result<()> {
+        let (head, body) = self.prepare_head(extra)?;
+        w.write_all(&head)?;
+        body.write_to(w)
+    }
+
+    // ヘッダまでと、あとから送るボディに分けて返す (Expect: 100-continue 用)。
+    // ストリームは一度しか取り出せない
+    pub(crate) fn prepare_head(&self, extra: &HttpHeader) -> Result<(Vec<u8>, OutgoingBody)> {
+        self.check()?;
+        // NOTE: HTTP/2 はバイナリのフレームで送るので、HTTP/1 のリクエスト行には書けない。H2Client を使うこと
+        if self.version == HttpVersion::Http2 {
+            bail!("HTTP/2 requests must be sent with H2Client");
+        }
+        let stream = self.take_stream()?;
+        let (head, body) = self.build_head(extra)?;
+        Ok((head, OutgoingBody { body, stream }))
+    }
+
+    // 最初のエラーだけを覚えておく
+    fn fail(&mut self, message: String) -> &mut Self {
+        self.error.get_or_insert(message);
+        self
+    }
+
+    // リクエストを組み立てる途中でエラーがあれば返す
+    pub(crate) fn check(&self) -> Result<()> {
+        match &self.error {
+            Some(e) => bail!("{}", e),
+            None => Ok(()),
         }
-        message.append(&mut newline);
-        message
     }
 
     pub fn to_string(&self) -> Result<String> {
-        let result = self.build();
+        self.check()?;
+        let result = self.build()?;
         String::from_utf8(result).map_err(|x| anyhow!("{}", x))
     }
 }
@@ -140,6 +435,7 @@ mod test {
     use anyhow::Result;
 
     use super::*;
+    use crate::compression::ContentEncoding;
 
     #[derive(Serialize, Clone)]
     struct Animal {
@@ -184,7 +480,7 @@ mod test {
             .body("test body".as_bytes().to_vec());
 
         let want = [
-            "POST /images/json?image=ubuntu&name=nvim HTTP/1.1",
+            "POST /images/json?name=nvim&image=ubuntu HTTP/1.1",
             "Host: localhost",
             "bar: 1000",
             "foo: value",
@@ -221,4 +517,307 @@ mod test {
         assert_eq!(got, want);
         Ok(())
     }
+
+    #[test]
+    fn with_compressed_body() -> Result<()> {
+        let body = "gorilla ".repeat(100);
+        let header: HttpHeader = [("Content-length", "800")].into_iter().collect();
+
+        let mut req = Request::new("/upload".into());
+        req.method(HttpMethod::Post)
+            .header(header)
+            .body(body.as_bytes().to_vec())
+            .compress(Compression::gzip(6));
+        let got = req.build()?;
+
+        let encoded = compression::encode(body.as_bytes(), Compression::gzip(6))?;
+        let head = [
+            "POST /upload HTTP/1.1",
+            "Host: localhost",
+            "Content-Encoding: gzip",
+            &format!("Content-Length: {}", encoded.len()),
+            "",
+            "",
+        ]
+        .join("\r\n");
+        let mut want = head.into_bytes();
+        want.extend(&encoded);
+        want.extend(b"\r\n");
+        assert_eq!(got, want);
+
+        let decoded = compression::decode(&encoded, &[ContentEncoding::Gzip], None)?;
+        assert_eq!(decoded, body.as_bytes());
+        Ok(())
+    }
+
+    #[test]
+    fn compress_error() -> Result<()> {
+        let mut req = Request::new("/upload".into());
+        req.method(HttpMethod::Post)
+            .body(b"gorilla".to_vec())
+            .compress(Compression::zstd(100));
+        let err = req.to_string().unwrap_err();
+        assert!(err.to_string().contains("cannot compress request body"));
+
+        let mut wire = Vec::new();
+        assert!(req.write_to(&mut wire, &HttpHeader::new()).is_err());
+        assert!(wire.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn with_stream_body() -> Result<()> {
+        let mut req = Request::new("/upload".into());
+        req.method(HttpMethod::Put)
+            .body_reader(io::Cursor::new(b"streamed".to_vec()), Some(8));
+
+        let mut got = Vec::new();
+        req.write_to(&mut got, &HttpHeader::new())?;
+        let want = [
+            "PUT /upload HTTP/1.1",
+            "Host: localhost",
+            "Content-Length: 8",
+            "",
+            "streamed",
+        ]
+        .join("\r\n");
+        assert_eq!(String::from_utf8(got)?, want);
+
+        // 一度送ったストリームはもう送れない
+        assert!(req.write_to(&mut Vec::new(), &HttpHeader::new()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn with_chunked_stream_body() -> Result<()> {
+        let mut req = Request::new("/upload".into());
+        req.method(HttpMethod::Post)
+            .body_reader(io::Cursor::new(b"streamed".to_vec()), None);
+
+        let mut got = Vec::new();
+        req.write_to(&mut got, &HttpHeader::new())?;
+        let want = [
+            "POST /upload HTTP/1.1",
+            "Host: localhost",
+            "Transfer-Encoding: chunked",
+            "",
+            "8",
+            "streamed",
+            "0",
+            "",
+            "",
+        ]
+        .join("\r\n");
+        assert_eq!(String::from_utf8(got)?, want);
+        Ok(())
+    }
+
+    #[test]
+    fn with_short_stream_body() {
+        let mut req = Request::new("/upload".into());
+        req.method(HttpMethod::Post)
+            .body_reader(io::Cursor::new(b"short".to_vec()), Some(10));
+        let err = req
+            .write_to(&mut Vec::new(), &HttpHeader::new())
+            .unwrap_err();
+        assert!(err.to_string().contains("5 of 10 bytes"));
+    }
+
+    #[test]
+    fn with_query() -> Result<()> {
+        #[derive(Serialize)]
+        struct Filter<'a> {
+            status: Option<&'a str>,
+            label: Vec<&'a str>,
+            limit: u32,
+        }
+
+        let mut req = Request::get("/containers/json");
+        req.query(&Filter {
+            status: None,
+            label: vec!["a=1", "b"],
+            limit: 5,
+        })
+        .query_with_style(
+            &[("id", vec!["x", "y"])]
+                .into_iter()
+                .collect::<std::collections::BTreeMap<_, _>>(),
+            ArrayStyle::Comma,
+        );
+        let want = [
+            "GET /containers/json?label=a%3D1&label=b&limit=5&id=x%2Cy HTTP/1.1",
+            "Host: localhost",
+            "",
+            "",
+        ]
+        .join("\r\n");
+        assert_eq!(req.to_string()?, want);
+        Ok(())
+    }
+
+    #[test]
+    fn with_unserializable_query() {
+        #[derive(Serialize)]
+        struct Nested {
+            inner: Animal,
+        }
+
+        let mut req = Request::get("/containers/json");
+        req.query(&"gorilla").query(&Nested {
+            inner: Animal {
+                name: "gorilla".into(),
+                age: 5,
+            },
+        });
+        // 最初のエラーを送るときに返す
+        let want = "cannot serialize query: query must be a struct or map";
+        assert_eq!(req.to_string().unwrap_err().to_string(), want);
+        let err = req
+            .write_to(&mut Vec::new(), &HttpHeader::new())
+            .unwrap_err();
+        assert_eq!(err.to_string(), want);
+    }
+
+    #[test]
+    fn with_form() -> Result<()> {
+        #[derive(Serialize)]
+        struct Login<'a> {
+            user: &'a str,
+            password: &'a str,
+        }
+
+        let mut req = Request::new("/login".into());
+        req.method(HttpMethod::Post).form(&Login {
+            user: "gorilla",
+            password: "p@ss word&",
+        });
+        let want = [
+            "POST /login HTTP/1.1",
+            "Host: localhost",
+            "Content-Length: 36",
+            "Content-Type: application/x-www-form-urlencoded",
+            "",
+            "user=gorilla&password=p%40ss+word%26",
+            "",
+        ]
+        .join("\r\n");
+        assert_eq!(req.to_string()?, want);
+        Ok(())
+    }
+
+    #[test]
+    fn with_unserializable_form() {
+        let mut req = Request::new("/login".into());
+        req.method(HttpMethod::Post).form(&"user=gorilla");
+        let err = req
+            .write_to(&mut Vec::new(), &HttpHeader::new())
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "cannot serialize form: query must be a struct or map"
+        );
+    }
+
+    #[cfg(any(feature = "msgpack", feature = "cbor", feature = "xml"))]
+    #[derive(Serialize, serde::Deserialize, Debug, PartialEq, Eq)]
+    struct Gorilla {
+        name: String,
+        tags: Vec<String>,
+    }
+
+    // ボディを Content-Type 付きで Body::decode に渡し、元に戻るか確かめる
+    #[cfg(any(feature = "msgpack", feature = "cbor", feature = "xml"))]
+    fn round_trip(req: &Request, content_type: &str) -> Result<()> {
+        let header = req.header.clone().unwrap_or_default();
+        assert_eq!(header.get("Content-Type").unwrap(), content_type);
+        let body = Body::with_header(req.body.clone().unwrap().into_bytes(), &header);
+        assert_eq!(body.decode::<Gorilla>()?, gorilla());
+
+        // 送るバイト列に Content-Length とボディが入っている
+        let mut wire = Vec::new();
+        req.write_to(&mut wire, &HttpHeader::new())?;
+        let length = format!("\r\nContent-Length: {}\r\n", body.len());
+        let end = wire.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+        assert!(String::from_utf8_lossy(&wire[..end]).contains(&length));
+        assert!(wire[end..].starts_with(body.as_bytes()));
+        Ok(())
+    }
+
+    #[cfg(any(feature = "msgpack", feature = "cbor", feature = "xml"))]
+    fn gorilla() -> Gorilla {
+        Gorilla {
+            name: "ゴリラ".into(),
+            tags: vec!["big".into(), "strong".into()],
+        }
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn with_msgpack() -> Result<()> {
+        let mut req = Request::new("/gorilla".into());
+        req.method(HttpMethod::Post).msgpack(&gorilla());
+        round_trip(&req, "application/msgpack")
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn with_cbor() -> Result<()> {
+        let mut req = Request::new("/gorilla".into());
+        req.method(HttpMethod::Post).cbor(&gorilla());
+        round_trip(&req, "application/cbor")
+    }
+
+    #[cfg(feature = "xml")]
+    #[test]
+    fn with_xml() -> Result<()> {
+        let mut req = Request::new("/gorilla".into());
+        req.method(HttpMethod::Post).xml(&gorilla());
+        assert_eq!(
+            req.body.as_ref().unwrap().text()?,
+            "<Gorilla><name>ゴリラ</name><tags>big</tags><tags>strong</tags></Gorilla>"
+        );
+        round_trip(&req, "application/xml")
+    }
+
+    #[cfg(feature = "xml")]
+    #[test]
+    fn with_unserializable_xml() {
+        let mut req = Request::new("/gorilla".into());
+        req.method(HttpMethod::Post).xml(&vec![gorilla()]);
+        let err = req
+            .write_to(&mut Vec::new(), &HttpHeader::new())
+            .unwrap_err();
+        assert!(
+            err.to_string().starts_with("cannot serialize xml: "),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn with_multipart() -> Result<()> {
+        let mut form = Multipart::new();
+        form.boundary("XYZ").text("name", "gorilla");
+        let mut req = Request::new("/upload".into());
+        req.method(HttpMethod::Post).multipart(form);
+
+        let mut got = Vec::new();
+        req.write_to(&mut got, &HttpHeader::new())?;
+        let want = [
+            "POST /upload HTTP/1.1",
+            "Host: localhost",
+            "Content-Length: 72",
+            "Content-Type: multipart/form-data; boundary=XYZ",
+            "",
+            "--XYZ",
+            "Content-Disposition: form-data; name=\"name\"",
+            "",
+            "gorilla",
+            "--XYZ--",
+            "",
+        ]
+        .join("\r\n");
+        assert_eq!(String::from_utf8(got)?, want);
+        Ok(())
+    }
 }
@@ -0,0 +1,435 @@
+use crate::client::HttpClient;
+use anyhow::{anyhow, bail, Context, Result};
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::{mpsc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub trait Resolve: Send + Sync {
+    fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>>;
+}
+
+// `[::1]` のような URL 表記の IPv6 アドレスも受け付ける
+fn strip_brackets(host: &str) -> &str {
+    host.strip_prefix('[')
+        .and_then(|h| h.strip_suffix(']'))
+        .unwrap_or(host)
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemResolver;
+
+impl Resolve for SystemResolver {
+    fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>> {
+        let addrs: Vec<SocketAddr> = (strip_brackets(host), port)
+            .to_socket_addrs()
+            .with_context(|| format!("cannot resolve {}", host))?
+            .collect();
+        if addrs.is_empty() {
+            bail!("no addresses found for {}", host);
+        }
+        Ok(addrs)
+    }
+}
+
+// curl の `--resolve host:port:addr` と同じように、特定のホスト名だけ向き先を差し替える
+pub struct OverrideResolver<R = SystemResolver> {
+    inner: R,
+    overrides: HashMap<(String, Option<u16>), Vec<IpAddr>>,
+}
+
+impl OverrideResolver<SystemResolver> {
+    pub fn new() -> Self {
+        Self::with_resolver(SystemResolver)
+    }
+}
+
+impl Default for OverrideResolver<SystemResolver> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<R: Resolve> OverrideResolver<R> {
+    pub fn with_resolver(inner: R) -> Self {
+        Self {
+            inner,
+            overrides: HashMap::new(),
+        }
+    }
+
+    // すべてのポートに対して host を addr に向ける
+    pub fn add(&mut self, host: &str, addr: IpAddr) -> &mut Self {
+        self.overrides
+            .entry((host.to_lowercase(), None))
+            .or_default()
+            .push(addr);
+        self
+    }
+
+    pub fn add_port(&mut self, host: &str, port: u16, addr: IpAddr) -> &mut Self {
+        self.overrides
+            .entry((host.to_lowercase(), Some(port)))
+            .or_default()
+            .push(addr);
+        self
+    }
+
+    // `api.internal:443:127.0.0.1` や `api.internal:443:[::1]` の形式
+    pub fn add_curl(&mut self, entry: &str) -> Result<&mut Self> {
+        let mut cols = entry.splitn(3, ':');
+        let (host, port, addr) = match (cols.next(), cols.next(), cols.next()) {
+            (Some(h), Some(p), Some(a)) => (h, p, a),
+            _ => bail!("invalid resolve entry: {}", entry),
+        };
+        let port = port
+            .parse::<u16>()
+            .with_context(|| format!("invalid port in resolve entry: {}", entry))?;
+        let addr = strip_brackets(addr)
+            .parse::<IpAddr>()
+            .with_context(|| format!("invalid address in resolve entry: {}", entry))?;
+        Ok(self.add_port(host, port, addr))
+    }
+}
+
+impl<R: Resolve> Resolve for OverrideResolver<R> {
+    fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>> {
+        let key = strip_brackets(host).to_lowercase();
+        let ips = self
+            .overrides
+            .get(&(key.clone(), Some(port)))
+            .or_else(|| self.overrides.get(&(key, None)));
+        match ips {
+            Some(ips) => Ok(ips.iter().map(|ip| SocketAddr::new(*ip, port)).collect()),
+            None => self.inner.resolve(host, port),
+        }
+    }
+}
+
+type Cache = HashMap<(String, u16), (Instant, Vec<SocketAddr>)>;
+
+// NOTE: getaddrinfo はレコードの TTL を返さないので、キャッシュの有効期限は
+// 呼び出し側が決めた上限値になる
+pub struct CachingResolver<R = SystemResolver> {
+    inner: R,
+    ttl: Duration,
+    cache: Mutex<Cache>,
+}
+
+impl<R: Resolve> CachingResolver<R> {
+    pub fn new(inner: R, ttl: Duration) -> Self {
+        Self {
+            inner,
+            ttl,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn clear(&self) {
+        self.cache.lock().unwrap().clear();
+    }
+}
+
+impl<R: Resolve> Resolve for CachingResolver<R> {
+    fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>> {
+        let key = (host.to_lowercase(), port);
+        if let Some((at, addrs)) = self.cache.lock().unwrap().get(&key) {
+            if at.elapsed() < self.ttl {
+                return Ok(addrs.clone());
+            }
+        }
+
+        // NOTE: 解決中はロックを持たないので、同じホストを同時に引くと重複して問い合わせることがある
+        let addrs = self.inner.resolve(host, port)?;
+        self.cache
+            .lock()
+            .unwrap()
+            .insert(key, (Instant::now(), addrs.clone()));
+        Ok(addrs)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IpPreference {
+    // リゾルバが返した順番のまま
+    #[default]
+    System,
+    Ipv4First,
+    Ipv6First,
+    Ipv4Only,
+    Ipv6Only,
+}
+
+// RFC 8305 Section 4 のように、優先するファミリから始めて IPv6 と IPv4 を交互に並べる
+pub fn sort_addrs(addrs: &[SocketAddr], preference: IpPreference) -> Vec<SocketAddr> {
+    let (v6, v4): (Vec<SocketAddr>, Vec<SocketAddr>) = addrs.iter().partition(|a| a.is_ipv6());
+    let (first, second) = match preference {
+        IpPreference::Ipv4Only => return v4,
+        IpPreference::Ipv6Only => return v6,
+        IpPreference::Ipv4First => (v4, v6),
+        IpPreference::Ipv6First => (v6, v4),
+        IpPreference::System => match addrs.first() {
+            Some(a) if a.is_ipv4() => (v4, v6),
+            _ => (v6, v4),
+        },
+    };
+
+    let mut sorted = Vec::with_capacity(addrs.len());
+    let mut first = first.into_iter();
+    let mut second = second.into_iter();
+    loop {
+        match (first.next(), second.next()) {
+            (None, None) => break,
+            (a, b) => sorted.extend(a.into_iter().chain(b)),
+        }
+    }
+    sorted
+}
+
+#[derive(Debug, Clone)]
+pub struct HappyEyeballs {
+    pub preference: IpPreference,
+    // 前の接続試行の完了を待たずに次のアドレスへの接続を始めるまでの時間
+    pub attempt_delay: Duration,
+    pub connect_timeout: Option<Duration>,
+}
+
+impl Default for HappyEyeballs {
+    fn default() -> Self {
+        Self {
+            preference: IpPreference::default(),
+            attempt_delay: Duration::from_millis(250),
+            connect_timeout: None,
+        }
+    }
+}
+
+impl HappyEyeballs {
+    pub fn connect(&self, addrs: &[SocketAddr]) -> Result<TcpStream> {
+        let timeout = self.connect_timeout;
+        self.connect_with(addrs, move |addr| match timeout {
+            Some(t) => TcpStream::connect_timeout(&addr, t),
+            None => TcpStream::connect(addr),
+        })
+    }
+
+    // dial をアドレスごとに別スレッドで attempt_delay ずつずらして実行し、
+    // 最初に成功したコネクションを返す
+    pub fn connect_with<S, F>(&self, addrs: &[SocketAddr], dial: F) -> Result<S>
+    where
+        S: Send + 'static,
+        F: Fn(SocketAddr) -> io::Result<S> + Send + Sync + Clone + 'static,
+    {
+        let addrs = sort_addrs(addrs, self.preference);
+        if addrs.is_empty() {
+            bail!("no addresses to connect");
+        }
+
+        let (tx, rx) = mpsc::channel();
+        let mut pending = addrs.iter();
+        let mut running = 0;
+        let mut last_err = None;
+
+        let start = |addr: SocketAddr| {
+            let tx = tx.clone();
+            let dial = dial.clone();
+            thread::spawn(move || {
+                // NOTE: 受信側がもう居ない (他の試行が勝った) 場合はそのままコネクションを捨てる
+                let _ = tx.send((addr, dial(addr)));
+            });
+        };
+
+        start(*pending.next().unwrap());
+        running += 1;
+
+        while running > 0 {
+            match rx.recv_timeout(self.attempt_delay) {
+                Ok((_, Ok(conn))) => return Ok(conn),
+                Ok((addr, Err(e))) => {
+                    running -= 1;
+                    last_err = Some(anyhow!("cannot connect to {}: {}", addr, e));
+                    // 失敗したらすぐ次のアドレスを試す
+                    if let Some(addr) = pending.next() {
+                        start(*addr);
+                        running += 1;
+                    }
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    if let Some(addr) = pending.next() {
+                        start(*addr);
+                        running += 1;
+                    }
+                }
+                Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            }
+        }
+
+        Err(last_err.unwrap_or_else(|| anyhow!("cannot connect")))
+    }
+}
+
+impl HttpClient<TcpStream> {
+    pub fn connect(host: &str, port: u16) -> Result<Self> {
+        Self::connect_with(&SystemResolver, host, port)
+    }
+
+    pub fn connect_with<R: Resolve>(resolver: &R, host: &str, port: u16) -> Result<Self> {
+        let addrs = resolver.resolve(host, port)?;
+        let mut client = Self::new(HappyEyeballs::default().connect(&addrs)?);
+        client.read_timeout_fn(TcpStream::read_timeout, TcpStream::set_read_timeout);
+        Ok(client)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::request::Request;
+    use httptest::{matchers::*, responders::*, Expectation, ServerBuilder};
+    use std::net::{Ipv4Addr, Ipv6Addr, TcpListener};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    struct Counting(Arc<AtomicUsize>);
+
+    impl Resolve for Counting {
+        fn resolve(&self, _host: &str, port: u16) -> Result<Vec<SocketAddr>> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(vec![SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port)])
+        }
+    }
+
+    fn v4(last: u8) -> SocketAddr {
+        SocketAddr::new(Ipv4Addr::new(10, 0, 0, last).into(), 80)
+    }
+
+    fn v6(last: u16) -> SocketAddr {
+        SocketAddr::new(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, last).into(), 80)
+    }
+
+    #[test]
+    fn override_host() -> Result<()> {
+        let addr: SocketAddr = ([127, 0, 0, 1], 0).into();
+        let server = ServerBuilder::new().bind_addr(addr).run()?;
+        server.expect(
+            Expectation::matching(request::method_path("GET", "/hello"))
+                .respond_with(status_code(200).body("hello")),
+        );
+
+        let mut resolver = OverrideResolver::new();
+        resolver.add("api.internal", Ipv4Addr::LOCALHOST.into());
+        assert_eq!(
+            resolver.resolve("API.internal", 8080)?,
+            vec![SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 8080)]
+        );
+
+        let mut client = HttpClient::connect_with(&resolver, "api.internal", server.addr().port())?;
+        let resp = client.execute_request(&Request::get("/hello"))?;
+        assert_eq!(resp.body.unwrap().text()?, "hello");
+        Ok(())
+    }
+
+    #[test]
+    fn override_curl_style() -> Result<()> {
+        let mut resolver = OverrideResolver::with_resolver(Counting(Default::default()));
+        resolver
+            .add_curl("api.internal:443:[::1]")?
+            .add("api.internal", Ipv4Addr::new(10, 0, 0, 1).into());
+
+        assert_eq!(
+            resolver.resolve("api.internal", 443)?,
+            vec![SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 443)]
+        );
+        assert_eq!(resolver.resolve("api.internal", 80)?, vec![v4(1)]);
+        assert!(resolver.add_curl("api.internal:443").is_err());
+        assert!(resolver.add_curl("api.internal:https:127.0.0.1").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn system_resolver_literal() -> Result<()> {
+        let addrs = SystemResolver.resolve("[::1]", 80)?;
+        assert_eq!(addrs, vec![SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 80)]);
+        Ok(())
+    }
+
+    #[test]
+    fn cache_until_ttl() -> Result<()> {
+        let count = Arc::new(AtomicUsize::new(0));
+        let resolver = CachingResolver::new(Counting(count.clone()), Duration::from_millis(100));
+
+        resolver.resolve("example.com", 80)?;
+        resolver.resolve("EXAMPLE.com", 80)?;
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+
+        // ポートが違えば別のエントリ
+        resolver.resolve("example.com", 443)?;
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+
+        thread::sleep(Duration::from_millis(150));
+        resolver.resolve("example.com", 80)?;
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+
+        resolver.clear();
+        resolver.resolve("example.com", 443)?;
+        assert_eq!(count.load(Ordering::SeqCst), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn interleave_families() {
+        let addrs = vec![v6(1), v6(2), v6(3), v4(1), v4(2)];
+        assert_eq!(
+            sort_addrs(&addrs, IpPreference::System),
+            vec![v6(1), v4(1), v6(2), v4(2), v6(3)]
+        );
+        assert_eq!(
+            sort_addrs(&addrs, IpPreference::Ipv4First),
+            vec![v4(1), v6(1), v4(2), v6(2), v6(3)]
+        );
+        assert_eq!(
+            sort_addrs(&addrs, IpPreference::Ipv4Only),
+            vec![v4(1), v4(2)]
+        );
+        assert_eq!(
+            sort_addrs(&addrs, IpPreference::Ipv6Only),
+            vec![v6(1), v6(2), v6(3)]
+        );
+    }
+
+    #[test]
+    fn fallback_to_next_address() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let good = listener.local_addr()?;
+        // 一度 bind して閉じたポートには接続できない
+        let refused = TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+
+        let conn = HappyEyeballs::default().connect(&[refused, good])?;
+        assert_eq!(conn.peer_addr()?, good);
+
+        let err = HappyEyeballs::default().connect(&[refused]).unwrap_err();
+        assert!(err.to_string().contains(&refused.to_string()));
+        Ok(())
+    }
+
+    #[test]
+    fn race_slow_address() -> Result<()> {
+        let he = HappyEyeballs {
+            attempt_delay: Duration::from_millis(50),
+            ..Default::default()
+        };
+
+        let started = Instant::now();
+        let winner = he.connect_with(&[v6(1), v4(1)], |addr| {
+            if addr.is_ipv6() {
+                thread::sleep(Duration::from_secs(2));
+            }
+            Ok(addr)
+        })?;
+        assert_eq!(winner, v4(1));
+        assert!(started.elapsed() < Duration::from_secs(1));
+        Ok(())
+    }
+}
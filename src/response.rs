@@ -1,9 +1,137 @@
-use crate::body::Body;
+use crate::body::{Body, BodyReader, DecodeError};
 use crate::header::*;
+use crate::multipart::{self, BodyPart};
+use crate::version::HttpVersion;
+use anyhow::{anyhow, Result};
+use serde::de::DeserializeOwned;
 
 #[derive(Debug, Clone)]
 pub struct Response {
+    pub version: HttpVersion,
     pub status: u32,
     pub header: HttpHeader,
     pub body: Option<Body>,
 }
+
+impl Response {
+    // このレスポンスのあとも同じコネクションを使えるか。
+    // HTTP/1.1 は Connection: close が無ければ、HTTP/1.0 は Connection: keep-alive があれば使える
+    pub fn keep_alive(&self) -> bool {
+        let connection = self.header.get("connection");
+        let has = |token: &str| {
+            connection
+                .map(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+                .unwrap_or(false)
+        };
+        match self.version {
+            HttpVersion::Http10 => has("keep-alive") && !has("close"),
+            _ => !has("close"),
+        }
+    }
+
+    // Body::json と同じだが、DecodeError にステータスも入れる。ボディが無ければ空のボディとしてデコードする
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T> {
+        let result = match &self.body {
+            Some(body) => body.json(),
+            None => Body::with_header(Vec::new(), &self.header).json(),
+        };
+        result.map_err(|e| match e.downcast::<DecodeError>() {
+            Ok(mut e) => {
+                e.status = Some(self.status);
+                e.into()
+            }
+            Err(e) => e,
+        })
+    }
+
+    // multipart/mixed や multipart/byteranges のボディをパートに分ける
+    pub fn parts(&self) -> Result<Vec<BodyPart>> {
+        let content_type = self
+            .header
+            .get("content-type")
+            .ok_or_else(|| anyhow!("content-type is missing"))?;
+        let body = self.body.as_ref().map(|b| b.as_bytes()).unwrap_or_default();
+        multipart::parse(content_type, body)
+    }
+}
+
+pub struct StreamResponse<'a> {
+    pub version: HttpVersion,
+    pub status: u32,
+    pub header: HttpHeader,
+    pub body: BodyReader<'a>,
+}
+
+impl StreamResponse<'_> {
+    // ボディを最後まで読んで Response にする。
+    // NOTE: BodyReader::into_body と違い、Content-Type を Body に渡すので text や decode が charset を使える
+    pub fn into_response(self) -> Result<Response> {
+        let body = self.body.into_body()?;
+        let body = match body.is_empty() {
+            true => None,
+            false => Some(Body::with_header(body.into_bytes(), &self.header)),
+        };
+        Ok(Response {
+            version: self.version,
+            status: self.status,
+            header: self.header,
+            body,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn response(version: HttpVersion, connection: Option<&str>) -> Response {
+        let mut header = HttpHeader::new();
+        if let Some(v) = connection {
+            header.add("connection", v);
+        }
+        Response {
+            version,
+            status: 200,
+            header,
+            body: None,
+        }
+    }
+
+    #[test]
+    fn json_error_with_status() {
+        let mut resp = response(HttpVersion::Http11, None);
+        resp.status = 502;
+        resp.body = Some(Body::new("Bad Gateway"));
+        let err = resp.json::<serde_json::Value>().unwrap_err();
+        let err = err.downcast_ref::<DecodeError>().unwrap();
+        assert_eq!(err.status, Some(502));
+        assert_eq!(err.excerpt, "Bad Gateway");
+        assert!(err.to_string().contains(", status: 502, body: "), "{}", err);
+    }
+
+    #[test]
+    fn stream_into_response() -> Result<()> {
+        let (page, _, _) = encoding_rs::SHIFT_JIS.encode("ゴリラ");
+        let page = page.into_owned();
+        let resp = StreamResponse {
+            version: HttpVersion::Http11,
+            status: 200,
+            header: [("Content-Type", "text/plain; charset=Shift_JIS")]
+                .into_iter()
+                .collect(),
+            body: BodyReader::new(&page[..]),
+        };
+        let resp = resp.into_response()?;
+        assert_eq!(resp.body.unwrap().text()?, "ゴリラ");
+        Ok(())
+    }
+
+    #[test]
+    fn keep_alive() {
+        assert!(response(HttpVersion::Http11, None).keep_alive());
+        assert!(!response(HttpVersion::Http11, Some("Close")).keep_alive());
+        assert!(!response(HttpVersion::Http10, None).keep_alive());
+        assert!(response(HttpVersion::Http10, Some("Keep-Alive")).keep_alive());
+        assert!(!response(HttpVersion::Http10, Some("keep-alive, close")).keep_alive());
+    }
+}
@@ -0,0 +1,476 @@
+use crate::body::BodyReader;
+use crate::client::{HttpClient, ReadWriter};
+use crate::request::Request;
+use anyhow::{anyhow, bail, Result};
+use serde::de::Deserialize;
+use std::io::BufRead;
+use std::time::Duration;
+
+const DEFAULT_RETRY: Duration = Duration::from_secs(3);
+
+// text/event-stream で届いた 1 つのイベント
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    // event フィールドが無ければ "message"
+    pub event: String,
+    pub data: String,
+    // 最後に受け取った id。以降のイベントにも引き継がれる
+    pub id: Option<String>,
+}
+
+impl Event {
+    pub fn json<T: for<'b> Deserialize<'b>>(&self) -> Result<T> {
+        serde_json::from_str(&self.data).map_err(|x| anyhow!("{}", x))
+    }
+}
+
+// HTML Living Standard 9.2.6 に従って text/event-stream をパースする
+pub struct EventStream<R> {
+    inner: R,
+    line: Vec<u8>,
+    // 直前の行が CR で終わっていたら、次の LF は CRLF の一部として読み飛ばす
+    after_cr: bool,
+    first_line: bool,
+    event: String,
+    data: String,
+    has_data: bool,
+    last_event_id: Option<String>,
+    retry: Option<Duration>,
+}
+
+impl<R: BufRead> EventStream<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            line: Vec::new(),
+            after_cr: false,
+            first_line: true,
+            event: String::new(),
+            data: String::new(),
+            has_data: false,
+            last_event_id: None,
+            retry: None,
+        }
+    }
+
+    pub fn last_event_id(&self) -> Option<&str> {
+        self.last_event_id.as_deref()
+    }
+
+    // サーバーが retry フィールドで指定した再接続までの待ち時間
+    pub fn retry(&self) -> Option<Duration> {
+        self.retry
+    }
+
+    // 行末 (CRLF, LF, CR のいずれか) まで読む。ストリームが終わったら false を返す
+    fn read_line(&mut self) -> Result<bool> {
+        self.line.clear();
+        loop {
+            let buf = self.inner.fill_buf()?;
+            if buf.is_empty() {
+                // NOTE: 行末の無い最後の行は不完全なので捨てる
+                return Ok(false);
+            }
+            if self.after_cr {
+                self.after_cr = false;
+                if buf[0] == b'\n' {
+                    self.inner.consume(1);
+                    continue;
+                }
+            }
+            match buf.iter().position(|&b| b == b'\n' || b == b'\r') {
+                Some(i) => {
+                    self.after_cr = buf[i] == b'\r';
+                    self.line.extend_from_slice(&buf[..i]);
+                    self.inner.consume(i + 1);
+                    break;
+                }
+                None => {
+                    let n = buf.len();
+                    self.line.extend_from_slice(buf);
+                    self.inner.consume(n);
+                }
+            }
+        }
+        if self.first_line {
+            self.first_line = false;
+            if self.line.starts_with(b"\xEF\xBB\xBF") {
+                self.line.drain(..3);
+            }
+        }
+        Ok(true)
+    }
+
+    fn process_field(&mut self, field: &str, value: &str) {
+        match field {
+            "event" => self.event = value.into(),
+            "data" => {
+                if self.has_data {
+                    self.data.push('\n');
+                }
+                self.data.push_str(value);
+                self.has_data = true;
+            }
+            // NOTE: NULL を含む id は無視する。空の id は last event ID を消す
+            "id" if value.is_empty() => self.last_event_id = None,
+            "id" if !value.contains('\0') => self.last_event_id = Some(value.into()),
+            "retry" if !value.is_empty() && value.bytes().all(|b| b.is_ascii_digit()) => {
+                if let Ok(ms) = value.parse() {
+                    self.retry = Some(Duration::from_millis(ms));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // 次のイベントを返す。ストリームが終わったら None
+    pub fn next_event(&mut self) -> Result<Option<Event>> {
+        while self.read_line()? {
+            let line = String::from_utf8_lossy(&self.line).into_owned();
+            if line.is_empty() {
+                let event = std::mem::take(&mut self.event);
+                if !std::mem::take(&mut self.has_data) {
+                    continue;
+                }
+                return Ok(Some(Event {
+                    event: if event.is_empty() {
+                        "message".into()
+                    } else {
+                        event
+                    },
+                    data: std::mem::take(&mut self.data),
+                    id: self.last_event_id.clone(),
+                }));
+            }
+            // コロンで始まる行はコメント (keep-alive など) なので読み飛ばす
+            if line.starts_with(':') {
+                continue;
+            }
+            match line.split_once(':') {
+                Some((field, value)) => {
+                    let value = value.strip_prefix(' ').unwrap_or(value);
+                    self.process_field(field, value);
+                }
+                None => self.process_field(&line, ""),
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl<R: BufRead> Iterator for EventStream<R> {
+    type Item = Result<Event>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_event().transpose()
+    }
+}
+
+// EventSource のように、ストリームが切れたら Last-Event-ID を付けて再接続する。
+// connect は再接続のたびに呼ばれ、新しいコネクションの HttpClient を返す
+pub struct EventSource<T: ReadWriter, F> {
+    connect: F,
+    req: Request,
+    stream: Option<EventStream<BodyReader<'static>>>,
+    last_event_id: Option<String>,
+    retry: Duration,
+    max_retries: Option<u32>,
+    failures: u32,
+    closed: bool,
+    _conn: std::marker::PhantomData<fn() -> T>,
+}
+
+impl<T, F> EventSource<T, F>
+where
+    T: ReadWriter + 'static,
+    F: FnMut() -> Result<HttpClient<T>>,
+{
+    pub fn new(req: Request, connect: F) -> Self {
+        Self {
+            connect,
+            req,
+            stream: None,
+            last_event_id: None,
+            retry: DEFAULT_RETRY,
+            max_retries: None,
+            failures: 0,
+            closed: false,
+            _conn: std::marker::PhantomData,
+        }
+    }
+
+    // 再接続までの待ち時間。サーバーが retry フィールドを送ればそちらで上書きされる
+    pub fn retry(&mut self, p: Duration) -> &mut Self {
+        self.retry = p;
+        self
+    }
+
+    // イベントを受け取れないまま続けて再接続に失敗できる回数。None なら諦めない
+    pub fn max_retries(&mut self, p: Option<u32>) -> &mut Self {
+        self.max_retries = p;
+        self
+    }
+
+    pub fn last_event_id(&self) -> Option<&str> {
+        self.last_event_id.as_deref()
+    }
+
+    fn open(&mut self) -> Result<Option<EventStream<BodyReader<'static>>>> {
+        let mut header = self.req.header.clone().unwrap_or_default();
+        for key in ["Accept", "Cache-Control", "Last-Event-ID"] {
+            header.remove_ignore_case(key);
+        }
+        header.add("Accept", "text/event-stream");
+        header.add("Cache-Control", "no-cache");
+        if let Some(id) = &self.last_event_id {
+            header.add("Last-Event-ID", id);
+        }
+        self.req.header(header);
+
+        let resp = (self.connect)()?.into_stream(&self.req)?;
+        match resp.status {
+            200 => {}
+            // 204 はサーバーが再接続してほしくないという合図
+            204 => return Ok(None),
+            // NOTE: ステータスや Content-Type が違うのはサーバーの設定の問題なので再接続しない
+            status => {
+                self.closed = true;
+                bail!("unexpected status for event stream: {}", status)
+            }
+        }
+        let content_type = resp
+            .header
+            .get("content-type")
+            .map(|v| v.as_str())
+            .unwrap_or_default();
+        let mime = content_type.split(';').next().unwrap_or_default().trim();
+        if !mime.eq_ignore_ascii_case("text/event-stream") {
+            self.closed = true;
+            bail!(
+                "unexpected content-type for event stream: {:?}",
+                content_type
+            );
+        }
+        // NOTE: 最後の id は再接続しても引き継ぐ
+        let mut stream = EventStream::new(resp.body);
+        stream.last_event_id = self.last_event_id.clone();
+        Ok(Some(stream))
+    }
+
+    // 次のイベントを返す。サーバーが 204 を返して終わりを告げたら None
+    pub fn next_event(&mut self) -> Result<Option<Event>> {
+        loop {
+            if self.closed {
+                return Ok(None);
+            }
+            let result = match self.stream.as_mut() {
+                Some(stream) => {
+                    let result = stream.next_event();
+                    if let Some(retry) = stream.retry() {
+                        self.retry = retry;
+                    }
+                    // NOTE: stream は self.last_event_id から始めているので、消された場合もそのまま写す
+                    self.last_event_id = stream.last_event_id().map(|id| id.into());
+                    match result {
+                        Ok(Some(event)) => {
+                            self.failures = 0;
+                            return Ok(Some(event));
+                        }
+                        Ok(None) => Ok(()),
+                        Err(e) => Err(e),
+                    }
+                }
+                None => match self.open() {
+                    Ok(Some(stream)) => {
+                        self.stream = Some(stream);
+                        continue;
+                    }
+                    Ok(None) => {
+                        self.closed = true;
+                        return Ok(None);
+                    }
+                    Err(e) => Err(e),
+                },
+            };
+
+            // ストリームが切れたので、待ってから再接続する
+            self.stream = None;
+            if let Err(e) = result {
+                self.failures += 1;
+                if self.closed || self.max_retries.is_some_and(|max| self.failures > max) {
+                    self.closed = true;
+                    return Err(e);
+                }
+            }
+            std::thread::sleep(self.retry);
+        }
+    }
+}
+
+impl<T, F> Iterator for EventSource<T, F>
+where
+    T: ReadWriter + 'static,
+    F: FnMut() -> Result<HttpClient<T>>,
+{
+    type Item = Result<Event>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_event().transpose()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde::Deserialize;
+    use std::io::{BufReader, Write};
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    use std::sync::mpsc;
+
+    fn parse(data: &[u8]) -> Result<Vec<Event>> {
+        EventStream::new(data).collect()
+    }
+
+    fn event(event: &str, data: &str, id: Option<&str>) -> Event {
+        Event {
+            event: event.into(),
+            data: data.into(),
+            id: id.map(|v| v.into()),
+        }
+    }
+
+    #[test]
+    fn parse_fields() -> Result<()> {
+        let data = b"\xEF\xBB\xBF: keep-alive\n\
+                     data: first\ndata:second\n\n\
+                     event: update\rid: 42\rdata\r\r\
+                     id\r\ndata:  spaced\r\n\r\n\
+                     data: incomplete";
+        let want = vec![
+            event("message", "first\nsecond", None),
+            event("update", "", Some("42")),
+            event("message", " spaced", None),
+        ];
+        assert_eq!(parse(data)?, want);
+        Ok(())
+    }
+
+    #[test]
+    fn parse_retry_and_empty_events() -> Result<()> {
+        let mut stream =
+            EventStream::new(&b"retry: 1500\nevent: ping\n\nretry: soon\ndata: x\n\n"[..]);
+        assert_eq!(stream.next_event()?, Some(event("message", "x", None)));
+        assert_eq!(stream.retry(), Some(Duration::from_millis(1500)));
+        assert_eq!(stream.next_event()?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn event_json() -> Result<()> {
+        #[derive(Deserialize, Debug, PartialEq)]
+        struct Update {
+            count: u32,
+        }
+        let events = parse(b"data: {\"count\":\ndata: 3}\n\n")?;
+        assert_eq!(events[0].json::<Update>()?, Update { count: 3 });
+        Ok(())
+    }
+
+    // 1 回目は id: 1 のイベントを送って閉じ、2 回目は Last-Event-ID を確認して
+    // イベントを送って閉じ、3 回目は 204 で終わりを告げる
+    fn sse_server(tx: mpsc::Sender<String>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let bodies = [
+                Some("retry: 10\nid: 1\ndata: a\n\n"),
+                Some(": comment\ndata: b\n\n"),
+                None,
+            ];
+            for body in bodies {
+                let (conn, _) = listener.accept().unwrap();
+                let mut r = BufReader::new(conn.try_clone().unwrap());
+                let mut w = conn;
+                let mut head = String::new();
+                loop {
+                    let mut line = String::new();
+                    r.read_line(&mut line).unwrap();
+                    head.push_str(&line);
+                    if line == "\r\n" {
+                        break;
+                    }
+                }
+                tx.send(head).unwrap();
+                match body {
+                    Some(body) => write!(
+                        w,
+                        "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nConnection: close\r\n\r\n{}",
+                        body
+                    )
+                    .unwrap(),
+                    None => w
+                        .write_all(b"HTTP/1.1 204 No Content\r\nContent-Length: 0\r\n\r\n")
+                        .unwrap(),
+                }
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn event_source_reconnect() -> Result<()> {
+        let (tx, rx) = mpsc::channel();
+        let addr = sse_server(tx);
+        let mut source = EventSource::new(Request::get("/events"), move || {
+            Ok(HttpClient::new(TcpStream::connect(addr)?))
+        });
+        source.max_retries(Some(0));
+
+        let events: Vec<Event> = source.by_ref().collect::<Result<_>>()?;
+        assert_eq!(
+            events,
+            vec![
+                event("message", "a", Some("1")),
+                event("message", "b", Some("1")),
+            ]
+        );
+        assert_eq!(source.last_event_id(), Some("1"));
+
+        let heads: Vec<String> = rx.iter().collect();
+        assert_eq!(heads.len(), 3);
+        assert!(heads[0].contains("Accept: text/event-stream\r\n"));
+        assert!(!heads[0].contains("Last-Event-ID"));
+        assert!(heads[1].contains("Last-Event-ID: 1\r\n"));
+        assert!(heads[2].contains("Last-Event-ID: 1\r\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn event_source_wrong_content_type() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        std::thread::spawn(move || {
+            let (conn, _) = listener.accept().unwrap();
+            let mut r = BufReader::new(conn.try_clone().unwrap());
+            let mut w = conn;
+            let mut line = String::new();
+            while r.read_line(&mut line).unwrap() > 0 && line != "\r\n" {
+                line.clear();
+            }
+            w.write_all(
+                b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 2\r\n\r\nhi",
+            )
+            .unwrap();
+        });
+        let mut source = EventSource::new(Request::get("/events"), move || {
+            Ok(HttpClient::new(TcpStream::connect(addr)?))
+        });
+        let err = source.next().unwrap().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "unexpected content-type for event stream: \"text/plain\""
+        );
+        assert!(source.next().is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,120 @@
+use crate::client::HttpClient;
+use crate::connector::UnixConnector;
+use anyhow::Result;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+
+pub const DOCKER_SOCKET: &str = "/var/run/docker.sock";
+
+// NOTE: `/var/run/docker.sock` と `unix:///var/run/docker.sock` のどちらも受け付ける
+pub fn socket_path<P: AsRef<Path>>(path: P) -> PathBuf {
+    let path = path.as_ref();
+    match path.to_str().and_then(|p| p.strip_prefix("unix://")) {
+        Some(p) => PathBuf::from(p),
+        None => path.to_path_buf(),
+    }
+}
+
+impl HttpClient<UnixStream> {
+    // Host header は Request::base_url が未指定なら "localhost" になるので
+    // Docker などのローカルデーモンにはそのまま使える
+    pub fn unix<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::from_connector(&UnixConnector::new(path), "localhost", 0)
+    }
+
+    pub fn docker() -> Result<Self> {
+        match std::env::var("DOCKER_HOST") {
+            Ok(host) if host.starts_with("unix://") => Self::unix(host),
+            _ => Self::unix(DOCKER_SOCKET),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::request::Request;
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::net::UnixListener;
+    use std::thread;
+
+    fn socket(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("http_client-{}-{}.sock", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    // リクエストを受け取るたびに responses を順番に返すモックデーモン
+    fn serve(
+        listener: UnixListener,
+        responses: Vec<&'static str>,
+    ) -> thread::JoinHandle<Vec<String>> {
+        thread::spawn(move || {
+            let (conn, _) = listener.accept().unwrap();
+            let mut r = BufReader::new(conn.try_clone().unwrap());
+            let mut w = conn;
+            let mut requests = Vec::new();
+            for resp in responses {
+                let mut head = String::new();
+                loop {
+                    let mut line = String::new();
+                    r.read_line(&mut line).unwrap();
+                    if line == "\r\n" || line.is_empty() {
+                        break;
+                    }
+                    head.push_str(&line);
+                }
+                requests.push(head);
+                w.write_all(resp.as_bytes()).unwrap();
+            }
+            requests
+        })
+    }
+
+    #[test]
+    fn socket_path_from_url() {
+        assert_eq!(
+            socket_path("unix:///var/run/docker.sock"),
+            PathBuf::from(DOCKER_SOCKET)
+        );
+        assert_eq!(socket_path(DOCKER_SOCKET), PathBuf::from(DOCKER_SOCKET));
+    }
+
+    #[test]
+    fn request_over_unix_socket() -> Result<()> {
+        let path = socket("request");
+        let listener = UnixListener::bind(&path)?;
+        let server = serve(
+            listener,
+            vec![
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 2\r\n\r\n[]",
+                "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nOK",
+            ],
+        );
+
+        let url = format!("unix://{}", path.display());
+        let mut client = HttpClient::unix(url)?;
+
+        let resp = client.execute_request(&Request::get("/images/json"))?;
+        assert_eq!(resp.status, 200);
+        assert_eq!(resp.body.unwrap().text()?, "[]");
+
+        // 同じコネクションを使い回せること
+        let resp = client.execute_request(&Request::get("/_ping"))?;
+        assert_eq!(resp.body.unwrap().text()?, "OK");
+
+        let requests = server.join().unwrap();
+        assert!(requests[0].starts_with("GET /images/json HTTP/1.1\r\nHost: localhost\r\n"));
+        assert!(requests[1].starts_with("GET /_ping HTTP/1.1\r\n"));
+
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn connect_to_missing_socket() {
+        let path = socket("missing");
+        assert!(HttpClient::unix(path).is_err());
+    }
+}
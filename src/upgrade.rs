@@ -0,0 +1,56 @@
+use crate::header::HttpHeader;
+use std::io::{self, BufRead, BufReader, Read, Write};
+
+// 101 Switching Protocols のあとのコネクション。
+// レスポンスと一緒に先読みしてしまったバイトも、読み出すと最初に返ってくる
+pub struct Upgraded<T> {
+    conn: BufReader<T>,
+    header: HttpHeader,
+}
+
+impl<T: Read + Write> Upgraded<T> {
+    pub(crate) fn new(conn: BufReader<T>, header: HttpHeader) -> Self {
+        Self { conn, header }
+    }
+
+    // 101 レスポンスのヘッダ
+    pub fn header(&self) -> &HttpHeader {
+        &self.header
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.conn.get_mut()
+    }
+
+    // 生のコネクションと、先読み済みでまだ読まれていないバイト列を返す
+    pub fn into_parts(self) -> (T, Vec<u8>) {
+        let buffered = self.conn.buffer().to_vec();
+        (self.conn.into_inner(), buffered)
+    }
+}
+
+impl<T: Read + Write> Read for Upgraded<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.conn.read(buf)
+    }
+}
+
+impl<T: Read + Write> BufRead for Upgraded<T> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.conn.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.conn.consume(amt)
+    }
+}
+
+impl<T: Read + Write> Write for Upgraded<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.conn.get_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.conn.get_mut().flush()
+    }
+}
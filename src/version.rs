@@ -0,0 +1,32 @@
+use anyhow::{bail, Result};
+use std::fmt::Display;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Default)]
+pub enum HttpVersion {
+    Http10,
+    #[default]
+    Http11,
+    Http2,
+}
+
+impl Display for HttpVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let version = match self {
+            Self::Http10 => "HTTP/1.0",
+            Self::Http11 => "HTTP/1.1",
+            Self::Http2 => "HTTP/2",
+        };
+        write!(f, "{}", version)
+    }
+}
+
+impl HttpVersion {
+    // HTTP/1.x のステータス行の先頭の HTTP-version をパースする
+    pub fn parse(value: &str) -> Result<Self> {
+        match value {
+            "HTTP/1.0" => Ok(Self::Http10),
+            "HTTP/1.1" => Ok(Self::Http11),
+            _ => bail!("unsupported http version: {}", value),
+        }
+    }
+}
@@ -0,0 +1,420 @@
+use crate::client::{HttpClient, ReadWriter};
+use crate::header::HttpHeader;
+use crate::request::Request;
+use crate::upgrade::Upgraded;
+use anyhow::{anyhow, bail, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use sha1::{Digest, Sha1};
+use std::io::{Read, Write};
+
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xa;
+
+// RFC 6455 7.4.1
+pub const NORMAL_CLOSURE: u16 = 1000;
+pub const PROTOCOL_ERROR: u16 = 1002;
+pub const INVALID_PAYLOAD: u16 = 1007;
+pub const MESSAGE_TOO_BIG: u16 = 1009;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CloseFrame {
+    pub code: u16,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    Close(Option<CloseFrame>),
+}
+
+// Sec-WebSocket-Key に対して返されるべき Sec-WebSocket-Accept
+pub fn accept_key(key: &str) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.update(key.as_bytes());
+    sha1.update(GUID.as_bytes());
+    STANDARD.encode(sha1.finalize())
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+pub struct WebSocket<T: ReadWriter> {
+    conn: Upgraded<T>,
+    protocol: Option<String>,
+    max_message_size: usize,
+    fragment_size: Option<usize>,
+    // 受信途中の分割されたメッセージ
+    fragments: Option<(u8, Vec<u8>)>,
+    close_sent: bool,
+    close_received: bool,
+}
+
+impl<T: ReadWriter> WebSocket<T> {
+    // HttpClient のコネクションでオープニングハンドシェイクを行う。
+    // protocols が空でなければ Sec-WebSocket-Protocol でサブプロトコルを提示する
+    pub fn connect(client: HttpClient<T>, req: &Request, protocols: &[&str]) -> Result<Self> {
+        let key = STANDARD.encode(rand::random::<[u8; 16]>());
+        let mut header: HttpHeader = [
+            ("Sec-WebSocket-Key", key.as_str()),
+            ("Sec-WebSocket-Version", "13"),
+        ]
+        .into_iter()
+        .collect();
+        if !protocols.is_empty() {
+            header.add("Sec-WebSocket-Protocol", &protocols.join(", "));
+        }
+
+        let conn = client.upgrade_with_header(req, "websocket", header)?;
+
+        let accept = conn.header().get("sec-websocket-accept");
+        if accept.map(|v| v.as_str()) != Some(accept_key(&key).as_str()) {
+            bail!("invalid Sec-WebSocket-Accept: {:?}", accept);
+        }
+        let protocol = conn.header().get("sec-websocket-protocol").cloned();
+        if let Some(p) = &protocol {
+            if !protocols.contains(&p.as_str()) {
+                bail!("server selected a subprotocol that was not offered: {}", p);
+            }
+        }
+
+        Ok(Self {
+            conn,
+            protocol,
+            max_message_size: 64 << 20,
+            fragment_size: None,
+            fragments: None,
+            close_sent: false,
+            close_received: false,
+        })
+    }
+
+    // サーバーが選んだサブプロトコル
+    pub fn protocol(&self) -> Option<&str> {
+        self.protocol.as_deref()
+    }
+
+    // 受信するメッセージ (分割されている場合は合計) の上限
+    pub fn max_message_size(&mut self, p: usize) -> &mut Self {
+        self.max_message_size = p;
+        self
+    }
+
+    // Text や Binary を送るときに、p バイトごとのフレームに分割する
+    pub fn fragment_size(&mut self, p: Option<usize>) -> &mut Self {
+        self.fragment_size = p;
+        self
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.conn.get_mut()
+    }
+
+    fn write_frame(&mut self, fin: bool, opcode: u8, payload: &[u8]) -> Result<()> {
+        let mut buf = vec![if fin { 0x80 } else { 0 } | opcode];
+        // NOTE: クライアントから送るフレームは必ずマスクする
+        match payload.len() {
+            n if n < 126 => buf.push(0x80 | n as u8),
+            n if n <= u16::MAX as usize => {
+                buf.push(0x80 | 126);
+                buf.extend_from_slice(&(n as u16).to_be_bytes());
+            }
+            n => {
+                buf.push(0x80 | 127);
+                buf.extend_from_slice(&(n as u64).to_be_bytes());
+            }
+        }
+        let mask: [u8; 4] = rand::random();
+        buf.extend_from_slice(&mask);
+        buf.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+
+        self.conn.write_all(&buf)?;
+        self.conn.flush()?;
+        Ok(())
+    }
+
+    fn read_frame(&mut self) -> Result<Frame> {
+        let mut head = [0u8; 2];
+        self.conn.read_exact(&mut head)?;
+        let fin = head[0] & 0x80 != 0;
+        let opcode = head[0] & 0x0f;
+        if head[0] & 0x70 != 0 {
+            return Err(self.fail(PROTOCOL_ERROR, "reserved bits must be zero"));
+        }
+        if head[1] & 0x80 != 0 {
+            return Err(self.fail(PROTOCOL_ERROR, "server frames must not be masked"));
+        }
+
+        let len = match head[1] & 0x7f {
+            126 => {
+                let mut b = [0u8; 2];
+                self.conn.read_exact(&mut b)?;
+                u16::from_be_bytes(b) as u64
+            }
+            127 => {
+                let mut b = [0u8; 8];
+                self.conn.read_exact(&mut b)?;
+                u64::from_be_bytes(b)
+            }
+            n => n as u64,
+        };
+
+        if opcode >= CLOSE && (len > 125 || !fin) {
+            return Err(self.fail(PROTOCOL_ERROR, "invalid control frame"));
+        }
+        let buffered = self.fragments.as_ref().map(|(_, b)| b.len()).unwrap_or(0) as u64;
+        if opcode < CLOSE && buffered + len > self.max_message_size as u64 {
+            return Err(self.fail(MESSAGE_TOO_BIG, "message too big"));
+        }
+
+        let mut payload = vec![0u8; len as usize];
+        self.conn.read_exact(&mut payload)?;
+        Ok(Frame {
+            fin,
+            opcode,
+            payload,
+        })
+    }
+
+    // エラーを伝えるために Close を送り、返すエラーを作る
+    fn fail(&mut self, code: u16, reason: &str) -> anyhow::Error {
+        if !self.close_sent {
+            self.close_sent = true;
+            let _ = self.send_close(code, reason);
+        }
+        anyhow!("websocket protocol error: {}", reason)
+    }
+
+    fn send_close(&mut self, code: u16, reason: &str) -> Result<()> {
+        let mut payload = code.to_be_bytes().to_vec();
+        payload.extend_from_slice(reason.as_bytes());
+        self.write_frame(true, CLOSE, &payload)
+    }
+
+    pub fn send(&mut self, msg: Message) -> Result<()> {
+        if self.close_sent {
+            bail!("websocket is already closed");
+        }
+        let (opcode, payload) = match msg {
+            Message::Text(text) => (TEXT, text.into_bytes()),
+            Message::Binary(data) => (BINARY, data),
+            Message::Ping(data) => return self.write_frame(true, PING, &data),
+            Message::Pong(data) => return self.write_frame(true, PONG, &data),
+            Message::Close(frame) => {
+                let frame = frame.unwrap_or(CloseFrame {
+                    code: NORMAL_CLOSURE,
+                    reason: String::new(),
+                });
+                self.close_sent = true;
+                return self.send_close(frame.code, &frame.reason);
+            }
+        };
+
+        let size = self.fragment_size.unwrap_or(payload.len()).max(1);
+        let mut chunks = payload.chunks(size).peekable();
+        let mut opcode = opcode;
+        if chunks.peek().is_none() {
+            return self.write_frame(true, opcode, &[]);
+        }
+        while let Some(chunk) = chunks.next() {
+            self.write_frame(chunks.peek().is_none(), opcode, chunk)?;
+            opcode = CONTINUATION;
+        }
+        Ok(())
+    }
+
+    // 次のメッセージを読む。Ping には自動で Pong を返す。
+    // Close を受け取ったら (まだ送っていなければ) Close を返して、Message::Close を返す
+    pub fn read(&mut self) -> Result<Message> {
+        if self.close_received {
+            bail!("websocket is already closed");
+        }
+        loop {
+            let frame = self.read_frame()?;
+            match frame.opcode {
+                PING => {
+                    if !self.close_sent {
+                        self.write_frame(true, PONG, &frame.payload)?;
+                    }
+                    return Ok(Message::Ping(frame.payload));
+                }
+                PONG => return Ok(Message::Pong(frame.payload)),
+                CLOSE => {
+                    self.close_received = true;
+                    let close = match frame.payload.len() {
+                        0 => None,
+                        1 => return Err(self.fail(PROTOCOL_ERROR, "invalid close frame")),
+                        _ => Some(CloseFrame {
+                            code: u16::from_be_bytes([frame.payload[0], frame.payload[1]]),
+                            reason: String::from_utf8_lossy(&frame.payload[2..]).into_owned(),
+                        }),
+                    };
+                    if !self.close_sent {
+                        self.close_sent = true;
+                        let code = close.as_ref().map(|c| c.code).unwrap_or(NORMAL_CLOSURE);
+                        self.send_close(code, "")?;
+                    }
+                    return Ok(Message::Close(close));
+                }
+                TEXT | BINARY => {
+                    if self.fragments.is_some() {
+                        return Err(self.fail(PROTOCOL_ERROR, "expected continuation frame"));
+                    }
+                    if !frame.fin {
+                        self.fragments = Some((frame.opcode, frame.payload));
+                        continue;
+                    }
+                    return self.message(frame.opcode, frame.payload);
+                }
+                CONTINUATION => {
+                    let (opcode, mut data) = match self.fragments.take() {
+                        Some(fragments) => fragments,
+                        None => {
+                            return Err(self.fail(PROTOCOL_ERROR, "unexpected continuation frame"))
+                        }
+                    };
+                    data.extend(frame.payload);
+                    if !frame.fin {
+                        self.fragments = Some((opcode, data));
+                        continue;
+                    }
+                    return self.message(opcode, data);
+                }
+                _ => return Err(self.fail(PROTOCOL_ERROR, "unknown opcode")),
+            }
+        }
+    }
+
+    fn message(&mut self, opcode: u8, data: Vec<u8>) -> Result<Message> {
+        match opcode {
+            TEXT => match String::from_utf8(data) {
+                Ok(text) => Ok(Message::Text(text)),
+                Err(_) => Err(self.fail(INVALID_PAYLOAD, "text message is not valid utf-8")),
+            },
+            _ => Ok(Message::Binary(data)),
+        }
+    }
+
+    // クロージングハンドシェイク。Close を送り、相手の Close が届くまでメッセージを読み捨てる
+    pub fn close(&mut self, code: u16, reason: &str) -> Result<Option<CloseFrame>> {
+        if !self.close_sent {
+            self.send(Message::Close(Some(CloseFrame {
+                code,
+                reason: reason.into(),
+            })))?;
+        }
+        while !self.close_received {
+            if let Message::Close(frame) = self.read()? {
+                return Ok(frame);
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures_util::{FutureExt, StreamExt};
+    use std::net::{SocketAddr, TcpStream};
+    use warp::Filter;
+
+    // 受け取ったメッセージをそのまま返す warp のサーバー
+    fn echo_server(rt: &tokio::runtime::Runtime) -> SocketAddr {
+        let echo = warp::path("echo").and(warp::ws()).map(|ws: warp::ws::Ws| {
+            ws.on_upgrade(|socket| {
+                let (tx, rx) = socket.split();
+                rx.forward(tx).map(|_| ())
+            })
+        });
+        let chat = warp::path("chat").and(warp::ws()).map(|ws: warp::ws::Ws| {
+            let reply = ws.on_upgrade(|socket| {
+                let (tx, rx) = socket.split();
+                rx.forward(tx).map(|_| ())
+            });
+            warp::reply::with_header(reply, "sec-websocket-protocol", "chat")
+        });
+
+        let _guard = rt.enter();
+        let (addr, server) = warp::serve(echo.or(chat)).bind_ephemeral(([127, 0, 0, 1], 0));
+        rt.spawn(server);
+        addr
+    }
+
+    fn connect(addr: SocketAddr, path: &str, protocols: &[&str]) -> Result<WebSocket<TcpStream>> {
+        let client = HttpClient::new(TcpStream::connect(addr)?);
+        WebSocket::connect(client, &Request::get(path), protocols)
+    }
+
+    #[test]
+    fn accept_key_from_rfc() {
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn echo_messages() -> Result<()> {
+        let rt = tokio::runtime::Runtime::new()?;
+        let addr = echo_server(&rt);
+        let mut ws = connect(addr, "/echo", &[])?;
+        assert_eq!(ws.protocol(), None);
+
+        ws.send(Message::Text("hello gorilla".into()))?;
+        assert_eq!(ws.read()?, Message::Text("hello gorilla".into()));
+
+        // 分割して送っても 1 つのメッセージとして返ってくること
+        let data = vec![7u8; 100_000];
+        ws.fragment_size(Some(1000));
+        ws.send(Message::Binary(data.clone()))?;
+        assert_eq!(ws.read()?, Message::Binary(data));
+
+        ws.send(Message::Ping(b"ping".to_vec()))?;
+        assert_eq!(ws.read()?, Message::Pong(b"ping".to_vec()));
+
+        let close = ws.close(NORMAL_CLOSURE, "bye")?;
+        assert_eq!(close.map(|c| c.code), Some(NORMAL_CLOSURE));
+        assert!(ws.send(Message::Text("after close".into())).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn reject_too_big_message() -> Result<()> {
+        let rt = tokio::runtime::Runtime::new()?;
+        let addr = echo_server(&rt);
+        let mut ws = connect(addr, "/echo", &[])?;
+        ws.max_message_size(1000);
+
+        ws.send(Message::Binary(vec![0u8; 2000]))?;
+        let err = ws.read().unwrap_err();
+        assert_eq!(err.to_string(), "websocket protocol error: message too big");
+        Ok(())
+    }
+
+    #[test]
+    fn negotiate_subprotocol() -> Result<()> {
+        let rt = tokio::runtime::Runtime::new()?;
+        let addr = echo_server(&rt);
+
+        let ws = connect(addr, "/chat", &["superchat", "chat"])?;
+        assert_eq!(ws.protocol(), Some("chat"));
+
+        // 提示していないサブプロトコルが返されたらエラー
+        assert!(connect(addr, "/chat", &["superchat"]).is_err());
+        Ok(())
+    }
+}